@@ -33,6 +33,7 @@ public:
   uint64_t rank(size_t index) const { return rbv.rank(index); }
   size_t count_ones() const { return rbv.rank(rbv.size() - 1); }
   size_t num_blocks() const { return bitvector.size(); }
+  size_t bit_count() const { return bitvector.bitCount() + rbv.bitCount(); }
   uint64_t get_block(size_t block_index) const {
     return bitvector[block_index];
   }
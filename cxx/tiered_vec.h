@@ -44,6 +44,11 @@ using Layer32 =
     void insert(size_t idx, T elem) const { tiered.insert(idx, elem); }        \
     void remove(size_t idx) const { tiered.remove(idx); }                      \
     void insert_sorted(T elem) const { tiered.insert_sorted(elem); }           \
+    void extend_from(const TieredVec##W &other) const {                        \
+      for (size_t idx = 0; idx < other.tiered.size; idx++) {                   \
+        tiered.insert(tiered.size, other.tiered[idx]);                         \
+      }                                                                        \
+    }                                                                          \
     bool contains_sorted(T elem) const {                                       \
       size_t left = 0, right = tiered.size;                                    \
       while (left < right) {                                                   \
@@ -1,15 +1,20 @@
 #![allow(incomplete_features)]
 #![feature(generic_const_exprs)]
 
-use bincode::{DefaultOptions, Options};
-use cbl::{kmer::Kmer, CBL};
-use clap::{Args, Parser, Subcommand};
+use cbl::cbl::{Format, MemoryUsage, Stats};
+use cbl::delta::Delta;
+use cbl::kmer::{IntKmer, Kmer};
+use cbl::sharded::{is_sharded, ShardedCBL};
+use cbl::stream::SetOp;
+use cbl::wal::{log_path, LoggedCBL, SyncPolicy};
+use cbl::{builder::ExternalBuilder, CBL};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use const_format::formatcp;
 use needletail::{parse_fastx_file, FastxReader};
-use serde::{de::DeserializeOwned, Serialize};
+use std::env::temp_dir;
 use std::fs::File;
-use std::io::{stdout, BufReader, BufWriter, Write};
-use std::path::Path;
+use std::io::{stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 // Loads runtime-provided constants for which declarations
 // will be generated at `$OUT_DIR/constants.rs`.
@@ -24,8 +29,44 @@ use constants::{K, PREFIX_BITS, T};
 struct Cli {
     #[command(subcommand)]
     command: Command,
+    /// Write output indexes as a directory of N shards
+    #[arg(long, global = true)]
+    shards: Option<usize>,
+    /// Format of output indexes
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Bincode)]
+    format: OutputFormat,
+    /// Compression level used with the zstd format
+    #[arg(long, global = true, default_value_t = 3)]
+    zstd_level: i32,
+    /// Compact output indexes before writing them
+    #[arg(long, global = true)]
+    compact: bool,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// Direct serialization of the data structure
+    Bincode,
+    /// Elias-Fano coding of the buckets
+    Compact,
+    /// Elias-Fano coding of the buckets, compressed with zstd
+    Zstd,
+}
+
+/// Options used when writing indexes
+#[derive(Clone, Copy, Debug)]
+struct OutputOptions {
+    shards: Option<usize>,
+    format: Format,
+    compact: bool,
+}
+
+/// Maximum number of unmodified shards kept in memory
+const MAX_LOADED_SHARDS: usize = 16;
+
+/// Default memory budget in MB for buffering k-mers when building an index
+const DEFAULT_BUILD_MEMORY: usize = 1024;
+
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Build an index containing the k-mers of a FASTA/Q file
@@ -40,16 +81,34 @@ enum Command {
     Insert(UpdateArgs),
     /// Remove the k-mers of a FASTA/Q file from an index
     Remove(UpdateArgs),
-    /// Compute the union of two indexes
-    Merge(SetOpsArgs),
-    /// Compute the intersection of two indexes
-    Inter(SetOpsArgs),
+    /// Compute the union of several indexes
+    Merge(MultiOpsArgs),
+    /// Compute the intersection of several indexes
+    Inter(MultiOpsArgs),
+    /// Keep the k-mers contained in at least a given number of indexes
+    Threshold(ThresholdArgs),
+    /// Count the k-mers of several indexes by number of indexes containing them
+    UnionCount(IndexListArgs),
     /// Compute the difference of two indexes
     Diff(SetOpsArgs),
     /// Compute the symmetric difference of two indexes
     SymDiff(SetOpsArgs),
+    /// Compute a patch turning an index into another one
+    Delta(DeltaArgs),
+    /// Apply a patch to an index
+    Apply(ApplyArgs),
+    /// Split an index into a directory of shards
+    Shard(ShardArgs),
     /// Show the repartition of the k-mers in the data structure
-    Repartition(IndexArgs),
+    Repartition(RepartitionArgs),
+    /// Show how k-mers are stored in an index
+    Explain(ExplainArgs),
+    /// Check the internal consistency of an index
+    Check(IndexArgs),
+    /// Fold the log of an index into a new snapshot of the index
+    Checkpoint(IndexArgs),
+    /// Suggest a value of PREFIX_BITS for a FASTA/Q file or an index
+    RecommendPrefix(RecommendArgs),
 }
 
 #[derive(Args, Debug)]
@@ -62,17 +121,44 @@ struct BuildArgs {
     /// Use canonical k-mers
     #[arg(short, long)]
     canonical: bool,
+    /// Memory budget in MB for buffering k-mers, spilling sorted runs to disk beyond it
+    #[arg(short, long, default_value_t = DEFAULT_BUILD_MEMORY)]
+    memory: usize,
+    /// Directory for temporary runs (system temporary directory by default)
+    #[arg(long)]
+    tmp_dir: Option<String>,
 }
 
 #[derive(Args, Debug)]
 struct IndexArgs {
-    /// Index file (CBL format)
+    /// Index file (CBL format or shard directory)
+    index: String,
+}
+
+#[derive(Args, Debug)]
+struct RepartitionArgs {
+    /// Index file (CBL format or shard directory)
+    index: String,
+    /// Also print the statistics as JSON on stdout
+    #[arg(long, conflicts_with = "tsv")]
+    json: bool,
+    /// Also print the statistics as TSV on stdout, one `table<TAB>key<TAB>value` row per value
+    #[arg(long)]
+    tsv: bool,
+}
+
+#[derive(Args, Debug)]
+struct ExplainArgs {
+    /// Index file (CBL format or shard directory)
     index: String,
+    /// k-mers to explain (in ASCII)
+    #[arg(required = true)]
+    kmers: Vec<String>,
 }
 
 #[derive(Args, Debug)]
 struct ListArgs {
-    /// Index file (CBL format)
+    /// Index file (CBL format or shard directory)
     index: String,
     /// Output file (write to stdout by default)
     #[arg(short, long)]
@@ -81,7 +167,7 @@ struct ListArgs {
 
 #[derive(Args, Debug)]
 struct QueryArgs {
-    /// Index file (CBL format)
+    /// Index file (CBL format or shard directory)
     index: String,
     /// Input file to query (FASTA/Q, possibly gzipped)
     input: String,
@@ -89,24 +175,117 @@ struct QueryArgs {
 
 #[derive(Args, Debug)]
 struct UpdateArgs {
-    /// Index file (CBL format)
+    /// Index file (CBL format or shard directory)
     index: String,
     /// Input file to query (FASTA/Q, possibly gzipped)
     input: String,
     /// Output file (no serialization by default)
     #[arg(short, long)]
     output: Option<String>,
+    /// Append the updates to the log of the index instead of rewriting it
+    #[arg(short, long)]
+    log: bool,
 }
 
 #[derive(Args, Debug)]
 struct SetOpsArgs {
-    /// Index file (CBL format)
+    /// Index file (CBL format or shard directory)
     first_index: String,
-    /// Index file (CBL format)
+    /// Index file (CBL format or shard directory)
     second_index: String,
     /// Output file (no serialization by default)
     #[arg(short, long)]
     output: Option<String>,
+    /// Number of threads used to compute the operation (0 for all the cores, sequential by default)
+    #[arg(short, long)]
+    threads: Option<usize>,
+    /// Stream the indexes bucket by bucket instead of loading them (requires an output file)
+    #[arg(long)]
+    stream: bool,
+}
+
+#[derive(Args, Debug)]
+struct IndexListArgs {
+    /// Index files (CBL format or shard directories)
+    indexes: Vec<String>,
+    /// File listing index files, one per line
+    #[arg(short, long)]
+    list: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct MultiOpsArgs {
+    #[command(flatten)]
+    inputs: IndexListArgs,
+    /// Output file (no serialization by default)
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Number of threads used to compute the operation (0 for all the cores, sequential by default)
+    #[arg(short, long)]
+    threads: Option<usize>,
+    /// Stream the indexes bucket by bucket instead of loading them (requires an output file)
+    #[arg(long)]
+    stream: bool,
+}
+
+#[derive(Args, Debug)]
+struct ThresholdArgs {
+    #[command(flatten)]
+    inputs: IndexListArgs,
+    /// Minimum number of indexes containing a k-mer
+    #[arg(short, long)]
+    min_count: usize,
+    /// Output file (no serialization by default)
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct DeltaArgs {
+    /// Old version of the index (CBL format or shard directory)
+    old_index: String,
+    /// New version of the index (CBL format or shard directory)
+    new_index: String,
+    /// Output patch file
+    #[arg(short, long)]
+    output: String,
+}
+
+#[derive(Args, Debug)]
+struct ApplyArgs {
+    /// Index file (CBL format)
+    index: String,
+    /// Patch file computed by the delta command
+    patch: String,
+    /// Output file (the index is updated in place by default)
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct ShardArgs {
+    /// Index file (CBL format or shard directory)
+    index: String,
+    /// Output directory
+    output: String,
+    /// Number of shards
+    #[arg(short, long, default_value_t = 64)]
+    num_shards: usize,
+}
+
+#[derive(Args, Debug)]
+struct RecommendArgs {
+    /// Input file (FASTA/Q, possibly gzipped, or CBL format with --index)
+    input: String,
+    /// Read the input as an index file (CBL format or shard directory)
+    #[arg(short, long)]
+    index: bool,
+    /// Fraction of the k-mers sampled to predict the layout of the full set
+    #[arg(short, long, default_value_t = 0.01)]
+    fraction: f64,
+    /// Use canonical k-mers
+    #[arg(short, long)]
+    canonical: bool,
 }
 
 fn read_fasta<P: AsRef<Path> + Copy>(path: P) -> Box<dyn FastxReader> {
@@ -114,85 +293,396 @@ fn read_fasta<P: AsRef<Path> + Copy>(path: P) -> Box<dyn FastxReader> {
         .unwrap_or_else(|_| panic!("Failed to open {}", path.as_ref().to_str().unwrap()))
 }
 
-fn read_index<D: DeserializeOwned, P: AsRef<Path> + Copy>(path: P) -> D {
-    let index = File::open(path)
-        .unwrap_or_else(|_| panic!("Failed to open {}", path.as_ref().to_str().unwrap()));
-    let reader = BufReader::new(index);
+fn read_index<P: AsRef<Path> + Copy>(path: P) -> CBL<K, T, PREFIX_BITS> {
+    if is_sharded(path) {
+        eprintln!(
+            "Reading the shards stored in {}",
+            path.as_ref().to_str().unwrap()
+        );
+        return open_shards(path).to_cbl();
+    }
+    if log_path(path).exists() {
+        eprintln!(
+            "Reading the index stored in {} and replaying its log",
+            path.as_ref().to_str().unwrap()
+        );
+        return LoggedCBL::<K, T, PREFIX_BITS>::load(path).unwrap_or_else(|error| {
+            panic!(
+                "Failed to load index from {}: {error}",
+                path.as_ref().to_str().unwrap()
+            )
+        });
+    }
     eprintln!(
         "Reading the index stored in {}",
         path.as_ref().to_str().unwrap()
     );
-    DefaultOptions::new()
-        .with_varint_encoding()
-        .reject_trailing_bytes()
-        .deserialize_from(reader)
-        .unwrap()
+    CBL::<K, T, PREFIX_BITS>::load_from_file(path)
+}
+
+fn open_logged(path: &str) -> LoggedCBL<K, T, PREFIX_BITS> {
+    // the log is synchronized once at the end of the command
+    LoggedCBL::<K, T, PREFIX_BITS>::open(path, SyncPolicy::Never)
+        .unwrap_or_else(|error| panic!("Failed to open the log of {path}: {error}"))
+}
+
+fn open_shards<P: AsRef<Path> + Copy>(path: P) -> ShardedCBL<K, T, PREFIX_BITS> {
+    ShardedCBL::<K, T, PREFIX_BITS>::open(path, MAX_LOADED_SHARDS)
 }
 
-fn write_index<S: Serialize, P: AsRef<Path> + Copy>(index: &S, path: P) {
-    let output = File::create(path)
-        .unwrap_or_else(|_| panic!("Failed to open {}", path.as_ref().to_str().unwrap()));
-    let mut writer = BufWriter::new(output);
+fn write_index<P: AsRef<Path> + Copy>(
+    index: &mut CBL<K, T, PREFIX_BITS>,
+    path: P,
+    opts: OutputOptions,
+) {
+    if let Some(num_shards) = opts.shards {
+        eprintln!(
+            "Writing the index to {} as {num_shards} shards",
+            path.as_ref().to_str().unwrap()
+        );
+        return ShardedCBL::<K, T, PREFIX_BITS>::write(index, num_shards, path);
+    }
     eprintln!("Writing the index to {}", path.as_ref().to_str().unwrap());
-    DefaultOptions::new()
-        .with_varint_encoding()
-        .reject_trailing_bytes()
-        .serialize_into(&mut writer, &index)
-        .unwrap();
+    if opts.compact {
+        let reclaimed = index.compact_and_save(path, opts.format);
+        eprintln!("Compaction reclaimed {reclaimed} bytes");
+    } else {
+        index.save_to_file_with_format(path, opts.format);
+    }
+}
+
+/// An index stored in a single file, as a directory of shards, or in a single file with a log of its updates.
+enum Index {
+    Single(CBL<K, T, PREFIX_BITS>),
+    Sharded(ShardedCBL<K, T, PREFIX_BITS>),
+    Logged(LoggedCBL<K, T, PREFIX_BITS>),
+}
+
+impl Index {
+    fn read(path: &str) -> Self {
+        if is_sharded(path) {
+            eprintln!("Opening the shards stored in {path}");
+            Self::Sharded(open_shards(path))
+        } else {
+            Self::Single(read_index(path))
+        }
+    }
+
+    fn open_logged(path: &str) -> Self {
+        assert!(
+            !is_sharded(path),
+            "The log is not supported for directories of shards"
+        );
+        eprintln!("Reading the index stored in {path} and replaying its log");
+        Self::Logged(open_logged(path))
+    }
+
+    fn is_canonical(&self) -> bool {
+        match self {
+            Self::Single(cbl) => cbl.is_canonical(),
+            Self::Sharded(sharded) => sharded.is_canonical(),
+            Self::Logged(logged) => logged.cbl().is_canonical(),
+        }
+    }
+
+    fn contains_seq(&mut self, seq: &[u8]) -> Vec<bool> {
+        match self {
+            Self::Single(cbl) => cbl.contains_seq(seq),
+            Self::Sharded(sharded) => sharded.contains_seq(seq),
+            Self::Logged(logged) => logged.contains_seq(seq),
+        }
+    }
+
+    fn insert_seq(&mut self, seq: &[u8]) {
+        match self {
+            Self::Single(cbl) => cbl.insert_seq(seq),
+            Self::Sharded(sharded) => sharded.insert_seq(seq),
+            Self::Logged(logged) => logged
+                .insert_seq(seq)
+                .unwrap_or_else(|error| panic!("Failed to append to the log: {error}")),
+        }
+    }
+
+    fn remove_seq(&mut self, seq: &[u8]) {
+        match self {
+            Self::Single(cbl) => cbl.remove_seq(seq),
+            Self::Sharded(sharded) => sharded.remove_seq(seq),
+            Self::Logged(logged) => logged
+                .remove_seq(seq)
+                .unwrap_or_else(|error| panic!("Failed to append to the log: {error}")),
+        }
+    }
+
+    /// Synchronizes the log of the index to disk, if any.
+    fn sync(&mut self) {
+        if let Self::Logged(logged) = self {
+            logged
+                .sync()
+                .unwrap_or_else(|error| panic!("Failed to write the log: {error}"));
+            eprintln!(
+                "The log contains {} updates since the last checkpoint",
+                logged.pending_updates()
+            );
+        }
+    }
+
+    fn write(&mut self, path: &str, opts: OutputOptions) {
+        match self {
+            Self::Sharded(sharded) if opts.shards.is_none_or(|n| n == sharded.num_shards()) => {
+                eprintln!("Writing the shards to {path}");
+                sharded.save_to_dir(path);
+            }
+            Self::Sharded(sharded) => write_index(&mut sharded.to_cbl(), path, opts),
+            Self::Single(cbl) => write_index(cbl, path, opts),
+            Self::Logged(logged) => write_index(&mut logged.cbl().clone(), path, opts),
+        }
+    }
+}
+
+/// Applies a set operation shard by shard if both indexes are sharded in the same way.
+/// Returns `false` if the indexes must be loaded entirely instead.
+fn apply_sharded<F>(
+    first_index_filename: &str,
+    second_index_filename: &str,
+    output_filename: &str,
+    opts: OutputOptions,
+    op: F,
+) -> bool
+where
+    F: FnMut(&mut CBL<K, T, PREFIX_BITS>, &mut CBL<K, T, PREFIX_BITS>),
+{
+    if !is_sharded(first_index_filename) || !is_sharded(second_index_filename) {
+        return false;
+    }
+    let sharded = open_shards(first_index_filename);
+    let sharded2 = open_shards(second_index_filename);
+    if sharded.num_shards() != sharded2.num_shards()
+        || opts.shards.is_some_and(|n| n != sharded.num_shards())
+    {
+        return false;
+    }
+    eprintln!("Writing the shards to {output_filename}");
+    sharded.apply_to_dir(&sharded2, output_filename, op);
+    true
+}
+
+/// Applies an operation on multiple sharded indexes shard by shard.
+/// Returns `false` if the indexes are not all sharded the same way.
+fn apply_all_sharded<F>(
+    index_filenames: &[String],
+    output_filename: &str,
+    opts: OutputOptions,
+    op: F,
+) -> bool
+where
+    F: FnMut(Vec<&mut CBL<K, T, PREFIX_BITS>>) -> CBL<K, T, PREFIX_BITS>,
+{
+    if !index_filenames.iter().all(is_sharded) {
+        return false;
+    }
+    let indexes: Vec<_> = index_filenames
+        .iter()
+        .map(|filename| open_shards(filename.as_str()))
+        .collect();
+    let num_shards = indexes[0].num_shards();
+    if indexes
+        .iter()
+        .any(|sharded| sharded.num_shards() != num_shards)
+        || opts.shards.is_some_and(|n| n != num_shards)
+    {
+        return false;
+    }
+    eprintln!("Writing the shards to {output_filename}");
+    ShardedCBL::<K, T, PREFIX_BITS>::apply_all_to_dir(
+        &indexes.iter().collect::<Vec<_>>(),
+        output_filename,
+        op,
+    );
+    true
+}
+
+/// Returns the index files given as arguments followed by the ones listed in the list file.
+fn index_filenames(args: &IndexListArgs) -> Vec<String> {
+    let mut filenames = args.indexes.clone();
+    if let Some(list_filename) = args.list.as_deref() {
+        let list = std::fs::read_to_string(list_filename)
+            .unwrap_or_else(|_| panic!("Failed to read {}", list_filename));
+        filenames.extend(
+            list.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from),
+        );
+    }
+    assert!(!filenames.is_empty(), "No index given");
+    filenames
+}
+
+fn read_indexes(index_filenames: &[String]) -> Vec<CBL<K, T, PREFIX_BITS>> {
+    index_filenames
+        .iter()
+        .map(|filename| read_index(filename.as_str()))
+        .collect()
+}
+
+/// Computes a set operation between index files by streaming them bucket by bucket.
+fn stream_op(
+    op: SetOp,
+    index_filenames: &[&str],
+    output_filename: Option<&str>,
+    opts: OutputOptions,
+) {
+    let output_filename = output_filename.expect("Streaming requires an output file");
+    assert!(
+        opts.shards.is_none(),
+        "Streaming cannot write a directory of shards"
+    );
+    for &filename in index_filenames {
+        assert!(
+            !is_sharded(filename) && !log_path(filename).exists(),
+            "Streaming requires index files without logs, {filename} is not one"
+        );
+    }
+    eprintln!("Streaming the indexes to {output_filename}");
+    CBL::<K, T, PREFIX_BITS>::stream_op(op, index_filenames, output_filename, opts.format)
+        .unwrap_or_else(|error| panic!("Failed to stream the indexes: {error}"));
+}
+
+/// Names and sizes of the parts of a memory usage breakdown.
+fn memory_usage_parts(usage: &MemoryUsage) -> [(&'static str, usize); 8] {
+    [
+        ("prefix bitvector", usage.prefixes),
+        ("tiered vector", usage.tiered),
+        ("container array", usage.containers),
+        ("vec buckets", usage.vec_buckets),
+        ("trie nodes", usage.trie_nodes),
+        ("trie children", usage.trie_children),
+        ("split buckets", usage.split_buckets),
+        ("free containers", usage.free_containers),
+    ]
+}
+
+/// Prints statistics as TSV, with one `table<TAB>key<TAB>value` row per value.
+fn print_stats_tsv(stats: &Stats) {
+    let mut writer = stdout().lock();
+    writeln!(writer, "table\tkey\tvalue").unwrap();
+    writeln!(writer, "summary\tcount\t{}", stats.count).unwrap();
+    writeln!(writer, "summary\tbuckets\t{}", stats.buckets).unwrap();
+    writeln!(writer, "summary\tprefix_load\t{}", stats.prefix_load).unwrap();
+    writeln!(writer, "summary\tsmall_layout\t{}", stats.small_layout).unwrap();
+    for (size, count) in stats.bucket_sizes.iter() {
+        writeln!(writer, "bucket_sizes\t{size}\t{count}").unwrap();
+    }
+    for (nodes, count) in stats.bucket_nodes.iter() {
+        writeln!(writer, "bucket_nodes\t{nodes}\t{count}").unwrap();
+    }
+    for (kind, container) in stats.containers.iter() {
+        writeln!(writer, "container_buckets\t{kind:?}\t{}", container.buckets).unwrap();
+        writeln!(writer, "container_nodes\t{kind:?}\t{}", container.nodes).unwrap();
+    }
+    for (prefix, size) in stats.largest_buckets.iter() {
+        writeln!(writer, "largest_buckets\t{prefix}\t{size}").unwrap();
+    }
+    for (name, bytes) in memory_usage_parts(&stats.memory_usage) {
+        writeln!(writer, "memory_usage\t{}\t{bytes}", name.replace(' ', "_")).unwrap();
+    }
+}
+
+/// Sets the number of threads used by the parallel operations.
+/// Returns `true` if the operations should run in parallel.
+fn init_threads(threads: Option<usize>) -> bool {
+    let Some(threads) = threads else {
+        return false;
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .expect("Failed to create the thread pool");
+    threads != 1
 }
 
 fn main() {
     let args = Cli::parse();
+    let opts = OutputOptions {
+        shards: args.shards,
+        format: match args.format {
+            OutputFormat::Bincode => Format::Bincode,
+            OutputFormat::Compact => Format::Compact,
+            OutputFormat::Zstd => Format::Zstd(args.zstd_level),
+        },
+        compact: args.compact,
+    };
     match args.command {
         Command::Build(args) => {
             let input_filename = args.input.as_str();
-            let mut cbl = if args.canonical {
-                CBL::<K, T, PREFIX_BITS>::new_canonical()
-            } else {
-                CBL::<K, T, PREFIX_BITS>::new()
-            };
             let mut reader = read_fasta(input_filename);
-            if cbl.is_canonical() {
+            if args.canonical {
                 eprintln!("Building the index of canonical {K}-mers contained in {input_filename}");
             } else {
                 eprintln!("Building the index of {K}-mers contained in {input_filename}");
             }
+            // the k-mers are sorted in runs and bulk-loaded instead of being inserted one batch at a time
+            let tmp_dir = args.tmp_dir.map_or_else(temp_dir, PathBuf::from);
+            let mut builder = ExternalBuilder::<K, T, PREFIX_BITS>::new(
+                args.canonical,
+                args.memory << 20,
+                tmp_dir,
+            );
             while let Some(record) = reader.next() {
                 let seqrec = record.unwrap_or_else(|_| panic!("Invalid record"));
-                cbl.insert_seq(&seqrec.seq());
+                builder.insert_seq(&seqrec.seq());
             }
+            eprintln!("Merging {} runs", builder.num_runs());
+            let mut cbl = builder.build();
             if let Some(output_filename) = args.output {
-                write_index(&cbl, output_filename.as_str());
+                write_index(&mut cbl, output_filename.as_str(), opts);
             }
         }
         Command::Count(args) => {
             let index_filename = args.index.as_str();
-            let cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
-            if cbl.is_canonical() {
-                eprintln!("It contains {} canonical {K}-mers", cbl.count());
+            let (canonical, count) = if is_sharded(index_filename) {
+                let sharded = open_shards(index_filename);
+                (sharded.is_canonical(), sharded.count())
+            } else {
+                let cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
+                (cbl.is_canonical(), cbl.count())
+            };
+            if canonical {
+                eprintln!("It contains {count} canonical {K}-mers");
             } else {
-                eprintln!("It contains {} {K}-mers", cbl.count());
+                eprintln!("It contains {count} {K}-mers");
             }
         }
         Command::List(args) => {
             let index_filename = args.index.as_str();
-            let cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
-            if cbl.is_canonical() {
-                eprintln!("Listing canonical {K}-mers contained in {index_filename}");
-            } else {
-                eprintln!("Listing {K}-mers contained in {index_filename}");
-            }
-            if let Some(output_filename) = args.output {
+            let mut writer: Box<dyn Write> = if let Some(output_filename) = args.output {
                 let output_filename = output_filename.as_str();
                 let file = File::create(output_filename)
                     .unwrap_or_else(|_| panic!("Failed to open {}", output_filename));
-                let mut writer = BufWriter::new(file);
-                for kmer in cbl.iter() {
-                    writer.write_all(&kmer.to_nucs()).unwrap();
-                    writer.write_all(b"\n").unwrap();
+                Box::new(BufWriter::new(file))
+            } else {
+                Box::new(stdout().lock())
+            };
+            if is_sharded(index_filename) {
+                let mut sharded = open_shards(index_filename);
+                if sharded.is_canonical() {
+                    eprintln!("Listing canonical {K}-mers contained in {index_filename}");
+                } else {
+                    eprintln!("Listing {K}-mers contained in {index_filename}");
+                }
+                for shard in 0..sharded.num_shards() {
+                    for kmer in sharded.shard(shard).iter() {
+                        writer.write_all(&kmer.to_nucs()).unwrap();
+                        writer.write_all(b"\n").unwrap();
+                    }
                 }
             } else {
-                let mut writer = stdout().lock();
+                let cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
+                if cbl.is_canonical() {
+                    eprintln!("Listing canonical {K}-mers contained in {index_filename}");
+                } else {
+                    eprintln!("Listing {K}-mers contained in {index_filename}");
+                }
                 for kmer in cbl.iter() {
                     writer.write_all(&kmer.to_nucs()).unwrap();
                     writer.write_all(b"\n").unwrap();
@@ -202,9 +692,9 @@ fn main() {
         Command::Query(args) => {
             let index_filename = args.index.as_str();
             let input_filename = args.input.as_str();
-            let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
+            let mut index = Index::read(index_filename);
             let mut reader = read_fasta(input_filename);
-            if cbl.is_canonical() {
+            if index.is_canonical() {
                 eprintln!("Querying the canonical {K}-mers contained in {input_filename}");
             } else {
                 eprintln!("Querying the {K}-mers contained in {input_filename}");
@@ -213,7 +703,7 @@ fn main() {
             let mut positive = 0usize;
             while let Some(record) = reader.next() {
                 let seqrec = record.expect("Invalid record");
-                let contained = cbl.contains_seq(&seqrec.seq());
+                let contained = index.contains_seq(&seqrec.seq());
                 total += contained.len();
                 for p in contained {
                     if p {
@@ -230,9 +720,13 @@ fn main() {
         Command::Insert(args) => {
             let index_filename = args.index.as_str();
             let input_filename = args.input.as_str();
-            let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
+            let mut index = if args.log {
+                Index::open_logged(index_filename)
+            } else {
+                Index::read(index_filename)
+            };
             let mut reader = read_fasta(input_filename);
-            if cbl.is_canonical() {
+            if index.is_canonical() {
                 eprintln!(
                     "Adding the canonical {K}-mers contained in {input_filename} to the index"
                 );
@@ -241,18 +735,23 @@ fn main() {
             }
             while let Some(record) = reader.next() {
                 let seqrec = record.expect("Invalid record");
-                cbl.insert_seq(&seqrec.seq());
+                index.insert_seq(&seqrec.seq());
             }
+            index.sync();
             if let Some(output_filename) = args.output {
-                write_index(&cbl, output_filename.as_str());
+                index.write(output_filename.as_str(), opts);
             }
         }
         Command::Remove(args) => {
             let index_filename = args.index.as_str();
             let input_filename = args.input.as_str();
-            let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
+            let mut index = if args.log {
+                Index::open_logged(index_filename)
+            } else {
+                Index::read(index_filename)
+            };
             let mut reader = read_fasta(input_filename);
-            if cbl.is_canonical() {
+            if index.is_canonical() {
                 eprintln!(
                     "Removing the canonical {K}-mers contained in {input_filename} from the index"
                 );
@@ -261,108 +760,428 @@ fn main() {
             }
             while let Some(record) = reader.next() {
                 let seqrec = record.expect("Invalid record");
-                cbl.remove_seq(&seqrec.seq());
+                index.remove_seq(&seqrec.seq());
             }
+            index.sync();
             if let Some(output_filename) = args.output {
-                write_index(&cbl, output_filename.as_str());
+                index.write(output_filename.as_str(), opts);
             }
         }
         Command::Merge(args) => {
-            let first_index_filename = args.first_index.as_str();
-            let second_index_filename = args.second_index.as_str();
-            let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(first_index_filename);
-            let mut cbl2: CBL<K, T, PREFIX_BITS> = read_index(second_index_filename);
-            cbl |= &mut cbl2;
+            let index_filenames = index_filenames(&args.inputs);
+            if args.stream {
+                let index_filenames: Vec<&str> =
+                    index_filenames.iter().map(String::as_str).collect();
+                stream_op(SetOp::Union, &index_filenames, args.output.as_deref(), opts);
+                return;
+            }
+            let parallel = init_threads(args.threads);
+            let op = |cbls: Vec<&mut CBL<K, T, PREFIX_BITS>>| {
+                if parallel {
+                    CBL::<K, T, PREFIX_BITS>::par_merge(cbls)
+                } else {
+                    CBL::<K, T, PREFIX_BITS>::merge(cbls)
+                }
+            };
+            if let Some(output_filename) = args.output.as_deref() {
+                if apply_all_sharded(&index_filenames, output_filename, opts, op) {
+                    return;
+                }
+            }
+            let mut cbls = read_indexes(&index_filenames);
+            let mut cbl = op(cbls.iter_mut().collect());
             if let Some(output_filename) = args.output {
-                write_index(&cbl, output_filename.as_str());
+                write_index(&mut cbl, output_filename.as_str(), opts);
             }
         }
         Command::Inter(args) => {
-            let first_index_filename = args.first_index.as_str();
-            let second_index_filename = args.second_index.as_str();
-            let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(first_index_filename);
-            let mut cbl2: CBL<K, T, PREFIX_BITS> = read_index(second_index_filename);
-            cbl &= &mut cbl2;
+            let index_filenames = index_filenames(&args.inputs);
+            if args.stream {
+                let index_filenames: Vec<&str> =
+                    index_filenames.iter().map(String::as_str).collect();
+                stream_op(
+                    SetOp::Intersection,
+                    &index_filenames,
+                    args.output.as_deref(),
+                    opts,
+                );
+                return;
+            }
+            let parallel = init_threads(args.threads);
+            let op = |cbls: Vec<&mut CBL<K, T, PREFIX_BITS>>| {
+                if parallel {
+                    CBL::<K, T, PREFIX_BITS>::par_intersect(cbls)
+                } else {
+                    CBL::<K, T, PREFIX_BITS>::intersect(cbls)
+                }
+            };
+            if let Some(output_filename) = args.output.as_deref() {
+                if apply_all_sharded(&index_filenames, output_filename, opts, op) {
+                    return;
+                }
+            }
+            let mut cbls = read_indexes(&index_filenames);
+            let mut cbl = op(cbls.iter_mut().collect());
             if let Some(output_filename) = args.output {
-                write_index(&cbl, output_filename.as_str());
+                write_index(&mut cbl, output_filename.as_str(), opts);
+            }
+        }
+        Command::Threshold(args) => {
+            let index_filenames = index_filenames(&args.inputs);
+            let min_count = args.min_count;
+            assert!(
+                min_count >= 1 && min_count <= index_filenames.len(),
+                "The minimum count must be between 1 and the number of indexes"
+            );
+            let op = |cbls: Vec<&mut CBL<K, T, PREFIX_BITS>>| {
+                let cbls: Vec<&CBL<K, T, PREFIX_BITS>> = cbls.iter().map(|cbl| &**cbl).collect();
+                CBL::<K, T, PREFIX_BITS>::threshold(&cbls, min_count)
+            };
+            if let Some(output_filename) = args.output.as_deref() {
+                if apply_all_sharded(&index_filenames, output_filename, opts, op) {
+                    return;
+                }
+            }
+            let mut cbls = read_indexes(&index_filenames);
+            let mut cbl = op(cbls.iter_mut().collect());
+            if let Some(output_filename) = args.output {
+                write_index(&mut cbl, output_filename.as_str(), opts);
+            }
+        }
+        Command::UnionCount(args) => {
+            let index_filenames = index_filenames(&args);
+            let cbls = read_indexes(&index_filenames);
+            let counts =
+                CBL::<K, T, PREFIX_BITS>::occurrence_counts(&cbls.iter().collect::<Vec<_>>());
+            let mut output = BufWriter::new(stdout().lock());
+            writeln!(output, "indexes\tk-mers").unwrap();
+            for (num_indexes, count) in counts.into_iter().enumerate().skip(1) {
+                writeln!(output, "{num_indexes}\t{count}").unwrap();
             }
         }
         Command::Diff(args) => {
             let first_index_filename = args.first_index.as_str();
             let second_index_filename = args.second_index.as_str();
+            if args.stream {
+                stream_op(
+                    SetOp::Difference,
+                    &[first_index_filename, second_index_filename],
+                    args.output.as_deref(),
+                    opts,
+                );
+                return;
+            }
+            let parallel = init_threads(args.threads);
+            let op = |cbl: &mut CBL<K, T, PREFIX_BITS>, cbl2: &mut CBL<K, T, PREFIX_BITS>| {
+                if parallel {
+                    *cbl = cbl.par_difference(cbl2);
+                } else {
+                    *cbl -= cbl2;
+                }
+            };
+            if let Some(output_filename) = args.output.as_deref() {
+                if apply_sharded(
+                    first_index_filename,
+                    second_index_filename,
+                    output_filename,
+                    opts,
+                    op,
+                ) {
+                    return;
+                }
+            }
             let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(first_index_filename);
             let mut cbl2: CBL<K, T, PREFIX_BITS> = read_index(second_index_filename);
-            cbl -= &mut cbl2;
+            op(&mut cbl, &mut cbl2);
             if let Some(output_filename) = args.output {
-                write_index(&cbl, output_filename.as_str());
+                write_index(&mut cbl, output_filename.as_str(), opts);
             }
         }
         Command::SymDiff(args) => {
             let first_index_filename = args.first_index.as_str();
             let second_index_filename = args.second_index.as_str();
+            if args.stream {
+                stream_op(
+                    SetOp::SymmetricDifference,
+                    &[first_index_filename, second_index_filename],
+                    args.output.as_deref(),
+                    opts,
+                );
+                return;
+            }
+            let parallel = init_threads(args.threads);
+            let op = |cbl: &mut CBL<K, T, PREFIX_BITS>, cbl2: &mut CBL<K, T, PREFIX_BITS>| {
+                if parallel {
+                    *cbl = cbl.par_symmetric_difference(cbl2);
+                } else {
+                    *cbl ^= cbl2;
+                }
+            };
+            if let Some(output_filename) = args.output.as_deref() {
+                if apply_sharded(
+                    first_index_filename,
+                    second_index_filename,
+                    output_filename,
+                    opts,
+                    op,
+                ) {
+                    return;
+                }
+            }
             let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(first_index_filename);
             let mut cbl2: CBL<K, T, PREFIX_BITS> = read_index(second_index_filename);
-            cbl ^= &mut cbl2;
+            op(&mut cbl, &mut cbl2);
             if let Some(output_filename) = args.output {
-                write_index(&cbl, output_filename.as_str());
+                write_index(&mut cbl, output_filename.as_str(), opts);
             }
         }
+        Command::Shard(args) => {
+            let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(args.index.as_str());
+            write_index(
+                &mut cbl,
+                args.output.as_str(),
+                OutputOptions {
+                    shards: Some(args.num_shards),
+                    ..opts
+                },
+            );
+        }
         Command::Repartition(args) => {
             let index_filename = args.index.as_str();
             let cbl: CBL<K, T, PREFIX_BITS> = read_index(index_filename);
+            let stats = cbl.stats();
             eprintln!(
                 "{:.1}% of the available prefixes are used",
-                cbl.prefix_load() * 100.0
+                stats.prefix_load * 100.0
             );
-            let buckets_size_count = cbl.buckets_size_count();
-            let total_buckets: usize = buckets_size_count.iter().map(|(_, &c)| c).sum();
-            let total_items: usize = buckets_size_count.iter().map(|(&s, &c)| s * c).sum();
             eprintln!(
                 "The average bucket size is {:.1} items",
-                total_items as f64 / total_buckets as f64
+                stats.count as f64 / stats.buckets as f64
             );
             let mut bucket_count = 0;
             let mut item_count = 0;
-            for (&size, &count) in buckets_size_count.iter() {
+            for (&size, &count) in stats.bucket_sizes.iter() {
                 bucket_count += count;
                 item_count += size * count;
-                if count > total_buckets / 100 / 2
-                    || size * count > total_items / 100 / 2
-                    || bucket_count == total_buckets
+                if count > stats.buckets / 100 / 2
+                    || size * count > stats.count / 100 / 2
+                    || bucket_count == stats.buckets
                 {
                     eprintln!(
                         "{:.1}% of items are in a bucket of size ≤ {size} ({:.1}% of buckets)",
-                        (item_count * 100) as f64 / total_items as f64,
-                        (bucket_count * 100) as f64 / total_buckets as f64,
+                        (item_count * 100) as f64 / stats.count as f64,
+                        (bucket_count * 100) as f64 / stats.buckets as f64,
                     );
                 }
             }
-            let (max_prefix, max_size) = cbl.buckets_sizes().max_by_key(|&(_, size)| size).unwrap();
-            eprintln!("The biggest bucket (of size {max_size}) corresponds to prefix {max_prefix}");
-            let buckets_node_count = cbl.buckets_node_count();
-            let mut vec_count = 0;
-            let mut vec_node_count = 0;
-            let mut trie_count = 0;
-            let mut trie_node_count = 0;
-            for (&nodes, &count) in buckets_node_count.iter() {
-                if nodes <= 1024 {
-                    vec_count += count;
-                    vec_node_count += nodes * count;
-                } else {
-                    trie_count += count;
-                    trie_node_count += nodes * count;
-                }
+            if let Some(&(max_prefix, max_size)) = stats.largest_buckets.first() {
+                eprintln!(
+                    "The biggest bucket (of size {max_size}) corresponds to prefix {max_prefix}"
+                );
+            }
+            if stats.small_layout {
+                eprintln!("The index uses the small layout, its buckets have no container");
+            }
+            let mut node_count = stats.buckets;
+            for (kind, container) in stats.containers.iter() {
+                eprintln!(
+                    "{} {kind:?} buckets, average node count = {:.1}",
+                    container.buckets,
+                    container.nodes as f64 / container.buckets as f64
+                );
+                node_count += container.nodes;
+            }
+            eprintln!("{node_count} nodes in total");
+            let usage = stats.memory_usage;
+            let total_bytes = usage.total();
+            eprintln!("{total_bytes} bytes used in total:");
+            for (name, bytes) in memory_usage_parts(&usage) {
+                eprintln!(
+                    "  {name}: {bytes} bytes ({:.1}%)",
+                    (bytes * 100) as f64 / total_bytes as f64
+                );
             }
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+            }
+            if args.tsv {
+                print_stats_tsv(&stats);
+            }
+        }
+        Command::Delta(args) => {
+            let mut old = read_index(args.old_index.as_str());
+            let mut new = read_index(args.new_index.as_str());
+            eprintln!("Computing the delta");
+            let delta = Delta::between(&mut old, &mut new);
+            eprintln!(
+                "{} {K}-mers added and {} {K}-mers removed",
+                delta.added().count(),
+                delta.removed().count()
+            );
+            eprintln!("Writing the patch to {}", args.output);
+            delta.save_to_file(args.output.as_str());
+        }
+        Command::Apply(args) => {
+            let index_filename = args.index.as_str();
+            assert!(
+                !is_sharded(index_filename),
+                "Patches cannot be applied to directories of shards"
+            );
+            assert!(
+                args.output.is_some() || !log_path(index_filename).exists(),
+                "The index has a log, checkpoint it before updating it in place"
+            );
+            let mut cbl = read_index(index_filename);
+            eprintln!("Reading the patch stored in {}", args.patch);
+            let mut delta = Delta::<K, T, PREFIX_BITS>::load_from_file(args.patch.as_str());
+            eprintln!("Applying the patch");
+            delta.apply(&mut cbl).unwrap_or_else(|error| {
+                eprintln!("The patch cannot be applied: {error}");
+                std::process::exit(1);
+            });
             eprintln!(
-                "{vec_count} vecs, average node count = {:.1}",
-                vec_node_count as f64 / vec_count as f64
+                "{} {K}-mers added and {} {K}-mers removed, the index contains {} {K}-mers",
+                delta.added().count(),
+                delta.removed().count(),
+                cbl.count()
             );
+            let output_filename = args.output.as_deref().unwrap_or(index_filename);
+            write_index(&mut cbl, output_filename, opts);
+        }
+        Command::Explain(args) => {
+            for kmer in args.kmers.iter() {
+                assert!(
+                    kmer.len() == K && kmer.bytes().all(|nuc| b"ACGTacgt".contains(&nuc)),
+                    "{kmer} is not a {K}-mer"
+                );
+            }
+            let cbl: CBL<K, T, PREFIX_BITS> = read_index(args.index.as_str());
+            for kmer in args.kmers.iter() {
+                let layout = cbl.explain(IntKmer::<K, T>::from_nucs(kmer.as_bytes()));
+                let stored = String::from_utf8_lossy(&layout.kmer.to_nucs()).into_owned();
+                println!("{K}-mer: {kmer}");
+                if cbl.is_canonical() {
+                    println!("canonical {K}-mer: {stored}");
+                }
+                println!("necklace: {:0width$b}", layout.necklace, width = 2 * K);
+                println!("rotation: {} bits", layout.pos);
+                println!("word: {:b}", layout.word);
+                println!("prefix: {} ({PREFIX_BITS} bits)", layout.prefix);
+                println!("suffix: {:b}", layout.suffix);
+                println!("present: {}", layout.present);
+                println!("bucket size: {}", layout.bucket_size);
+                match (layout.bucket_id, layout.container) {
+                    (Some(id), Some(container)) => {
+                        println!("bucket id: {id}");
+                        println!("container: {container:?}");
+                    }
+                    _ if layout.bucket_size > 0 => {
+                        println!("container: none (small layout)");
+                    }
+                    _ => println!("container: none (empty bucket)"),
+                }
+                if let Some(depth) = layout.trie_depth {
+                    println!("trie depth: {depth}");
+                }
+                println!();
+            }
+        }
+        Command::Check(args) => {
+            let index_filename = args.index.as_str();
+            let (count, res) = if is_sharded(index_filename) {
+                let sharded = open_shards(index_filename);
+                (sharded.count(), sharded.validate())
+            } else {
+                eprintln!("Reading the index stored in {index_filename}");
+                let cbl = CBL::<K, T, PREFIX_BITS>::try_load_from_file(index_filename)
+                    .unwrap_or_else(|error| {
+                        eprintln!("The index cannot be loaded: {error}");
+                        std::process::exit(1);
+                    });
+                (cbl.count(), cbl.validate())
+            };
+            match res {
+                Ok(()) => eprintln!("The index is consistent, it contains {count} {K}-mers"),
+                Err(errors) => {
+                    eprintln!(
+                        "The index is inconsistent, {} problems found:",
+                        errors.len()
+                    );
+                    for error in errors {
+                        eprintln!("  - {error}");
+                    }
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Checkpoint(args) => {
+            let index_filename = args.index.as_str();
+            eprintln!("Reading the index stored in {index_filename} and replaying its log");
+            let mut logged = open_logged(index_filename);
+            let pending = logged.pending_updates();
+            logged.set_format(opts.format);
+            logged.checkpoint().unwrap_or_else(|error| {
+                panic!("Failed to write the checkpoint of {index_filename}: {error}")
+            });
+            eprintln!("Folded {pending} updates of the log into {index_filename}");
+        }
+        Command::RecommendPrefix(args) => {
+            let input_filename = args.input.as_str();
+            let fraction = args.fraction;
+            let predictions = if args.index
+                && (is_sharded(input_filename) || log_path(input_filename).exists())
+            {
+                read_index(input_filename).predict_prefix_bits(8..=28)
+            } else if args.index {
+                eprintln!(
+                    "Sampling {:.2}% of the {K}-mers of {input_filename}",
+                    fraction * 100.0
+                );
+                CBL::<K, T, PREFIX_BITS>::predict_prefix_bits_from_file(
+                    input_filename,
+                    fraction,
+                    8..=28,
+                )
+                .unwrap_or_else(|error| panic!("Failed to read {input_filename}: {error}"))
+            } else {
+                let mut sample = if args.canonical {
+                    CBL::<K, T, PREFIX_BITS>::new_canonical()
+                } else {
+                    CBL::<K, T, PREFIX_BITS>::new()
+                };
+                let mut reader = read_fasta(input_filename);
+                eprintln!(
+                    "Sampling {:.2}% of the {K}-mers of {input_filename}",
+                    fraction * 100.0
+                );
+                while let Some(record) = reader.next() {
+                    let seqrec = record.expect("Invalid record");
+                    sample.insert_seq_sampled(&seqrec.seq(), fraction);
+                }
+                eprintln!("The sample contains {} {K}-mers", sample.count());
+                sample.predict_prefix_bits_sampled(fraction, 8..=28)
+            };
+            if let Some(prediction) = predictions.first() {
+                eprintln!("Predictions for about {} {K}-mers", prediction.kmers);
+            }
+            eprintln!("PREFIX_BITS\tbuckets\tmax bucket size\tmemory (MB)");
+            for prediction in predictions.iter() {
+                eprintln!(
+                    "{}\t{}\t{}\t{:.1}",
+                    prediction.prefix_bits,
+                    prediction.buckets,
+                    prediction.max_bucket_size,
+                    prediction.memory as f64 / 1e6
+                );
+            }
+            let best = predictions
+                .iter()
+                .min_by_key(|prediction| prediction.memory)
+                .unwrap();
             eprintln!(
-                "{trie_count} tries, average node count = {:.1}",
-                trie_node_count as f64 / trie_count as f64
+                "Recommended PREFIX_BITS: {} (this binary uses PREFIX_BITS={PREFIX_BITS})",
+                best.prefix_bits
             );
-            let total_count = total_buckets + vec_node_count + trie_node_count;
-            eprintln!("{total_count} nodes in total");
         }
     }
 }
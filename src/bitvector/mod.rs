@@ -2,25 +2,57 @@ mod set_ops;
 mod tiny;
 
 use crate::ffi::{RankBV, UniquePtr, WithinUniquePtr};
+use core::ops::Range;
 use serde::{
-    de::{SeqAccess, Visitor},
+    de::{self, SeqAccess, Visitor},
     ser::SerializeSeq,
     Deserialize, Deserializer, Serialize, Serializer,
 };
+use std::sync::Arc;
 pub use tiny::*;
 
+/// Largest bitlength accepted when deserializing a bitvector.
+const MAX_BITLENGTH: usize = 28;
+
+/// A bitvector with rank support, shared between its clones until one of them is modified.
 pub struct Bitvector {
-    bv: UniquePtr<RankBV>,
+    bv: Arc<UniquePtr<RankBV>>,
 }
 
+// SAFETY: `RankBV` (cxx/rank_bv.h) keeps its `sux::util::Vector` and its `WordDynRankSel<FenwickByteL>` in `mutable`
+// members so that its const methods can call the methods of sux, none of which is const-qualified.
+// Only `set`, `clear`, `toggle` and `update_block` write to them, through `WordDynRankSel::set`, `clear`, `toggle`
+// and `update`, which change a word and the Fenwick tree, and they are only called through `&mut Bitvector`
+// once the bitvector is no longer shared (see `bv_mut`).
+// The other methods only read them: `get` and `get_block` read a word through `Vector::operator[]`,
+// `rank` and `count_ones` call `WordDynRankSel::rank`, which adds `FenwickByteL::prefix` over the previous words
+// to the popcount of the current word without caching anything, and `size`, `num_blocks` and `bit_count`
+// read the sizes of the structures.
+// Concurrent reads through `&Bitvector` are therefore data-race free, see `test_concurrent_reads`.
+unsafe impl Send for Bitvector {}
+unsafe impl Sync for Bitvector {}
+
 impl Bitvector {
     #[inline]
     pub fn new_with_bitlength(bitlength: usize) -> Self {
         Self {
-            bv: RankBV::new(1 << bitlength).within_unique_ptr(),
+            bv: Arc::new(RankBV::new(1 << bitlength).within_unique_ptr()),
+        }
+    }
+
+    /// Creates a placeholder without allocating the underlying bitvector.
+    #[inline]
+    pub fn unallocated() -> Self {
+        Self {
+            bv: Arc::new(UniquePtr::null()),
         }
     }
 
+    #[inline]
+    pub fn is_allocated(&self) -> bool {
+        !self.bv.is_null()
+    }
+
     #[inline]
     pub fn bitlength(&self) -> usize {
         self.bv.size().ilog2() as usize
@@ -33,12 +65,38 @@ impl Bitvector {
 
     #[inline]
     pub fn insert(&mut self, index: usize) -> bool {
-        !self.bv.set(index)
+        // the bitvector is only copied if it actually changes
+        !self.contains(index) && !self.bv_mut().set(index)
     }
 
     #[inline]
     pub fn remove(&mut self, index: usize) -> bool {
-        self.bv.clear(index)
+        self.contains(index) && self.bv_mut().clear(index)
+    }
+
+    /// Returns the underlying bitvector to modify it, copying it first if it is shared with a clone.
+    #[inline]
+    fn bv_mut(&mut self) -> &UniquePtr<RankBV> {
+        if Arc::get_mut(&mut self.bv).is_none() {
+            *self = self.deep_clone();
+        }
+        &self.bv
+    }
+
+    /// Copies the underlying bitvector.
+    fn deep_clone(&self) -> Self {
+        if !self.is_allocated() {
+            return Self::unallocated();
+        }
+        let res = Self::new_with_bitlength(self.bitlength());
+        let num_blocks = self.bv.num_blocks();
+        for i in 0..num_blocks {
+            let block = self.bv.get_block(i);
+            if block != 0 {
+                res.bv.update_block(i, block);
+            }
+        }
+        res
     }
 
     #[inline]
@@ -51,12 +109,40 @@ impl Bitvector {
         self.bv.count_ones()
     }
 
+    /// Returns the number of bytes used by the bitvector and its rank structure.
+    #[inline]
+    pub fn heap_size(&self) -> usize {
+        if !self.is_allocated() {
+            return 0;
+        }
+        self.bv.bit_count().div_ceil(8)
+    }
+
     #[inline]
     pub fn iter(&self) -> BitvectorIterator<'_> {
         BitvectorIterator {
-            bitvector: &self.bv,
+            bitvector: &*self.bv,
             block_index: 0,
             block: self.bv.get_block(0),
+            end: self.bv.size(),
+        }
+    }
+
+    /// Returns an iterator over the set bits whose index is in `range`.
+    #[inline]
+    pub fn iter_range(&self, range: Range<usize>) -> BitvectorIterator<'_> {
+        let end = range.end.min(self.bv.size());
+        let start = range.start.min(end);
+        let block_index = start / 64;
+        BitvectorIterator {
+            bitvector: &*self.bv,
+            block_index,
+            block: if block_index < self.bv.num_blocks() {
+                self.bv.get_block(block_index) & (u64::MAX << (start % 64))
+            } else {
+                0
+            },
+            end,
         }
     }
 }
@@ -65,6 +151,7 @@ pub struct BitvectorIterator<'a> {
     bitvector: &'a UniquePtr<RankBV>,
     block_index: usize,
     block: u64,
+    end: usize,
 }
 
 impl<'a> Iterator for BitvectorIterator<'a> {
@@ -80,21 +167,23 @@ impl<'a> Iterator for BitvectorIterator<'a> {
         }
         let bit_index = self.block.trailing_zeros() as usize;
         self.block -= 1 << bit_index;
-        Some(self.block_index * 64 + bit_index)
+        let index = self.block_index * 64 + bit_index;
+        if index >= self.end {
+            // skips the remaining blocks
+            self.block_index = num_blocks;
+            self.block = 0;
+            return None;
+        }
+        Some(index)
     }
 }
 
 impl Clone for Bitvector {
+    /// Shares the bitvector with the clone, it is copied when one of them is modified.
     fn clone(&self) -> Self {
-        let res = Self::new_with_bitlength(self.bitlength());
-        let num_blocks = self.bv.num_blocks();
-        for i in 0..num_blocks {
-            let block = self.bv.get_block(i);
-            if block != 0 {
-                res.bv.update_block(i, block);
-            }
+        Self {
+            bv: Arc::clone(&self.bv),
         }
-        res
     }
 }
 
@@ -119,14 +208,26 @@ impl<'de> Visitor<'de> for TinyBitvectorVisitor {
     }
 
     fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
-        let num_blocks = access.size_hint().unwrap();
+        let num_blocks = access
+            .size_hint()
+            .ok_or_else(|| de::Error::custom("the number of blocks is unknown"))?;
+        // the length is checked before allocating anything
+        if !num_blocks.is_power_of_two() || num_blocks > 1 << (MAX_BITLENGTH - 6) {
+            return Err(de::Error::invalid_length(num_blocks, &self));
+        }
         let bitlength = (num_blocks * 64).ilog2() as usize;
         let bitvector = Bitvector::new_with_bitlength(bitlength);
         let mut block_index = 0;
         while let Some(block) = access.next_element()? {
+            if block_index == num_blocks {
+                return Err(de::Error::invalid_length(block_index + 1, &self));
+            }
             bitvector.bv.update_block(block_index, block);
             block_index += 1;
         }
+        if block_index != num_blocks {
+            return Err(de::Error::invalid_length(block_index, &self));
+        }
         Ok(bitvector)
     }
 }
@@ -185,4 +286,19 @@ mod tests {
         assert_eq!(iter.next(), Some((1 << BITS) - 1));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn test_bitvector_iter_range() {
+        let mut bitset = Bitvector::new_with_bitlength(BITS);
+        for i in [1, 3, 42, 64, 101010, (1 << BITS) - 1] {
+            bitset.insert(i);
+        }
+        assert!(bitset.iter_range(2..64).eq([3, 42]));
+        assert!(bitset.iter_range(42..65).eq([42, 64]));
+        assert!(bitset
+            .iter_range(65..(1 << BITS))
+            .eq([101010, (1 << BITS) - 1]));
+        assert_eq!(bitset.iter_range(43..64).next(), None);
+        assert_eq!(bitset.iter_range((1 << BITS)..usize::MAX).next(), None);
+    }
 }
@@ -19,10 +19,11 @@ impl BitOr<Self> for &Bitvector {
 impl BitOrAssign<&Self> for Bitvector {
     fn bitor_assign(&mut self, other: &Self) {
         assert_eq!(self.bv.num_blocks(), other.bv.num_blocks());
-        for i in 0..self.bv.num_blocks() {
-            let a = self.bv.get_block(i);
+        let bv = self.bv_mut();
+        for i in 0..bv.num_blocks() {
+            let a = bv.get_block(i);
             let b = other.bv.get_block(i);
-            self.bv.update_block(i, a | b);
+            bv.update_block(i, a | b);
         }
     }
 }
@@ -45,10 +46,11 @@ impl BitAnd<Self> for &Bitvector {
 impl BitAndAssign<&Self> for Bitvector {
     fn bitand_assign(&mut self, other: &Self) {
         assert_eq!(self.bv.num_blocks(), other.bv.num_blocks());
-        for i in 0..self.bv.num_blocks() {
-            let a = self.bv.get_block(i);
+        let bv = self.bv_mut();
+        for i in 0..bv.num_blocks() {
+            let a = bv.get_block(i);
             let b = other.bv.get_block(i);
-            self.bv.update_block(i, a & b);
+            bv.update_block(i, a & b);
         }
     }
 }
@@ -71,10 +73,11 @@ impl Sub<Self> for &Bitvector {
 impl SubAssign<&Self> for Bitvector {
     fn sub_assign(&mut self, other: &Self) {
         assert_eq!(self.bv.num_blocks(), other.bv.num_blocks());
-        for i in 0..self.bv.num_blocks() {
-            let a = self.bv.get_block(i);
+        let bv = self.bv_mut();
+        for i in 0..bv.num_blocks() {
+            let a = bv.get_block(i);
             let b = other.bv.get_block(i);
-            self.bv.update_block(i, a & !b);
+            bv.update_block(i, a & !b);
         }
     }
 }
@@ -97,10 +100,11 @@ impl BitXor<Self> for &Bitvector {
 impl BitXorAssign<&Self> for Bitvector {
     fn bitxor_assign(&mut self, other: &Self) {
         assert_eq!(self.bv.num_blocks(), other.bv.num_blocks());
-        for i in 0..self.bv.num_blocks() {
-            let a = self.bv.get_block(i);
+        let bv = self.bv_mut();
+        for i in 0..bv.num_blocks() {
+            let a = bv.get_block(i);
             let b = other.bv.get_block(i);
-            self.bv.update_block(i, a ^ b);
+            bv.update_block(i, a ^ b);
         }
     }
 }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use serde::{
-    de::{SeqAccess, Visitor},
+    de::{self, SeqAccess, Visitor},
     ser::SerializeSeq,
     Deserialize, Deserializer, Serialize, Serializer,
 };
@@ -115,7 +115,13 @@ impl<'de> Visitor<'de> for TinyBitvectorVisitor {
 
     fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
         let mut bv = TinyBitvector::new();
-        while let Some(index) = access.next_element()? {
+        let mut last = None;
+        while let Some(index) = access.next_element::<u8>()? {
+            // the indices are serialized in increasing order, anything else is corrupted
+            if last.is_some_and(|last| last >= index) {
+                return Err(de::Error::custom("the indices are not strictly increasing"));
+            }
+            last = Some(index);
             bv.insert(index);
         }
         Ok(bv)
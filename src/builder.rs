@@ -0,0 +1,326 @@
+//! Out-of-core construction of [`CBL`] for inputs that do not fit in memory.
+
+use crate::cbl::*;
+use crate::kmer::Base;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use itertools::Itertools;
+use std::fs::{remove_file, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Counter used to give a unique name to the runs of each builder.
+static BUILDER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A builder computing the words of the input in memory and spilling them to sorted runs on disk.
+///
+/// The words are buffered until they reach the memory budget, then they are sorted, deduplicated
+/// and written to temporary files, one per range of prefixes.
+/// The runs are finally merged partition by partition to build a [`CBL`] in a single pass.
+///
+/// # Type Parameters
+/// - `K`: the length of the *k*-mers, it must be ≤ 59.
+/// - `T`: the integer type used to store *k*-mers, it must be large enough to store $2k + \lg(2k)$ bits.
+/// - `PREFIX_BITS` (optional): the size of the prefixes in bits.
+pub struct ExternalBuilder<const K: usize, T: Base, const PREFIX_BITS: usize = 24>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    cbl: CBL<K, T, PREFIX_BITS>,
+    id: usize,
+    temp_dir: PathBuf,
+    max_words: usize,
+    words: Vec<T>,
+    runs: Vec<Vec<PathBuf>>,
+    num_runs: usize,
+}
+
+macro_rules! impl_builder {
+    ($T:ty) => {
+        impl<const K: usize, const PREFIX_BITS: usize> ExternalBuilder<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            const PARTITION_BITS: usize = if PREFIX_BITS < 8 { PREFIX_BITS } else { 8 };
+            const PARTITION_SHIFT: usize =
+                PREFIX_BITS + suffix_bits::<K, PREFIX_BITS>() - Self::PARTITION_BITS;
+            /// Maximum number of runs merged at once, which bounds the number of open files.
+            const MAX_FAN_IN: usize = 64;
+
+            /// Creates a builder using at most `memory_budget` bytes to buffer words,
+            /// and storing its runs in `temp_dir`.
+            pub fn new<P: AsRef<Path>>(canonical: bool, memory_budget: usize, temp_dir: P) -> Self {
+                let cbl = if canonical {
+                    CBL::<K, $T, PREFIX_BITS>::new_canonical()
+                } else {
+                    CBL::<K, $T, PREFIX_BITS>::new()
+                };
+                Self {
+                    cbl,
+                    id: BUILDER_ID.fetch_add(1, Ordering::Relaxed),
+                    temp_dir: temp_dir.as_ref().to_path_buf(),
+                    max_words: (memory_budget / size_of::<$T>()).max(1),
+                    words: Vec::new(),
+                    runs: vec![Vec::new(); 1 << Self::PARTITION_BITS],
+                    num_runs: 0,
+                }
+            }
+
+            /// Returns the number of runs spilled to disk so far.
+            #[inline]
+            pub fn num_runs(&self) -> usize {
+                self.num_runs
+            }
+
+            /// Adds all the *k*-mers of a sequence, spilling a run whenever the memory budget is reached.
+            /// Long sequences are processed by chunks so that they do not exceed the budget either.
+            pub fn insert_seq(&mut self, seq: &[u8]) {
+                assert!(
+                    seq.len() >= K,
+                    "Sequence size ({}) is smaller than K ({})",
+                    seq.len(),
+                    K
+                );
+                let num_kmers = seq.len() - K + 1;
+                let mut start = 0;
+                while start < num_kmers {
+                    let len = (self.max_words - self.words.len()).min(num_kmers - start);
+                    self.words
+                        .append(&mut self.cbl.seq_words(&seq[start..(start + len + K - 1)]));
+                    start += len;
+                    if self.words.len() >= self.max_words {
+                        self.spill();
+                    }
+                }
+            }
+
+            #[inline]
+            fn partition(word: $T) -> usize {
+                (word >> Self::PARTITION_SHIFT) as usize
+            }
+
+            /// Returns the path of a run, `name` telling apart the runs of a partition.
+            fn run_path(&self, partition: usize, name: &str) -> PathBuf {
+                self.temp_dir.join(format!(
+                    "cbl-{}-{}-{name}-{partition}.run",
+                    std::process::id(),
+                    self.id,
+                ))
+            }
+
+            /// Writes sorted words to a run.
+            fn write_run<I: IntoIterator<Item = $T>>(path: &Path, words: I) {
+                let file = File::create(path)
+                    .unwrap_or_else(|_| panic!("Failed to create {}", path.to_str().unwrap()));
+                let mut writer = BufWriter::new(file);
+                for word in words {
+                    writer.write_all(&word.to_le_bytes()).unwrap_or_else(|_| {
+                        panic!("Failed to write run to {}", path.to_str().unwrap())
+                    });
+                }
+                writer.flush().unwrap_or_else(|_| {
+                    panic!("Failed to write run to {}", path.to_str().unwrap())
+                });
+            }
+
+            /// Sorts the buffered words and writes them to one file per partition.
+            fn spill(&mut self) {
+                self.words.sort_unstable();
+                self.words.dedup();
+                for (partition, words) in
+                    &self.words.iter().chunk_by(|&&word| Self::partition(word))
+                {
+                    let path = self.run_path(partition, &self.num_runs.to_string());
+                    Self::write_run(&path, words.copied());
+                    self.runs[partition].push(path);
+                }
+                self.words.clear();
+                self.num_runs += 1;
+            }
+
+            /// Merges the runs of a partition by groups of `MAX_FAN_IN` until there are at most `MAX_FAN_IN` of them.
+            fn reduce_runs(&mut self, partition: usize) {
+                let mut pass = 0;
+                while self.runs[partition].len() > Self::MAX_FAN_IN {
+                    let old = self.runs[partition].len();
+                    for (i, start) in (0..old).step_by(Self::MAX_FAN_IN).enumerate() {
+                        let path = self.run_path(partition, &format!("m{pass}-{i}"));
+                        let group = &self.runs[partition][start..old.min(start + Self::MAX_FAN_IN)];
+                        Self::write_run(
+                            &path,
+                            group
+                                .iter()
+                                .map(|path| Self::read_run(path))
+                                .kmerge()
+                                .dedup(),
+                        );
+                        // the merged run is tracked before its inputs are removed, so that it is cleaned up on drop
+                        self.runs[partition].push(path);
+                    }
+                    for path in self.runs[partition].drain(..old) {
+                        remove_file(path).ok();
+                    }
+                    pass += 1;
+                }
+            }
+
+            /// Reads the words of a run.
+            fn read_run(path: &Path) -> impl Iterator<Item = $T> {
+                let file = File::open(path)
+                    .unwrap_or_else(|_| panic!("Failed to open {}", path.to_str().unwrap()));
+                let mut reader = BufReader::new(file);
+                std::iter::from_fn(move || {
+                    let mut bytes = [0u8; size_of::<$T>()];
+                    reader.read_exact(&mut bytes).ok()?;
+                    Some(<$T>::from_le_bytes(bytes))
+                })
+            }
+
+            /// Adds a word, spilling a run whenever the memory budget is reached.
+            #[inline]
+            pub(crate) fn push_word(&mut self, word: $T) {
+                self.words.push(word);
+                if self.words.len() >= self.max_words {
+                    self.spill();
+                }
+            }
+
+            /// Merges the runs and the remaining words into an iterator over the distinct words in increasing order.
+            /// The remaining words are spilled as a last run, so that the iterator only buffers the runs it reads,
+            /// and the runs are removed when it is dropped.
+            pub(crate) fn into_sorted_words(mut self) -> impl Iterator<Item = $T> {
+                if !self.words.is_empty() {
+                    self.spill();
+                }
+                self.words = Vec::new();
+                for partition in 0..self.runs.len() {
+                    self.reduce_runs(partition);
+                }
+                let runs = self.runs.clone();
+                (0..runs.len())
+                    .flat_map(move |partition| {
+                        // the builder lives as long as the iterator, it removes the runs on drop
+                        let _builder = &self;
+                        runs[partition]
+                            .iter()
+                            .map(|path| Self::read_run(path))
+                            .collect_vec()
+                            .into_iter()
+                            .kmerge()
+                    })
+                    .dedup()
+            }
+
+            /// Merges the runs and the remaining words into a [`CBL`].
+            ///
+            /// Only the runs of a single partition are open at the same time, and at most `MAX_FAN_IN` of them:
+            /// partitions with more runs are first merged in several passes.
+            pub fn build(mut self) -> CBL<K, $T, PREFIX_BITS> {
+                self.words.sort_unstable();
+                self.words.dedup();
+                for partition in 0..self.runs.len() {
+                    self.reduce_runs(partition);
+                }
+                let words = &self.words;
+                let runs = &self.runs;
+                let sorted_words = (0..runs.len()).flat_map(|partition| {
+                    let start = words.partition_point(|&word| Self::partition(word) < partition);
+                    let end = words.partition_point(|&word| Self::partition(word) <= partition);
+                    runs[partition]
+                        .iter()
+                        .map(|path| Self::read_run(path))
+                        .collect_vec()
+                        .into_iter()
+                        .kmerge()
+                        .merge(words[start..end].iter().copied())
+                });
+                CBL::<K, $T, PREFIX_BITS>::from_sorted_words(sorted_words, self.cbl.is_canonical())
+            }
+        }
+    };
+}
+
+impl_builder!(u32);
+impl_builder!(u64);
+impl_builder!(u128);
+
+impl<const K: usize, T: Base, const PREFIX_BITS: usize> Drop for ExternalBuilder<K, T, PREFIX_BITS>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    fn drop(&mut self) {
+        for path in self.runs.iter().flatten() {
+            remove_file(path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kmer::{IntKmer, Kmer};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::env::temp_dir;
+
+    const K: usize = 25;
+    type T = u64;
+    type KmerT = IntKmer<K, T>;
+    const N: usize = 100_000;
+
+    #[test]
+    fn test_external_builder() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let seqs = (0..10)
+            .map(|_| {
+                (0..N)
+                    .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+                    .collect_vec()
+            })
+            .collect_vec();
+        let mut set = CBL::<K, T>::new_canonical();
+        let mut builder = ExternalBuilder::<K, T>::new(true, 1 << 16, temp_dir());
+        for seq in seqs.iter() {
+            set.insert_seq(seq);
+            builder.insert_seq(seq);
+        }
+        // every sequence is longer than the budget, and the partitions need several merge passes
+        assert!(builder.words.len() < builder.max_words);
+        assert!(builder.num_runs() > ExternalBuilder::<K, T>::MAX_FAN_IN);
+        let paths = builder.runs.iter().flatten().cloned().collect_vec();
+        assert!(paths.iter().all(|path| path.exists()));
+        let res = builder.build();
+        assert!(paths.iter().all(|path| !path.exists()));
+        assert!(res.is_canonical());
+        assert_eq!(res.count(), set.count());
+        assert!(set.iter().all(|kmer| res.contains(kmer)));
+        assert!(res.contains(KmerT::from_nucs(&seqs[0][..K])));
+    }
+
+    #[test]
+    fn test_sorted_words() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let words = (0..N).map(|_| rng.gen_range(0..(1 << 40))).collect_vec();
+        let mut builder = ExternalBuilder::<K, T>::new(false, 1 << 12, temp_dir());
+        for &word in words.iter() {
+            builder.push_word(word);
+        }
+        assert!(builder.num_runs() > ExternalBuilder::<K, T>::MAX_FAN_IN);
+        let paths = builder.runs.iter().flatten().cloned().collect_vec();
+        let sorted = builder.into_sorted_words();
+        assert_eq!(
+            sorted.collect_vec(),
+            words.into_iter().sorted_unstable().dedup().collect_vec()
+        );
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+}
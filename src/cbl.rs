@@ -1,20 +1,76 @@
 //! Fully dynamic sets of *k*-mers.
 #![allow(clippy::suspicious_arithmetic_impl)]
 
+use crate::builder::ExternalBuilder;
+use crate::checksum::{
+    checksum, invalid_data, ChecksumReader, ChecksumWriter, Fnv, CHECKSUM_BYTES,
+};
+use crate::compact::{self, Compression, Header};
 use crate::kmer::{Base, IntKmer, Kmer, RevComp};
 use crate::necklace::*;
+use crate::snapshot::Snapshot;
+use crate::transaction::Transaction;
 use crate::wordset::*;
 use bincode::{DefaultOptions, Options};
 use core::cmp::min;
+use core::hash::{Hash, Hasher};
 use core::ops::*;
+use iter_set_ops::merge_iters_detailed_by;
+use itertools::Either;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::path::Path;
 
+pub use crate::compact::Format;
+pub use crate::trievec::ContainerKind;
+pub use crate::wordset::{ContainerStats, MemoryUsage, Stats};
+
 const M: usize = 9;
 
+/// Magic bytes identifying the bincode format.
+pub(crate) const BINCODE_MAGIC: [u8; 4] = *b"CBLB";
+/// Version of the bincode format.
+pub(crate) const BINCODE_VERSION: u8 = 2;
+/// Size of the header of the bincode format: its magic bytes, its version, *k* and the size of the prefixes.
+pub(crate) const BINCODE_HEADER_BYTES: usize = BINCODE_MAGIC.len() + 3;
+
+/// Encodes the header of the bincode format for the given parameters.
+pub(crate) fn bincode_header(k: usize, prefix_bits: usize) -> [u8; BINCODE_HEADER_BYTES] {
+    let mut header = [0u8; BINCODE_HEADER_BYTES];
+    header[..BINCODE_MAGIC.len()].copy_from_slice(&BINCODE_MAGIC);
+    header[BINCODE_MAGIC.len()..].copy_from_slice(&[BINCODE_VERSION, k as u8, prefix_bits as u8]);
+    header
+}
+
+/// Reads the header of the bincode format, whose magic bytes must be next in `reader`.
+/// Returns the size of the header and the parameters (*k* and the size of the prefixes) it records,
+/// which the first version of the format did not.
+pub(crate) fn read_bincode_header<R: Read>(
+    reader: &mut R,
+) -> io::Result<(usize, Option<(usize, usize)>)> {
+    let mut header = [0u8; BINCODE_HEADER_BYTES];
+    let version_end = BINCODE_MAGIC.len() + 1;
+    reader.read_exact(&mut header[..version_end])?;
+    match header[BINCODE_MAGIC.len()] {
+        1 => Ok((version_end, None)),
+        BINCODE_VERSION => {
+            reader.read_exact(&mut header[version_end..])?;
+            let params = (
+                header[version_end] as usize,
+                header[version_end + 1] as usize,
+            );
+            Ok((BINCODE_HEADER_BYTES, Some(params)))
+        }
+        version => Err(invalid_data(format!(
+            "unsupported version {version} of the bincode format"
+        ))),
+    }
+}
+
 /// Size of a *k*-mer in bits
 pub const fn kmer_bits<const K: usize>() -> usize {
     2 * K
@@ -31,6 +87,96 @@ pub const fn suffix_bits<const K: usize, const PREFIX_BITS: usize>() -> usize {
         .saturating_sub(PREFIX_BITS)
 }
 
+/// Predicted layout of a set for a given size of prefixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixPrediction {
+    /// Size of the prefixes in bits.
+    pub prefix_bits: usize,
+    /// Number of *k*-mers of the set, estimated when the prediction is made from a sample.
+    pub kmers: usize,
+    /// Number of non-empty buckets.
+    pub buckets: usize,
+    /// Size of the largest bucket.
+    pub max_bucket_size: usize,
+    /// Estimated memory usage in bytes.
+    pub memory: usize,
+}
+
+/// Description of how a *k*-mer is stored in a set, returned by [`CBL::explain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KmerLayout<const K: usize, T: Base> {
+    /// *k*-mer as it is stored, i.e. its canonical form in a canonical set.
+    pub kmer: IntKmer<K, T>,
+    /// Smallest cyclic rotation of the *k*-mer.
+    pub necklace: T,
+    /// Position of the necklace among the rotations of the *k*-mer, in bits.
+    pub pos: usize,
+    /// Necklace and rotation packed into a single integer.
+    pub word: T,
+    /// High bits of the word, selecting the bucket.
+    pub prefix: usize,
+    /// Low bits of the word, stored in the bucket.
+    pub suffix: T,
+    /// `true` if the *k*-mer is contained in the set.
+    pub present: bool,
+    /// Number of words in the bucket.
+    pub bucket_size: usize,
+    /// Id of the container of the bucket, `None` if the bucket is empty or if the set uses the small layout.
+    pub bucket_id: Option<usize>,
+    /// Representation of the bucket, `None` if the bucket is empty or if the set uses the small layout.
+    pub container: Option<ContainerKind>,
+    /// Number of trie nodes, split levels included, that a lookup of the suffix goes through,
+    /// `None` if there is no such container or if it is a vector.
+    pub trie_depth: Option<usize>,
+}
+
+/// Converts a bincode error, keeping the I/O errors as they are.
+pub(crate) fn bincode_error(error: bincode::Error) -> io::Error {
+    match *error {
+        bincode::ErrorKind::Io(error) => error,
+        error => invalid_data(error),
+    }
+}
+
+/// Estimated size in bytes of an entry of the tiered vector.
+const TIERED_ENTRY_BYTES: usize = 4;
+/// Estimated size in bytes of an empty bucket.
+const BUCKET_BYTES: usize = 32;
+
+/// Expected number of non-empty buckets when `words` words fall uniformly into `buckets` buckets.
+fn expected_buckets(buckets: f64, words: f64) -> f64 {
+    -buckets * (-words / buckets).exp_m1()
+}
+
+/// Extrapolates the number of non-empty buckets of a set of `words` words from a sample of `sample_words` of its words,
+/// which occupy `sample_buckets` buckets out of `max_buckets`.
+/// The words are modelled as falling uniformly into an effective number of buckets,
+/// chosen so that the sample is expected to occupy as many buckets as it does.
+fn extrapolate_buckets(
+    sample_buckets: usize,
+    sample_words: usize,
+    words: f64,
+    max_buckets: f64,
+) -> usize {
+    if sample_words == 0 {
+        return 0;
+    }
+    let (observed, sample_words) = (sample_buckets as f64, sample_words as f64);
+    let (mut lo, mut hi) = (observed, max_buckets);
+    // without collisions in the sample, all the buckets can be used
+    if expected_buckets(hi, sample_words) > observed {
+        for _ in 0..64 {
+            let mid = (lo + hi) / 2.0;
+            if expected_buckets(mid, sample_words) < observed {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+    }
+    expected_buckets(hi, words).round().max(observed) as usize
+}
+
 /// A fully dynamic set of *k*-mers.
 ///
 /// # Type Parameters
@@ -53,6 +199,22 @@ where
     necklace_queue_rev: NecklaceQueue<{ kmer_bits::<K>() }, T, { queue_width::<K>() }, true>,
 }
 
+/// Iterator removing the *k*-mers of a [`CBL`] that match a predicate, created by [`CBL::extract_if`].
+pub struct ExtractIf<'a, const K: usize, T: Base, const PREFIX_BITS: usize, F>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    cbl: &'a mut CBL<K, T, PREFIX_BITS>,
+    pred: F,
+    /// Smallest prefix of the buckets that have not been filtered yet.
+    prefix: usize,
+    /// Words removed from the last bucket, not yielded yet.
+    extracted: std::vec::IntoIter<T>,
+}
+
 macro_rules! impl_cbl {
     ($T:ty) => {
         impl<const K: usize, const PREFIX_BITS: usize> CBL<K, $T, PREFIX_BITS>
@@ -65,6 +227,10 @@ macro_rules! impl_cbl {
             const KMER_BITS: usize = 2 * K;
             const POS_BITS: usize = Self::KMER_BITS.next_power_of_two().ilog2() as usize;
             const CHUNK_SIZE: usize = 2048;
+            /// Number of *k*-mers sorted at once by [`iter_sorted`](Self::iter_sorted).
+            const RUN_SIZE: usize = 1 << 20;
+            /// A range is answered by lookups if it holds fewer than `count / RANGE_LOOKUP_RATIO` *k*-mers.
+            const RANGE_LOOKUP_RATIO: usize = 8;
 
             /// Creates an empty [`CBL`].
             #[inline]
@@ -80,7 +246,7 @@ macro_rules! impl_cbl {
 
             /// Creates a [`CBL`] with the given wordset.
             #[inline]
-            fn new_with_wordset(
+            pub(crate) fn new_with_wordset(
                 wordset: WordSet<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>,
                 canonical: bool,
             ) -> Self {
@@ -103,6 +269,28 @@ macro_rules! impl_cbl {
                 }
             }
 
+            /// Creates a [`CBL`] from words sorted in increasing order, possibly with duplicates.
+            /// The words must be obtained with [`seq_words`](Self::seq_words) on a set with the same `canonical` setting.
+            pub fn from_sorted_words<I: IntoIterator<Item = $T>>(
+                words: I,
+                canonical: bool,
+            ) -> Self {
+                Self::new_with_wordset(WordSet::from_sorted_iter(words), canonical)
+            }
+
+            /// Creates a [`CBL`] containing the given *k*-mers.
+            /// The *k*-mers are transformed, sorted and then loaded in a single pass.
+            pub fn from_kmers<I: IntoIterator<Item = IntKmer<K, $T>>>(
+                kmers: I,
+                canonical: bool,
+            ) -> Self {
+                let mut res = Self::new_with_wordset(WordSet::new(), canonical);
+                let mut words: Vec<$T> = kmers.into_iter().map(|kmer| res.get_word(kmer)).collect();
+                words.sort_unstable();
+                res.wordset = WordSet::from_sorted_iter(words);
+                res
+            }
+
             /// Merge multiple [`CBL`] into a new one.
             #[inline]
             pub fn merge(mut cbls: Vec<&mut Self>) -> Self {
@@ -123,16 +311,222 @@ macro_rules! impl_cbl {
                 Self::new_with_wordset(merged, canonical)
             }
 
+            /// Returns a new [`CBL`] containing the *k*-mers present in at least `min_count` of the given sets.
+            pub fn threshold(cbls: &[&Self], min_count: usize) -> Self {
+                let canonical = cbls[0].is_canonical();
+                assert!(cbls.iter().all(|cbl| cbl.is_canonical() == canonical));
+                let mut iters: Vec<_> = cbls.iter().map(|cbl| cbl.words()).collect();
+                let words = merge_iters_detailed_by(&mut iters, |x, y| x.cmp(y))
+                    .filter(|details| details.len() >= min_count)
+                    .map(|details| details[0].1);
+                Self::from_sorted_words(words, canonical)
+            }
+
+            /// Counts the *k*-mers of the given sets by number of sets containing them:
+            /// the `i`-th element of the result is the number of *k*-mers contained in exactly `i` sets.
+            pub fn occurrence_counts(cbls: &[&Self]) -> Vec<usize> {
+                let canonical = cbls[0].is_canonical();
+                assert!(cbls.iter().all(|cbl| cbl.is_canonical() == canonical));
+                let mut counts = vec![0; cbls.len() + 1];
+                let mut iters: Vec<_> = cbls.iter().map(|cbl| cbl.words()).collect();
+                for details in merge_iters_detailed_by(&mut iters, |x, y| x.cmp(y)) {
+                    counts[details.len()] += 1;
+                }
+                counts
+            }
+
+            /// Merge multiple [`CBL`] into a new one, processing prefix ranges in parallel.
+            pub fn par_merge(mut cbls: Vec<&mut Self>) -> Self {
+                let canonical = cbls[0].is_canonical();
+                assert!(cbls.iter().all(|cbl| cbl.is_canonical() == canonical));
+                let wordsets: Vec<_> = cbls.iter_mut().map(|cbl| &mut cbl.wordset).collect();
+                let merged = WordSet::par_merge(wordsets);
+                Self::new_with_wordset(merged, canonical)
+            }
+
+            /// Intersect multiple [`CBL`] into a new one, processing prefix ranges in parallel.
+            pub fn par_intersect(mut cbls: Vec<&mut Self>) -> Self {
+                let canonical = cbls[0].is_canonical();
+                assert!(cbls.iter().all(|cbl| cbl.is_canonical() == canonical));
+                let wordsets: Vec<_> = cbls.iter_mut().map(|cbl| &mut cbl.wordset).collect();
+                let merged = WordSet::par_intersect(wordsets);
+                Self::new_with_wordset(merged, canonical)
+            }
+
+            /// Perfom the union of two sets, processing prefix ranges in parallel.
+            pub fn par_union(&mut self, other: &mut Self) -> Self {
+                assert_eq!(
+                    self.canonical, other.canonical,
+                    "One of the index is canonical while the other isn't"
+                );
+                Self::new_with_wordset(self.wordset.par_union(&mut other.wordset), self.canonical)
+            }
+
+            /// Perfom the intersection of two sets, processing prefix ranges in parallel.
+            pub fn par_intersection(&mut self, other: &mut Self) -> Self {
+                assert_eq!(
+                    self.canonical, other.canonical,
+                    "One of the index is canonical while the other isn't"
+                );
+                Self::new_with_wordset(
+                    self.wordset.par_intersection(&mut other.wordset),
+                    self.canonical,
+                )
+            }
+
+            /// Perfom the difference of two sets, processing prefix ranges in parallel.
+            pub fn par_difference(&mut self, other: &mut Self) -> Self {
+                assert_eq!(
+                    self.canonical, other.canonical,
+                    "One of the index is canonical while the other isn't"
+                );
+                Self::new_with_wordset(
+                    self.wordset.par_difference(&mut other.wordset),
+                    self.canonical,
+                )
+            }
+
+            /// Perfom the symmetric difference of two sets, processing prefix ranges in parallel.
+            pub fn par_symmetric_difference(&mut self, other: &mut Self) -> Self {
+                assert_eq!(
+                    self.canonical, other.canonical,
+                    "One of the index is canonical while the other isn't"
+                );
+                Self::new_with_wordset(
+                    self.wordset.par_symmetric_difference(&mut other.wordset),
+                    self.canonical,
+                )
+            }
+
+            /// Copies the set into a new [`CBL`] using prefixes of `NEW_PREFIX_BITS` bits.
+            /// The words are streamed in increasing order and bulk-loaded into the new set.
+            pub fn reprefix<const NEW_PREFIX_BITS: usize>(&self) -> CBL<K, $T, NEW_PREFIX_BITS>
+            where
+                [(); NEW_PREFIX_BITS.div_ceil(8)]:,
+                [(); suffix_bits::<K, NEW_PREFIX_BITS>().div_ceil(8)]:,
+            {
+                CBL::<K, $T, NEW_PREFIX_BITS>::from_sorted_words(self.words(), self.canonical)
+            }
+
+            /// Predicts the number of buckets, the size of the largest bucket and the memory usage of the set
+            /// for each size of prefixes given in `prefix_bits`.
+            pub fn predict_prefix_bits<I: IntoIterator<Item = usize>>(
+                &self,
+                prefix_bits: I,
+            ) -> Vec<PrefixPrediction> {
+                Self::predict_from_words(self.words(), 1.0, prefix_bits)
+            }
+
+            /// Predicts the layout of the set this set is a sample of, as [`predict_prefix_bits`](Self::predict_prefix_bits),
+            /// if the sample keeps each *k*-mer with probability `fraction`, e.g. with [`insert_seq_sampled`](Self::insert_seq_sampled).
+            /// The number of *k*-mers and the size of the largest bucket are scaled by `1 / fraction`,
+            /// and the number of buckets is extrapolated assuming that the words fall uniformly into the buckets they occupy.
+            pub fn predict_prefix_bits_sampled<I: IntoIterator<Item = usize>>(
+                &self,
+                fraction: f64,
+                prefix_bits: I,
+            ) -> Vec<PrefixPrediction> {
+                Self::predict_from_words(self.words(), fraction, prefix_bits)
+            }
+
+            /// Predicts the layout of a set from the words of a sample keeping each word with probability `fraction`,
+            /// given in increasing order, see [`predict_prefix_bits_sampled`](Self::predict_prefix_bits_sampled).
+            /// The words are counted in a single pass, with a running bucket per size of prefixes.
+            pub(crate) fn predict_from_words<
+                W: IntoIterator<Item = $T>,
+                I: IntoIterator<Item = usize>,
+            >(
+                words: W,
+                fraction: f64,
+                prefix_bits: I,
+            ) -> Vec<PrefixPrediction> {
+                assert!(
+                    fraction > 0.0 && fraction <= 1.0,
+                    "The sampled fraction ({fraction}) must be in (0, 1]"
+                );
+                let word_bits = Self::KMER_BITS + Self::POS_BITS;
+                let prefix_bits = prefix_bits
+                    .into_iter()
+                    .filter(|&p| (1..=32).contains(&p) && p < word_bits)
+                    .collect::<Vec<_>>();
+                // for each size of prefixes: current prefix, size of its bucket,
+                // number of buckets and size of the largest bucket
+                let mut buckets: Vec<(Option<$T>, usize, usize, usize)> =
+                    vec![(None, 0, 0, 0); prefix_bits.len()];
+                let mut sample_words = 0;
+                for word in words {
+                    sample_words += 1;
+                    for (&p, (current, size, count, max_size)) in
+                        prefix_bits.iter().zip(buckets.iter_mut())
+                    {
+                        let prefix = word >> (word_bits - p);
+                        if *current != Some(prefix) {
+                            *current = Some(prefix);
+                            *size = 0;
+                            *count += 1;
+                        }
+                        *size += 1;
+                        *max_size = (*max_size).max(*size);
+                    }
+                }
+                let words = sample_words as f64 / fraction;
+                prefix_bits
+                    .into_iter()
+                    .zip(buckets)
+                    .map(|(p, (_, _, count, max_size))| {
+                        let suffix_bits = word_bits - p;
+                        let buckets = if fraction < 1.0 {
+                            extrapolate_buckets(count, sample_words, words, (1u64 << p) as f64)
+                        } else {
+                            count
+                        };
+                        let memory = (1usize << p) / 8
+                            + buckets * (TIERED_ENTRY_BYTES + BUCKET_BYTES)
+                            + words as usize * suffix_bits.div_ceil(8);
+                        PrefixPrediction {
+                            prefix_bits: p,
+                            kmers: words.round() as usize,
+                            buckets,
+                            max_bucket_size: (max_size as f64 / fraction).round() as usize,
+                            memory,
+                        }
+                    })
+                    .collect()
+            }
+
+            /// Returns `true` if a word is kept by a sample keeping each word with probability `fraction`,
+            /// chosen by hashing the word so that the sample does not depend on the order of the words.
+            #[inline]
+            pub(crate) fn is_sampled(word: $T, fraction: f64) -> bool {
+                let mut hash = Fnv::new();
+                hash.update(&word.to_le_bytes());
+                (hash.finish() as f64) < fraction * u64::MAX as f64
+            }
+
             /// Saves the set to a file.
+            /// The bytes written only depend on the content of the set, not on the order of the operations that built it.
             pub fn save_to_file<P: AsRef<Path> + Copy>(&self, path: P) {
+                self.save_to_file_with_format(path, Format::Bincode);
+            }
+
+            /// Compacts the set (see [`compact`](Self::compact)) and then saves it to a file in the given [`Format`].
+            /// Returns the number of bytes reclaimed by the compaction.
+            pub fn compact_and_save<P: AsRef<Path> + Copy>(
+                &mut self,
+                path: P,
+                format: Format,
+            ) -> usize {
+                let reclaimed = self.compact();
+                self.save_to_file_with_format(path, format);
+                reclaimed
+            }
+
+            /// Saves the set to a file in the given [`Format`].
+            pub fn save_to_file_with_format<P: AsRef<Path> + Copy>(&self, path: P, format: Format) {
                 let index_file = File::create(path).unwrap_or_else(|_| {
                     panic!("Failed to create {}", path.as_ref().to_str().unwrap())
                 });
-                let mut writer = BufWriter::new(index_file);
-                DefaultOptions::new()
-                    .with_varint_encoding()
-                    .reject_trailing_bytes()
-                    .serialize_into(&mut writer, self)
+                self.write_to(BufWriter::new(index_file), format)
                     .unwrap_or_else(|_| {
                         panic!(
                             "Failed to write index to {}",
@@ -141,22 +535,199 @@ macro_rules! impl_cbl {
                     });
             }
 
-            /// Loads the set from a file.
+            /// Writes the set in the given [`Format`], followed by a checksum of the bytes written.
+            pub(crate) fn write_to<W: Write>(&self, writer: W, format: Format) -> io::Result<()> {
+                let mut writer = ChecksumWriter::new(writer);
+                let (compression, level) = match format {
+                    Format::Bincode => {
+                        writer.write_all(&bincode_header(K, PREFIX_BITS))?;
+                        DefaultOptions::new()
+                            .with_varint_encoding()
+                            .reject_trailing_bytes()
+                            .serialize_into(&mut writer, self)
+                            .map_err(bincode_error)?;
+                        return writer.finish().map(|_| ());
+                    }
+                    Format::Compact => (Compression::None, 0),
+                    Format::Zstd(level) => (Compression::Zstd, level),
+                };
+                let header = Header {
+                    compression,
+                    canonical: self.canonical,
+                    k: K,
+                    prefix_bits: PREFIX_BITS,
+                    count: self.count(),
+                };
+                let payload = compact::encode(
+                    self.words().map(|word| word as u128),
+                    PREFIX_BITS,
+                    suffix_bits::<K, PREFIX_BITS>(),
+                );
+                let payload = compact::compress(payload, compression, level);
+                writer.write_all(&header.to_bytes())?;
+                writer.write_all(&payload)?;
+                writer.finish().map(|_| ())
+            }
+
+            /// Loads the set from a file, in any [`Format`].
+            /// It panics if the file cannot be loaded, see [`try_load_from_file`](Self::try_load_from_file) to handle errors.
             pub fn load_from_file<P: AsRef<Path> + Copy>(path: P) -> Self {
-                let index_file = File::open(path).unwrap_or_else(|_| {
-                    panic!("Failed to open {}", path.as_ref().to_str().unwrap())
-                });
-                let reader = BufReader::new(index_file);
-                DefaultOptions::new()
+                Self::try_load_from_file(path).unwrap_or_else(|error| {
+                    panic!(
+                        "Failed to load index from {}: {error}",
+                        path.as_ref().to_str().unwrap()
+                    )
+                })
+            }
+
+            /// Loads the set from a file, in any [`Format`].
+            /// Truncated or corrupted files, and indexes built with other parameters, are rejected
+            /// with an error of kind [`InvalidData`](io::ErrorKind::InvalidData).
+            pub fn try_load_from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+                let index_file = File::open(path)?;
+                let len = index_file.metadata()?.len();
+                Self::read_from(BufReader::new(index_file), len)
+            }
+
+            /// Reads a set written with [`write_to`](Self::write_to), taking at most `len` bytes.
+            /// Files without a header are read with the unversioned bincode layout of older versions.
+            pub(crate) fn read_from<R: BufRead>(mut reader: R, len: u64) -> io::Result<Self> {
+                let start = reader.fill_buf()?;
+                let (is_compact, is_bincode) = (
+                    start.starts_with(&compact::MAGIC),
+                    start.starts_with(&BINCODE_MAGIC),
+                );
+                if is_compact {
+                    let mut bytes = Vec::new();
+                    reader.take(len).read_to_end(&mut bytes)?;
+                    return Self::from_compact_bytes(&bytes);
+                }
+                if !is_bincode {
+                    let mut bytes = Vec::new();
+                    reader.take(len).read_to_end(&mut bytes)?;
+                    return Self::from_legacy_bytes(&bytes);
+                }
+                let mut reader = ChecksumReader::new(reader);
+                let (header_len, params) = read_bincode_header(&mut reader)?;
+                if let Some((k, prefix_bits)) = params {
+                    Self::check_params(k, prefix_bits)?;
+                }
+                // the limit bounds the allocations made for corrupted lengths
+                let res = DefaultOptions::new()
                     .with_varint_encoding()
-                    .reject_trailing_bytes()
-                    .deserialize_from(reader)
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Failed to load index from {}",
-                            path.as_ref().to_str().unwrap()
-                        )
-                    })
+                    .with_limit(len.saturating_sub((header_len + CHECKSUM_BYTES) as u64))
+                    .allow_trailing_bytes()
+                    .deserialize_from(&mut reader)
+                    .map_err(bincode_error)?;
+                reader.finish()?;
+                Self::check_loaded(res)
+            }
+
+            /// Decodes a set saved in the bincode format before it had a header:
+            /// its canonicity followed by its map of buckets, possibly preceded by its number of words,
+            /// in which case it may also be followed by a checksum.
+            fn from_legacy_bytes(bytes: &[u8]) -> io::Result<Self> {
+                // the limit bounds the allocations made for corrupted lengths
+                let options = || {
+                    DefaultOptions::new()
+                        .with_varint_encoding()
+                        .with_limit(bytes.len() as u64)
+                        .reject_trailing_bytes()
+                };
+                if let Some(content_len) = bytes.len().checked_sub(CHECKSUM_BYTES) {
+                    let (content, trailer) = bytes.split_at(content_len);
+                    if u64::from_le_bytes(trailer.try_into().unwrap()) == checksum(content) {
+                        let res = options().deserialize(content).map_err(bincode_error)?;
+                        return Self::check_loaded(res);
+                    }
+                }
+                let res = match options().deserialize::<(
+                    bool,
+                    Buckets<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>,
+                )>(bytes)
+                {
+                    Ok((canonical, Buckets(wordset))) => {
+                        Self::new_with_wordset(wordset, canonical)
+                    }
+                    Err(_) => options().deserialize(bytes).map_err(bincode_error)?,
+                };
+                Self::check_loaded(res)
+            }
+
+            /// Checks the structure of a loaded set and the positions of its words, so that a corrupted file
+            /// whose checksum matches is rejected instead of giving a set that cannot be iterated.
+            /// This is linear in the number of words: checking that they encode necklaces is left to [`validate`](Self::validate).
+            fn check_loaded(set: Self) -> io::Result<Self> {
+                set.wordset
+                    .validate()
+                    .map_err(|errors| invalid_data(errors.join(", ")))?;
+                if let Some(word) = set
+                    .words()
+                    .find(|&word| Self::split_necklace_pos(word).1 >= Self::KMER_BITS)
+                {
+                    return Err(invalid_data(format!(
+                        "the stored word {word:#x} has a position larger than {}",
+                        Self::KMER_BITS
+                    )));
+                }
+                Ok(set)
+            }
+
+            /// Checks that the parameters recorded by a file match the ones of the set.
+            pub(crate) fn check_params(k: usize, prefix_bits: usize) -> io::Result<()> {
+                if k != K {
+                    return Err(invalid_data(format!("the index was built for K={k}")));
+                }
+                if prefix_bits != PREFIX_BITS {
+                    return Err(invalid_data(format!(
+                        "the index was built for PREFIX_BITS={prefix_bits}"
+                    )));
+                }
+                Ok(())
+            }
+
+            /// Decodes the header of the compact format and checks that it matches the parameters of the set.
+            pub(crate) fn read_header(bytes: &[u8]) -> io::Result<Header> {
+                let header =
+                    Header::from_bytes(bytes).ok_or_else(|| invalid_data("invalid header"))?;
+                Self::check_params(header.k, header.prefix_bits)?;
+                Ok(header)
+            }
+
+            /// Decodes a set saved in the compact format.
+            fn from_compact_bytes(bytes: &[u8]) -> io::Result<Self> {
+                let content_len = bytes
+                    .len()
+                    .checked_sub(CHECKSUM_BYTES)
+                    .ok_or_else(|| invalid_data("the index is truncated"))?;
+                let (content, trailer) = bytes.split_at(content_len);
+                if u64::from_le_bytes(trailer.try_into().unwrap()) != checksum(content) {
+                    return Err(invalid_data("checksum mismatch"));
+                }
+                let header = Self::read_header(content)?;
+                let suffix_bits = suffix_bits::<K, PREFIX_BITS>();
+                let payload = compact::decompress(
+                    content[Header::BYTES..].to_vec(),
+                    header.compression,
+                    compact::max_payload_bytes(header.count, PREFIX_BITS, suffix_bits),
+                )?;
+                let mut decoder = compact::Decoder::new(&payload, PREFIX_BITS, suffix_bits)
+                    .map_err(invalid_data)?;
+                let res = Self::from_sorted_words(
+                    decoder.by_ref().map(|word| word as $T),
+                    header.canonical,
+                );
+                if let Some(error) = decoder.error() {
+                    return Err(invalid_data(error));
+                }
+                if res.count() != header.count {
+                    return Err(invalid_data(format!(
+                        "the index contains {} k-mers but {} were expected",
+                        res.count(),
+                        header.count
+                    )));
+                }
+                Self::check_loaded(res)
             }
 
             /// Returns `true` if the set stores canonical *k*-mers.
@@ -165,17 +736,95 @@ macro_rules! impl_cbl {
                 self.canonical
             }
 
-            /// Counts the number of *k*-mers in the set.
+            /// Returns the number of *k*-mers in the set, in constant time.
             pub fn count(&self) -> usize {
                 self.wordset.count()
             }
 
+            /// Checks the internal consistency of the set: the structure of the prefixes and buckets,
+            /// and the validity of every stored word (a necklace and the position of the *k*-mer in it).
+            /// Returns the list of violated invariants, if any.
+            pub fn validate(&self) -> Result<(), Vec<String>> {
+                // the words can only be decoded safely if the structure is consistent
+                self.wordset.validate()?;
+                let mut invalid = 0;
+                let mut example = None;
+                for word in self.words() {
+                    let (_, pos) = Self::split_necklace_pos(word);
+                    if pos >= Self::KMER_BITS || self.get_word(Self::recover_kmer(word)) != word {
+                        invalid += 1;
+                        example.get_or_insert(word);
+                    }
+                }
+                match example {
+                    None => Ok(()),
+                    Some(word) => Err(vec![format!(
+                        "{invalid} stored words do not encode a {}k-mer as its necklace and position (e.g. {word:#x})",
+                        if self.canonical { "canonical " } else { "" }
+                    )]),
+                }
+            }
+
+            /// Releases the memory kept by the buckets emptied by removals: the live buckets are renumbered contiguously
+            /// and their unused capacity is released.
+            /// Returns the number of bytes reclaimed.
+            pub fn compact(&mut self) -> usize {
+                self.wordset.compact()
+            }
+
+            /// Releases the unused capacity of the buckets, without renumbering them.
+            pub fn shrink_to_fit(&mut self) {
+                self.wordset.shrink_to_fit();
+            }
+
+            /// Returns a hash of the set that only depends on its content (the *k*-mers and whether they are canonical).
+            /// Unlike [`Hash`], it is stable across runs, platforms and versions, and can be used to content-address indexes.
+            pub fn content_hash(&self) -> u64 {
+                let mut hash = Fnv::new();
+                hash.update(&(K as u64).to_le_bytes());
+                hash.update(&[self.canonical as u8]);
+                for word in self.words() {
+                    hash.update(&(word as u128).to_le_bytes());
+                }
+                hash.finish()
+            }
+
             /// Returns `true` if there are no *k*-mers in the set.
             #[inline]
             pub fn is_empty(&self) -> bool {
                 self.wordset.is_empty()
             }
 
+            /// Returns an immutable snapshot of the set, that can be queried from other threads while the set is modified.
+            /// Taking a snapshot copies nothing: the snapshot shares the buckets and the prefix structures of the set.
+            /// The first modification of the set afterwards copies the prefix structures, in time proportional to 2^`PREFIX_BITS` plus the number of buckets
+            /// (2 MiB of prefix bitvector with the default `PREFIX_BITS`, plus a few bytes per bucket),
+            /// and every modification only copies the buckets it touches.
+            #[inline]
+            pub fn snapshot(&self) -> Snapshot<K, $T, PREFIX_BITS> {
+                Snapshot { cbl: self.clone() }
+            }
+
+            /// Runs `f` on a [`Transaction`] over the set: the changes it makes are kept if `f` returns `Ok`,
+            /// and rolled back if it returns `Err` or panics.
+            pub fn transaction<R, E, F>(&mut self, f: F) -> Result<R, E>
+            where
+                F: FnOnce(&mut Transaction<'_, K, $T, PREFIX_BITS>) -> Result<R, E>,
+            {
+                let mut tx = Transaction::new(self);
+                match catch_unwind(AssertUnwindSafe(|| f(&mut tx))) {
+                    Ok(Ok(res)) => Ok(res),
+                    Ok(Err(error)) => {
+                        tx.rollback();
+                        Err(error)
+                    }
+                    Err(panic) => {
+                        tx.rollback();
+                        resume_unwind(panic)
+                    }
+                }
+            }
+
             /// Packs a necklace and its position into a single integer.
             #[inline]
             fn merge_necklace_pos(necklace: $T, pos: usize) -> $T {
@@ -185,7 +834,7 @@ macro_rules! impl_cbl {
 
             /// Unpacks a necklace and its position from a single integer.
             #[inline]
-            fn split_necklace_pos(word: $T) -> ($T, usize) {
+            pub(crate) fn split_necklace_pos(word: $T) -> ($T, usize) {
                 (
                     // word / (Self::KMER_BITS as $T),
                     // (word % (Self::KMER_BITS as $T)) as usize,
@@ -196,7 +845,7 @@ macro_rules! impl_cbl {
 
             /// Returns the necklace transformation of a *k*-mer.
             #[inline]
-            fn get_word(&self, kmer: IntKmer<K, $T>) -> $T {
+            pub(crate) fn get_word(&self, kmer: IntKmer<K, $T>) -> $T {
                 let (necklace, pos) = necklace_pos::<{ kmer_bits::<K>() }, $T>(if self.canonical {
                     kmer.canonical().to_int()
                 } else {
@@ -207,7 +856,7 @@ macro_rules! impl_cbl {
 
             /// Recovers a *k*-mer from its necklace transformation.
             #[inline]
-            fn recover_kmer(word: $T) -> IntKmer<K, $T> {
+            pub(crate) fn recover_kmer(word: $T) -> IntKmer<K, $T> {
                 let (necklace, pos) = Self::split_necklace_pos(word);
                 IntKmer::<K, $T>::from_int(revert_necklace_pos::<{ kmer_bits::<K>() }, $T>(
                     necklace, pos,
@@ -234,6 +883,68 @@ macro_rules! impl_cbl {
                 self.wordset.remove(self.get_word(kmer))
             }
 
+            /// Returns the words of the given *k*-mers, sorted so that the words of a bucket are grouped together.
+            fn sorted_words(&self, kmers: &[IntKmer<K, $T>]) -> Vec<$T> {
+                let mut words: Vec<$T> = kmers.iter().map(|&kmer| self.get_word(kmer)).collect();
+                words.sort_unstable();
+                words
+            }
+
+            /// For each *k*-mer, returns `true` if it is contained in the set.
+            /// The *k*-mers are looked up bucket by bucket.
+            pub fn contains_kmers(&self, kmers: &[IntKmer<K, $T>]) -> Vec<bool> {
+                let mut words: Vec<($T, usize)> = kmers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &kmer)| (self.get_word(kmer), i))
+                    .collect();
+                words.sort_unstable();
+                let sorted: Vec<$T> = words.iter().map(|&(word, _)| word).collect();
+                let mut res = vec![false; kmers.len()];
+                for (&(_, i), present) in words.iter().zip(self.wordset.contains_batch(&sorted)) {
+                    res[i] = present;
+                }
+                res
+            }
+
+            /// Adds the given *k*-mers to the set, bucket by bucket.
+            /// Returns the number of *k*-mers that were absent from the set.
+            pub fn insert_kmers(&mut self, kmers: &[IntKmer<K, $T>]) -> usize {
+                let words = self.sorted_words(kmers);
+                let mut count = 0;
+                self.wordset.insert_batch_with(&words, |_| count += 1);
+                count
+            }
+
+            /// Removes the given *k*-mers from the set, bucket by bucket.
+            /// Returns the number of *k*-mers that were present in the set.
+            pub fn remove_kmers(&mut self, kmers: &[IntKmer<K, $T>]) -> usize {
+                let words = self.sorted_words(kmers);
+                let mut count = 0;
+                self.wordset.remove_batch_with(&words, |_| count += 1);
+                count
+            }
+
+            /// Only keeps the *k*-mers for which `f` returns `true`, going through the set bucket by bucket.
+            pub fn retain<F: FnMut(IntKmer<K, $T>) -> bool>(&mut self, mut f: F) {
+                self.extract_if(|kmer| !f(kmer)).for_each(drop);
+            }
+
+            /// Returns an iterator removing the *k*-mers for which `pred` returns `true` and yielding them.
+            /// The set is filtered lazily, one bucket at a time:
+            /// if the iterator is dropped before the end, the *k*-mers of the remaining buckets are kept.
+            pub fn extract_if<F: FnMut(IntKmer<K, $T>) -> bool>(
+                &mut self,
+                pred: F,
+            ) -> ExtractIf<'_, K, $T, PREFIX_BITS, F> {
+                ExtractIf {
+                    cbl: self,
+                    pred,
+                    prefix: 0,
+                    extracted: Vec::new().into_iter(),
+                }
+            }
+
             /// Splits a sequence into chunks of size `Self::CHUNK_SIZE` and returns an iterator over the chunks.
             #[inline]
             fn get_seq_chunks(seq: &'_ [u8]) -> impl Iterator<Item = &'_ [u8]> {
@@ -288,6 +999,21 @@ macro_rules! impl_cbl {
                 }
             }
 
+            /// Returns the words (necklace and position packed together) used to store the *k*-mers of a sequence.
+            pub fn seq_words(&mut self, seq: &[u8]) -> Vec<$T> {
+                assert!(
+                    seq.len() >= K,
+                    "Sequence size ({}) is smaller than K ({})",
+                    seq.len(),
+                    K
+                );
+                let mut res = Vec::with_capacity(seq.len() - K + 1);
+                for chunk in Self::get_seq_chunks(seq) {
+                    res.append(&mut self.get_seq_words(chunk));
+                }
+                res
+            }
+
             /// Returns `true` if the set contains all the *k*-mers of a sequence.
             #[inline]
             pub fn contains_all(&mut self, seq: &[u8]) -> bool {
@@ -338,6 +1064,22 @@ macro_rules! impl_cbl {
                 }
             }
 
+            /// Adds the *k*-mers of a sequence kept by a sample keeping each *k*-mer with probability `fraction`,
+            /// chosen by hashing it, to predict the layout of the full set with [`predict_prefix_bits_sampled`](Self::predict_prefix_bits_sampled).
+            pub fn insert_seq_sampled(&mut self, seq: &[u8], fraction: f64) {
+                assert!(
+                    seq.len() >= K,
+                    "Sequence size ({}) is smaller than K ({})",
+                    seq.len(),
+                    K
+                );
+                for chunk in Self::get_seq_chunks(seq) {
+                    let mut words = self.get_seq_words(chunk);
+                    words.retain(|&word| Self::is_sampled(word, fraction));
+                    self.wordset.insert_batch(&words);
+                }
+            }
+
             /// Removes all the *k*-mers of a sequence from the set.
             #[inline]
             pub fn remove_seq(&mut self, seq: &[u8]) {
@@ -353,58 +1095,437 @@ macro_rules! impl_cbl {
                 }
             }
 
+            /// Returns the underlying wordset.
+            #[inline]
+            pub(crate) fn wordset(&self) -> &WordSet<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }> {
+                &self.wordset
+            }
+
+            /// Returns the prefix of a word, i.e. the index of its bucket.
+            #[inline]
+            pub(crate) fn word_prefix(word: $T) -> usize {
+                (word >> suffix_bits::<K, PREFIX_BITS>()) as usize
+            }
+
+            /// For each word, returns `true` if it is contained in the set.
+            #[inline]
+            pub(crate) fn contains_words(&self, words: &[$T]) -> Vec<bool> {
+                self.wordset.contains_batch(words)
+            }
+
+            /// Adds words to the set.
+            #[inline]
+            pub(crate) fn insert_words(&mut self, words: &[$T]) {
+                self.wordset.insert_batch(words);
+            }
+
+            /// Removes words from the set.
+            #[inline]
+            pub(crate) fn remove_words(&mut self, words: &[$T]) {
+                self.wordset.remove_batch(words);
+            }
+
+            /// Adds words to the set and calls `inserted` on each word that was absent.
+            #[inline]
+            pub(crate) fn insert_words_with<F: FnMut($T)>(&mut self, words: &[$T], inserted: F) {
+                self.wordset.insert_batch_with(words, inserted);
+            }
+
+            /// Removes words from the set and calls `removed` on each word that was present.
+            #[inline]
+            pub(crate) fn remove_words_with<F: FnMut($T)>(&mut self, words: &[$T], removed: F) {
+                self.wordset.remove_batch_with(words, removed);
+            }
+
+            /// Returns an iterator over the words of the set, in increasing order.
+            #[inline]
+            pub(crate) fn words(&self) -> impl Iterator<Item = $T> + '_ {
+                self.wordset.iter::<$T>()
+            }
+
             /// Returns an iterator over the *k*-mers of the set.
             #[inline]
             pub fn iter(&self) -> impl Iterator<Item = IntKmer<K, $T>> + '_ {
                 self.wordset.iter::<$T>().map(Self::recover_kmer)
             }
 
-            /// Returns the proportion of available prefixes used in the set.
-            #[inline]
-            pub fn prefix_load(&self) -> f64 {
-                self.wordset.prefix_load()
+            /// Returns a parallel iterator over the *k*-mers of the set, split by prefix range.
+            /// Collecting it gives the *k*-mers in the same order as [`iter`](Self::iter).
+            pub fn par_iter(&self) -> impl ParallelIterator<Item = IntKmer<K, $T>> + '_ {
+                let num_parts =
+                    WordSet::<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>::num_parts();
+                (0..num_parts).into_par_iter().flat_map_iter(move |part| {
+                    let prefixes = WordSet::<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>::part_prefixes(part, num_parts);
+                    self.wordset
+                        .iter_prefix_range::<$T>(prefixes)
+                        .map(Self::recover_kmer)
+                })
+            }
+
+            /// Returns an iterator over the *k*-mers of the set in increasing order of their 2-bit encoding,
+            /// which is their lexicographic order with A < C < T < G.
+            ///
+            /// The *k*-mers are sorted by runs of `RUN_SIZE`, each run is kept with Elias-Fano coding
+            /// and the runs are merged lazily, so that the sorted copy takes less space than the *k*-mers themselves.
+            /// All the runs are built when the iterator is created and stay in memory until it is dropped,
+            /// see [`iter_sorted_external`](Self::iter_sorted_external) to keep them on disk instead.
+            pub fn iter_sorted(&self) -> impl Iterator<Item = IntKmer<K, $T>> {
+                let runs = Self::sorted_runs(self.iter().map(|kmer| kmer.to_int()), self.count());
+                itertools::kmerge(runs).map(|kmer| IntKmer::<K, $T>::from_int(kmer as $T))
+            }
+
+            /// Returns an iterator over the *k*-mers of the set in increasing order of their 2-bit encoding,
+            /// as [`iter_sorted`](Self::iter_sorted), using at most `memory_budget` bytes to sort them.
+            ///
+            /// The *k*-mers are sorted with the runs of an [`ExternalBuilder`](crate::builder::ExternalBuilder):
+            /// they are buffered until they reach the memory budget and then spilled to a sorted run in `temp_dir`,
+            /// and the runs are merged lazily.
+            /// The runs are removed when the iterator is dropped.
+            pub fn iter_sorted_external<P: AsRef<Path>>(
+                &self,
+                memory_budget: usize,
+                temp_dir: P,
+            ) -> impl Iterator<Item = IntKmer<K, $T>> {
+                let mut builder = ExternalBuilder::<K, $T, PREFIX_BITS>::new(
+                    self.canonical,
+                    memory_budget,
+                    temp_dir,
+                );
+                for kmer in self.iter() {
+                    builder.push_word(kmer.to_int());
+                }
+                builder.into_sorted_words().map(IntKmer::<K, $T>::from_int)
+            }
+
+            /// Sorts the 2-bit encodings of *k*-mers by runs kept with Elias-Fano coding, see [`iter_sorted`](Self::iter_sorted).
+            /// `len` is an upper bound on the number of *k*-mers, used to size the runs.
+            fn sorted_runs<I: Iterator<Item = $T>>(
+                kmers: I,
+                len: usize,
+            ) -> Vec<compact::Decoder<compact::StreamBitReader<io::Cursor<Vec<u8>>>>> {
+                let prefix_bits = min(Self::KMER_BITS, Self::RUN_SIZE.ilog2() as usize);
+                let suffix_bits = Self::KMER_BITS - prefix_bits;
+                let mut kmers = kmers.peekable();
+                let mut run = Vec::with_capacity(min(Self::RUN_SIZE, len));
+                let mut runs = Vec::new();
+                while kmers.peek().is_some() {
+                    run.clear();
+                    run.extend(kmers.by_ref().take(Self::RUN_SIZE));
+                    run.sort_unstable();
+                    let bytes =
+                        compact::encode(run.iter().map(|&kmer| kmer as u128), prefix_bits, suffix_bits);
+                    let len = bytes.len();
+                    let bits = compact::StreamBitReader::new(io::Cursor::new(bytes), len);
+                    runs.push(
+                        compact::Decoder::from_bits(bits, prefix_bits, suffix_bits)
+                            .expect("Failed to decode a sorted run"),
+                    );
+                }
+                runs
+            }
+
+            /// Converts bounds on *k*-mers into an inclusive range of 2-bit encodings, or `None` if it is empty.
+            fn int_bounds<R: RangeBounds<IntKmer<K, $T>>>(range: &R) -> Option<($T, $T)> {
+                let lo = match range.start_bound() {
+                    Bound::Included(kmer) => kmer.to_int(),
+                    Bound::Excluded(kmer) => kmer.to_int().checked_add(1)?,
+                    Bound::Unbounded => 0,
+                };
+                let hi = match range.end_bound() {
+                    Bound::Included(kmer) => kmer.to_int(),
+                    Bound::Excluded(kmer) => kmer.to_int().checked_sub(1)?,
+                    Bound::Unbounded => <$T>::MAX >> (<$T>::BITS as usize - Self::KMER_BITS),
+                };
+                (lo <= hi).then_some((lo, hi))
+            }
+
+            /// Returns an iterator over the *k*-mers of the set within `range`, in increasing order of their 2-bit encoding.
+            /// In a canonical set, only the canonical *k*-mers are considered, as for [`iter`](Self::iter).
+            ///
+            /// A narrow range is answered by looking up all its *k*-mers bucket by bucket, without going through the set.
+            /// A wide range is answered by scanning the set and sorting the *k*-mers that fall into it by runs,
+            /// as in [`iter_sorted`](Self::iter_sorted), which are kept in memory until the iterator is dropped.
+            pub fn range<R: RangeBounds<IntKmer<K, $T>>>(
+                &self,
+                range: R,
+            ) -> impl Iterator<Item = IntKmer<K, $T>> + '_ {
+                let bounds = Self::int_bounds(&range);
+                match bounds {
+                    Some((lo, hi)) if hi - lo < (self.count() / Self::RANGE_LOOKUP_RATIO) as $T => {
+                        let chunk = Self::CHUNK_SIZE as $T;
+                        Either::Left((0..=((hi - lo) / chunk)).flat_map(move |i| {
+                            let start = lo + i * chunk;
+                            let end = start + min(hi - start, chunk - 1);
+                            let kmers: Vec<IntKmer<K, $T>> = (start..=end)
+                                .map(IntKmer::<K, $T>::from_int)
+                                .filter(|kmer| !self.canonical || kmer.is_canonical())
+                                .collect();
+                            let present = self.contains_kmers(&kmers);
+                            kmers
+                                .into_iter()
+                                .zip(present)
+                                .filter_map(|(kmer, present)| present.then_some(kmer))
+                        }))
+                    }
+                    _ => {
+                        let kmers = bounds.into_iter().flat_map(|(lo, hi)| {
+                            self.iter()
+                                .map(|kmer| kmer.to_int())
+                                .filter(move |kmer| (lo..=hi).contains(kmer))
+                        });
+                        let runs = Self::sorted_runs(kmers, self.count());
+                        Either::Right(
+                            itertools::kmerge(runs)
+                                .map(|kmer| IntKmer::<K, $T>::from_int(kmer as $T)),
+                        )
+                    }
+                }
+            }
+
+            /// Returns an iterator over the *k*-mers of the set starting with the given nucleotides (in ASCII),
+            /// in increasing order of their 2-bit encoding.
+            /// The *k*-mers sharing a prefix form a range, see [`range`](Self::range).
+            ///
+            /// The words of the set are not ordered by *k*-mer, so only long prefixes are answered by lookups:
+            /// a prefix of length $m$ covers $4^{k - m}$ *k*-mers, and if this is more than `count / RANGE_LOOKUP_RATIO`
+            /// (with `RANGE_LOOKUP_RATIO` = 8), the whole set is scanned as for a wide [`range`](Self::range).
+            ///
+            /// # Panics
+            ///
+            /// Panics if the prefix is longer than `K` or contains a character other than `A`, `C`, `G` and `T`
+            /// (in upper or lower case).
+            pub fn iter_with_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = IntKmer<K, $T>> + '_ {
+                let bases: Vec<$T> = prefix
+                    .iter()
+                    .map(|&nuc| {
+                        <$T>::from_nuc(&nuc).unwrap_or_else(|| {
+                            panic!("Invalid nucleotide {:?} in the prefix", nuc as char)
+                        })
+                    })
+                    .collect();
+                assert!(
+                    bases.len() <= K,
+                    "Prefix size ({}) is larger than K ({})",
+                    bases.len(),
+                    K
+                );
+                let shift = (2 * (K - bases.len())) as u32;
+                let lo = bases
+                    .into_iter()
+                    .fold(0, |acc, base| (acc << 2) | base)
+                    .checked_shl(shift)
+                    .unwrap_or(0);
+                let mask = <$T>::MAX.checked_shr(<$T>::BITS - shift).unwrap_or(0);
+                self.range(IntKmer::<K, $T>::from_int(lo)..=IntKmer::<K, $T>::from_int(lo | mask))
+            }
+
+            /// Returns the proportion of available prefixes used in the set.
+            #[inline]
+            pub fn prefix_load(&self) -> f64 {
+                self.wordset.prefix_load()
+            }
+
+            /// Returns an iterator over the prefixes of the set, with the size of the associated buckets.
+            #[inline]
+            pub fn buckets_sizes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+                self.wordset.buckets_sizes()
+            }
+
+            /// Returns a Map storing the number of buckets of each size.
+            #[inline]
+            pub fn buckets_size_count(&self) -> BTreeMap<usize, usize> {
+                self.wordset.buckets_size_count()
+            }
+
+            /// Returns a Map storing the proportion of buckets of each size.
+            #[inline]
+            pub fn buckets_load_repartition(&self) -> BTreeMap<usize, f64> {
+                self.wordset.buckets_load_repartition()
+            }
+
+            /// Returns an iterator over the prefixes of the set, with the number of nodes of the associated buckets.
+            #[inline]
+            pub fn buckets_nodes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+                self.wordset.buckets_nodes()
+            }
+
+            /// Returns a Map storing the number of buckets for each node count.
+            #[inline]
+            pub fn buckets_node_count(&self) -> BTreeMap<usize, usize> {
+                self.wordset.buckets_node_count()
+            }
+
+            /// Returns a breakdown of the memory used by the set, in bytes.
+            #[inline]
+            pub fn memory_usage(&self) -> MemoryUsage {
+                self.wordset.memory_usage()
+            }
+
+            /// Returns statistics on the buckets of the set, see [`Stats`].
+            /// Its `count` is the number of *k*-mers of the set.
+            #[inline]
+            pub fn stats(&self) -> Stats {
+                self.wordset.stats()
+            }
+
+            /// Describes how a *k*-mer is stored in the set, whether it is contained in it or not.
+            /// The steps are the same as in [`contains`](Self::contains).
+            pub fn explain(&self, kmer: IntKmer<K, $T>) -> KmerLayout<K, $T> {
+                let stored = if self.canonical {
+                    kmer.canonical()
+                } else {
+                    kmer
+                };
+                let (necklace, pos) = necklace_pos::<{ kmer_bits::<K>() }, $T>(stored.to_int());
+                let word = Self::merge_necklace_pos(necklace, pos);
+                debug_assert_eq!(word, self.get_word(kmer));
+                let (prefix, suffix) =
+                    WordSet::<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>::split_prefix_suffix(
+                        word,
+                    );
+                let (bucket_size, bucket) = self.wordset.locate(prefix);
+                KmerLayout {
+                    kmer: stored,
+                    necklace,
+                    pos,
+                    word,
+                    prefix,
+                    suffix: suffix.get(),
+                    present: self.wordset.contains_split(prefix, &suffix),
+                    bucket_size,
+                    bucket_id: bucket.map(|(id, _)| id),
+                    container: bucket.map(|(_, container)| container.kind()),
+                    trie_depth: bucket.and_then(|(_, container)| container.lookup_depth(&suffix)),
+                }
+            }
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> PartialEq for CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            fn eq(&self, other: &Self) -> bool {
+                self.canonical == other.canonical
+                    && self.count() == other.count()
+                    && self.words().eq(other.words())
+            }
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> Eq for CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> Hash for CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.canonical.hash(state);
+                for word in self.words() {
+                    word.hash(state);
+                }
             }
+        }
 
-            /// Returns an iterator over the prefixes of the set, with the size of the associated buckets.
-            #[inline]
-            pub fn buckets_sizes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
-                self.wordset.buckets_sizes()
+        impl<const K: usize, const PREFIX_BITS: usize> Default for CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            fn default() -> Self {
+                Self::new()
             }
+        }
 
-            /// Returns a Map storing the number of buckets of each size.
-            #[inline]
-            pub fn buckets_size_count(&self) -> BTreeMap<usize, usize> {
-                self.wordset.buckets_size_count()
+        impl<const K: usize, const PREFIX_BITS: usize> FromIterator<IntKmer<K, $T>>
+            for CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Creates a non-canonical set containing the *k*-mers, see [`from_kmers`](Self::from_kmers) for a canonical one.
+            fn from_iter<I: IntoIterator<Item = IntKmer<K, $T>>>(kmers: I) -> Self {
+                Self::from_kmers(kmers, false)
             }
+        }
 
-            /// Returns a Map storing the proportion of buckets of each size.
-            #[inline]
-            pub fn buckets_load_repartition(&self) -> BTreeMap<usize, f64> {
-                self.wordset.buckets_load_repartition()
+        impl<const K: usize, const PREFIX_BITS: usize> Extend<IntKmer<K, $T>>
+            for CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Adds the *k*-mers to the set, by batches of `CHUNK_SIZE` *k*-mers.
+            fn extend<I: IntoIterator<Item = IntKmer<K, $T>>>(&mut self, kmers: I) {
+                let mut kmers = kmers.into_iter();
+                loop {
+                    let batch: Vec<_> = kmers.by_ref().take(Self::CHUNK_SIZE).collect();
+                    if batch.is_empty() {
+                        break;
+                    }
+                    self.insert_kmers(&batch);
+                }
             }
+        }
 
-            /// Returns an iterator over the prefixes of the set, with the number of nodes of the associated buckets.
-            #[inline]
-            pub fn buckets_nodes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
-                self.wordset.buckets_nodes()
-            }
+        impl<'a, const K: usize, const PREFIX_BITS: usize> IntoIterator for &'a CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            type Item = IntKmer<K, $T>;
+            type IntoIter = Box<dyn Iterator<Item = IntKmer<K, $T>> + 'a>;
 
-            /// Returns a Map storing the number of buckets for each node count.
-            #[inline]
-            pub fn buckets_node_count(&self) -> BTreeMap<usize, usize> {
-                self.wordset.buckets_node_count()
+            fn into_iter(self) -> Self::IntoIter {
+                Box::new(self.iter())
             }
         }
 
-        impl<const K: usize, const PREFIX_BITS: usize> Default for CBL<K, $T, PREFIX_BITS>
+        impl<const K: usize, const PREFIX_BITS: usize, F: FnMut(IntKmer<K, $T>) -> bool> Iterator
+            for ExtractIf<'_, K, $T, PREFIX_BITS, F>
         where
             [(); kmer_bits::<K>()]:,
             [(); PREFIX_BITS.div_ceil(8)]:,
             [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
             [(); queue_width::<K>()]:,
         {
-            fn default() -> Self {
-                Self::new()
+            type Item = IntKmer<K, $T>;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                loop {
+                    if let Some(word) = self.extracted.next() {
+                        return Some(CBL::<K, $T, PREFIX_BITS>::recover_kmer(word));
+                    }
+                    let prefix = self.cbl.wordset.next_prefix(self.prefix)?;
+                    self.prefix = prefix + 1;
+                    let pred = &mut self.pred;
+                    self.extracted = self
+                        .cbl
+                        .wordset
+                        .extract_bucket_if(prefix, |word| {
+                            pred(CBL::<K, $T, PREFIX_BITS>::recover_kmer(word))
+                        })
+                        .into_iter();
+                }
             }
         }
 
@@ -912,4 +2033,507 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_par_iter() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs[..100]);
+        assert_eq!(set.par_iter().collect::<Vec<_>>(), set.iter().collect_vec());
+        let mut set2 = CBL::<K, T>::new_canonical();
+        set2.insert_seq(&nucs);
+        assert_eq!(
+            set2.par_iter().collect::<Vec<_>>(),
+            set2.iter().collect_vec()
+        );
+        let union = set.par_union(&mut set2);
+        assert!(union == &mut set | &mut set2);
+        assert_eq!(union.par_iter().count(), union.count());
+    }
+
+    #[test]
+    fn test_threshold() {
+        const C: usize = 6;
+        const N: usize = 10_000;
+        const K: usize = 15;
+        const PREFIX_BITS: usize = 14;
+        type T = u32;
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity((C + 2) * N);
+        for _ in 0..((C + 2) * N) {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+
+        let mut sets = vec![CBL::<K, T, PREFIX_BITS>::new_canonical(); C];
+        for (i, set) in sets.iter_mut().enumerate() {
+            // each set overlaps the next two ones
+            set.insert_seq(&nucs[(i * N)..((i + 3) * N)]);
+        }
+        let refs = sets.iter().collect_vec();
+        let counts = CBL::<K, T, PREFIX_BITS>::occurrence_counts(&refs);
+        assert_eq!(counts.len(), C + 1);
+        assert_eq!(counts[0], 0);
+        for min_count in 1..=C {
+            let res = CBL::<K, T, PREFIX_BITS>::threshold(&refs, min_count);
+            assert_eq!(res.count(), counts[min_count..].iter().sum::<usize>());
+            for kmer in res.iter() {
+                assert!(sets.iter().filter(|set| set.contains(kmer)).count() >= min_count);
+            }
+        }
+        let mut copies = sets.clone();
+        let union = CBL::<K, T, PREFIX_BITS>::merge(copies.iter_mut().collect());
+        assert!(CBL::<K, T, PREFIX_BITS>::threshold(&refs, 1) == union);
+        let inter = CBL::<K, T, PREFIX_BITS>::intersect(copies[..3].iter_mut().collect());
+        assert!(CBL::<K, T, PREFIX_BITS>::threshold(&refs[..3], 3) == inter);
+    }
+
+    #[test]
+    fn test_reprefix() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs);
+        let expected = set.iter().sorted().collect_vec();
+        let small = set.reprefix::<16>();
+        assert!(small.is_canonical());
+        assert_eq!(small.iter().sorted().collect_vec(), expected);
+        let large = set.reprefix::<28>();
+        assert_eq!(large.iter().sorted().collect_vec(), expected);
+        let mut back = large.reprefix::<24>();
+        assert!(back.contains_seq(&nucs).iter().all(|&b| b));
+    }
+
+    #[test]
+    fn test_predict_prefix_bits() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new();
+        set.insert_seq(&nucs);
+        let predictions = set.predict_prefix_bits(20..=28);
+        assert_eq!(predictions.len(), 9);
+        let current = predictions.iter().find(|p| p.prefix_bits == 24).unwrap();
+        assert_eq!(current.kmers, set.count());
+        assert_eq!(current.buckets, set.buckets_sizes().count());
+        assert_eq!(
+            current.max_bucket_size,
+            set.buckets_sizes().map(|(_, size)| size).max().unwrap()
+        );
+        // a sample of a tenth of the k-mers predicts the full set
+        let mut sample = CBL::<K, T>::new();
+        sample.insert_seq_sampled(&nucs, 0.1);
+        assert!(sample.count() < set.count() / 5);
+        let sampled = sample.predict_prefix_bits_sampled(0.1, 20..=28);
+        for (exact, estimate) in predictions.iter().zip(sampled.iter()) {
+            assert_eq!(exact.prefix_bits, estimate.prefix_bits);
+            assert!(estimate.kmers.abs_diff(exact.kmers) < exact.kmers / 10);
+            assert!(estimate.buckets.abs_diff(exact.buckets) < exact.buckets / 5);
+            assert!(estimate.memory.abs_diff(exact.memory) < exact.memory / 5);
+        }
+    }
+
+    #[test]
+    fn test_from_kmers() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs);
+        let mut res = CBL::<K, T>::from_kmers(KmerT::iter_from_nucs(nucs.iter()), true);
+        assert!(res.is_canonical());
+        assert_eq!(res.count(), set.count());
+        assert!(res.contains_seq(&nucs).iter().all(|&b| b));
+    }
+
+    #[test]
+    fn test_collection_traits() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let kmers = KmerT::iter_from_nucs(nucs.iter()).collect_vec();
+        let mut expected = CBL::<K, T>::new();
+        expected.insert_seq(&nucs);
+
+        let set: CBL<K, T> = kmers.iter().copied().collect();
+        assert!(set == expected);
+        assert_eq!((&set).into_iter().count(), set.count());
+        let mut extended = CBL::<K, T>::new();
+        extended.extend(kmers[..(N / 2)].iter().copied());
+        extended.extend(kmers.iter().copied());
+        assert!(extended == expected);
+
+        let (first, second) = kmers.split_at(N / 2);
+        let mut batch = CBL::<K, T>::new();
+        assert_eq!(batch.insert_kmers(first), batch.count());
+        for (&kmer, present) in kmers.iter().zip(batch.contains_kmers(&kmers)) {
+            assert_eq!(batch.contains(kmer), present);
+        }
+        let count = batch.count();
+        let removed = batch.remove_kmers(second);
+        assert_eq!(batch.count(), count - removed);
+        assert!(second.iter().all(|&kmer| !batch.contains(kmer)));
+
+        let even = |kmer: KmerT| kmer.to_int() % 2 == 0;
+        let mut retained = set.clone();
+        retained.retain(even);
+        assert!(retained.iter().all(even));
+        assert_eq!(
+            retained.count(),
+            set.iter().filter(|&kmer| even(kmer)).count()
+        );
+        let mut filtered = set.clone();
+        let extracted = filtered.extract_if(|kmer| !even(kmer)).collect_vec();
+        assert!(filtered == retained);
+        assert_eq!(extracted.len() + filtered.count(), set.count());
+        assert!(extracted
+            .iter()
+            .all(|&kmer| !even(kmer) && set.contains(kmer)));
+        assert!(filtered.validate().is_ok());
+        // the buckets that were not reached are kept
+        let mut partial = set.clone();
+        partial.extract_if(|_| true).take(10).for_each(drop);
+        assert!(partial.count() > 0 && partial.count() < set.count());
+        let mut small: CBL<K, T> = kmers[..100].iter().copied().collect();
+        small.retain(even);
+        assert!(small.iter().all(even));
+        assert!(set == expected);
+    }
+
+    #[test]
+    fn test_sorted_range_queries() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        for mut set in [CBL::<K, T>::new(), CBL::<K, T>::new_canonical()] {
+            set.insert_seq(&nucs);
+            let mut sorted = set.iter().collect_vec();
+            sorted.sort_unstable();
+            assert!(set.iter_sorted().eq(sorted.iter().copied()));
+            assert!(set.range(..).eq(sorted.iter().copied()));
+
+            let (lo, hi) = (sorted[N / 4], sorted[N / 4 + 1000]);
+            assert!(set
+                .range(lo..hi)
+                .eq(sorted[(N / 4)..(N / 4 + 1000)].iter().copied()));
+            let narrow = KmerT::from_int(lo.to_int() + 100_000);
+            let expected = sorted
+                .iter()
+                .copied()
+                .filter(|kmer| (lo..=narrow).contains(kmer));
+            assert!(set.range(lo..=narrow).eq(expected));
+            assert_eq!(set.range(hi..lo).count(), 0);
+
+            for m in [0, 3, K - 6, K] {
+                let prefix = &lo.to_nucs()[..m];
+                let expected = sorted
+                    .iter()
+                    .copied()
+                    .filter(|kmer| kmer.to_nucs().starts_with(prefix));
+                assert!(set.iter_with_prefix(prefix).eq(expected));
+            }
+            // invalid nucleotides are rejected instead of being skipped
+            assert!(
+                catch_unwind(AssertUnwindSafe(|| set.iter_with_prefix(b"ACN").count())).is_err()
+            );
+
+            let sorted_external = set.iter_sorted_external(1 << 12, std::env::temp_dir());
+            assert!(sorted_external.eq(sorted.iter().copied()));
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs);
+        let sizes: BTreeMap<usize, usize> = set.buckets_sizes().collect();
+        for kmer in KmerT::iter_from_nucs(nucs.iter()).step_by(1000) {
+            let layout = set.explain(kmer);
+            assert!(layout.present);
+            assert_eq!(layout.kmer, kmer.canonical());
+            assert_eq!(layout.word, set.get_word(kmer));
+            assert_eq!(CBL::<K, T>::recover_kmer(layout.word), layout.kmer);
+            assert_eq!(
+                revert_necklace_pos::<{ kmer_bits::<K>() }, T>(layout.necklace, layout.pos),
+                layout.kmer.to_int()
+            );
+            assert_eq!(
+                ((layout.prefix as T) << suffix_bits::<K, 24>()) | layout.suffix,
+                layout.word
+            );
+            assert_eq!(layout.bucket_size, sizes[&layout.prefix]);
+            assert!(layout.bucket_id.is_some());
+            // a lookup goes through one node per byte of the suffix, plus one per split level
+            let bytes = suffix_bits::<K, 24>().div_ceil(8);
+            match layout.container {
+                Some(ContainerKind::Vec) => assert_eq!(layout.trie_depth, None),
+                Some(ContainerKind::Trie) => assert_eq!(layout.trie_depth, Some(bytes)),
+                Some(ContainerKind::Split) => assert!(layout.trie_depth.unwrap() > bytes),
+                None => panic!("the bucket of a present k-mer has no container"),
+            }
+        }
+        let absent = KmerT::from_int(0);
+        set.remove(absent);
+        assert!(!set.explain(absent).present);
+
+        let mut small = CBL::<K, T>::new();
+        small.insert_seq(&nucs[..100]);
+        let kmer = KmerT::from_nucs(&nucs[..K]);
+        let layout = small.explain(kmer);
+        assert!(layout.present && layout.bucket_size >= 1);
+        assert_eq!(layout.bucket_id, None);
+        assert_eq!(layout.container, None);
+    }
+
+    #[test]
+    fn test_from_sorted_words() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new();
+        set.insert_seq(&nucs);
+        let mut words = set.seq_words(&nucs);
+        words.sort_unstable();
+        let res = CBL::<K, T>::from_sorted_words(words, false);
+        assert_eq!(
+            res.iter().sorted().collect_vec(),
+            set.iter().sorted().collect_vec()
+        );
+    }
+
+    #[test]
+    fn test_compact_format() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs);
+        let dir = std::env::temp_dir();
+        let bincode_path = dir.join(format!("cbl-test-bincode-{}", std::process::id()));
+        let compact_path = dir.join(format!("cbl-test-compact-{}", std::process::id()));
+        set.save_to_file_with_format(&bincode_path, Format::Bincode);
+        set.save_to_file_with_format(&compact_path, Format::Compact);
+        assert!(
+            std::fs::metadata(&compact_path).unwrap().len()
+                < std::fs::metadata(&bincode_path).unwrap().len()
+        );
+        for path in [&bincode_path, &compact_path] {
+            let res = CBL::<K, T>::load_from_file(path);
+            assert!(res.is_canonical());
+            assert_eq!(res.iter().collect_vec(), set.iter().collect_vec());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_format() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new();
+        set.insert_seq(&nucs);
+        let path = std::env::temp_dir().join(format!("cbl-test-zstd-{}", std::process::id()));
+        set.save_to_file_with_format(&path, Format::Zstd(3));
+        let res = CBL::<K, T>::load_from_file(&path);
+        assert_eq!(res.iter().collect_vec(), set.iter().collect_vec());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_files() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = (0..10_000)
+            .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+            .collect_vec();
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs);
+        let load = |bytes: &[u8]| CBL::<K, T>::read_from(bytes, bytes.len() as u64);
+        let mut formats = vec![Format::Bincode, Format::Compact];
+        if cfg!(feature = "zstd") {
+            formats.push(Format::Zstd(3));
+        }
+        for format in formats {
+            let mut bytes = Vec::new();
+            set.write_to(&mut bytes, format).unwrap();
+            assert_eq!(load(&bytes).unwrap().content_hash(), set.content_hash());
+            for i in 0..500 {
+                let mut corrupted = bytes.clone();
+                let pos = rng.gen_range(0..bytes.len());
+                match i % 5 {
+                    0 => corrupted[pos] ^= 1u8 << rng.gen_range(0..8u32),
+                    1 => corrupted.truncate(pos),
+                    2 => corrupted.insert(pos, rng.gen()),
+                    3 => {
+                        corrupted.remove(pos);
+                    }
+                    _ => {
+                        let end = (pos + 8).min(corrupted.len());
+                        corrupted[pos..end].fill(0xff);
+                    }
+                }
+                if corrupted == bytes {
+                    continue;
+                }
+                assert!(
+                    load(&corrupted).is_err(),
+                    "{format:?} corruption {i} was loaded"
+                );
+                // with a matching checksum, the corruption is either rejected or gives a usable set
+                if corrupted.len() < CHECKSUM_BYTES {
+                    continue;
+                }
+                let content_len = corrupted.len() - CHECKSUM_BYTES;
+                let sum = checksum(&corrupted[..content_len]);
+                corrupted[content_len..].copy_from_slice(&sum.to_le_bytes());
+                if let Ok(res) = load(&corrupted) {
+                    assert!(
+                        res.wordset.validate().is_ok(),
+                        "{format:?} corruption {i} gave an invalid set"
+                    );
+                    assert_eq!(res.iter().count(), res.count());
+                }
+            }
+        }
+        let path = std::env::temp_dir().join(format!("cbl-test-corrupted-{}", std::process::id()));
+        set.save_to_file_with_format(&path, Format::Bincode);
+        let error = CBL::<25, u64>::try_load_from_file(&path).err().unwrap();
+        assert!(error.to_string().contains("K=59"));
+        let error = CBL::<K, T, 20>::try_load_from_file(&path).err().unwrap();
+        assert!(error.to_string().contains("PREFIX_BITS=24"));
+        set.save_to_file_with_format(&path, Format::Compact);
+        assert!(CBL::<25, u64>::try_load_from_file(&path).is_err());
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        let error = CBL::<K, T>::try_load_from_file(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_canonical_serialization() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let kmers = KmerT::iter_from_nucs(nucs.iter()).collect_vec();
+        let mut set = CBL::<K, T>::new();
+        for &kmer in kmers.iter() {
+            set.insert(kmer);
+        }
+        let mut set2 = CBL::<K, T>::new();
+        for &kmer in kmers.iter().rev() {
+            set2.insert(kmer);
+        }
+        let mut extra = kmers.clone();
+        extra.shuffle(&mut rng);
+        for &kmer in extra.iter().take(N / 2) {
+            set2.remove(kmer);
+        }
+        for &kmer in extra.iter().take(N / 2) {
+            set2.insert(kmer);
+        }
+        assert!(set == set2);
+        assert_eq!(set.content_hash(), set2.content_hash());
+        let options = DefaultOptions::new()
+            .with_varint_encoding()
+            .reject_trailing_bytes();
+        assert_eq!(
+            options.serialize(&set).unwrap(),
+            options.serialize(&set2).unwrap()
+        );
+        set2.remove(kmers[0]);
+        assert!(set != set2);
+        assert_ne!(set.content_hash(), set2.content_hash());
+    }
+
+    #[test]
+    fn test_legacy_bincode_layouts() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = (0..N)
+            .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+            .collect_vec();
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs);
+        let options = DefaultOptions::new()
+            .with_varint_encoding()
+            .reject_trailing_bytes();
+        // canonicity, number of words and map of buckets, without header
+        let with_count = options.serialize(&set).unwrap();
+        // canonicity and map of buckets
+        let count_len = options.serialize(&set.count()).unwrap().len();
+        let map_only = [&with_count[..1], &with_count[(1 + count_len)..]].concat();
+        // canonicity, number of words and map of buckets, followed by a checksum
+        let mut with_checksum = with_count.clone();
+        with_checksum.extend_from_slice(&checksum(&with_count).to_le_bytes());
+        // first version of the header, without the parameters of the set
+        let mut first_version = [&BINCODE_MAGIC[..], &[1u8][..], &with_count[..]].concat();
+        first_version.extend_from_slice(&checksum(&first_version).to_le_bytes());
+        for bytes in [with_count, map_only, with_checksum, first_version] {
+            let res = CBL::<K, T>::read_from(&bytes[..], bytes.len() as u64).unwrap();
+            assert!(res.is_canonical());
+            assert_eq!(res.content_hash(), set.content_hash());
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        let mut set = CBL::<K, T>::new();
+        set.insert_seq(&nucs);
+        set.remove_seq(&nucs[..(N - 10_000)]);
+        let before = set.clone();
+        assert!(set.compact() > 0);
+        assert!(set == before);
+        assert_eq!(set.compact(), 0);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut nucs = Vec::with_capacity(N);
+        for _ in 0..N {
+            nucs.push(u8::bases()[rng.gen_range(0..4)].to_nuc());
+        }
+        for mut set in [CBL::<K, T>::new(), CBL::<K, T>::new_canonical()] {
+            assert_eq!(set.validate(), Ok(()));
+            set.insert_seq(&nucs);
+            set.remove_seq(&nucs[..(N / 2)]);
+            assert_eq!(set.validate(), Ok(()));
+            // 0b10 is a rotation of 0b01 so it is not a necklace
+            set.insert_words(&[CBL::<K, T>::merge_necklace_pos(0b10, 0)]);
+            assert_eq!(set.validate().unwrap_err().len(), 1);
+        }
+    }
 }
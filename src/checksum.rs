@@ -0,0 +1,146 @@
+//! 64-bit FNV-1a hashing, used for content hashes and for the checksums ending index files.
+
+use std::io::{Read, Result, Write};
+
+/// Number of bytes of the checksum written at the end of index files.
+pub(crate) const CHECKSUM_BYTES: usize = 8;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Incremental 64-bit FNV-1a hash.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fnv(u64);
+
+impl Fnv {
+    #[inline]
+    pub fn new() -> Self {
+        Self(FNV_OFFSET)
+    }
+
+    #[inline]
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    #[inline]
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A writer hashing all the bytes written through it.
+pub(crate) struct ChecksumWriter<W: Write> {
+    inner: W,
+    hash: Fnv,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hash: Fnv::new(),
+        }
+    }
+
+    /// Writes the checksum of the bytes written so far and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        let checksum = self.hash.finish();
+        self.inner.write_all(&checksum.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hash.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader hashing all the bytes read through it.
+pub(crate) struct ChecksumReader<R: Read> {
+    inner: R,
+    hash: Fnv,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hash: Fnv::new(),
+        }
+    }
+
+    /// Reads the checksum following the bytes read so far and checks that they match
+    /// and that nothing follows it.
+    pub fn finish(mut self) -> Result<()> {
+        let mut bytes = [0u8; CHECKSUM_BYTES];
+        self.inner.read_exact(&mut bytes)?;
+        if u64::from_le_bytes(bytes) != self.hash.finish() {
+            return Err(invalid_data("checksum mismatch"));
+        }
+        if self.inner.read(&mut [0u8])? != 0 {
+            return Err(invalid_data("unexpected bytes after the checksum"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hash.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Returns the checksum of a slice of bytes.
+#[inline]
+pub(crate) fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash = Fnv::new();
+    hash.update(bytes);
+    hash.finish()
+}
+
+/// Creates an error for invalid or corrupted data.
+#[inline]
+pub(crate) fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+    error: E,
+) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let mut writer = ChecksumWriter::new(Vec::new());
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        let mut bytes = writer.finish().unwrap();
+        assert_eq!(bytes.len(), 11 + CHECKSUM_BYTES);
+        assert_eq!(
+            u64::from_le_bytes(bytes[11..].try_into().unwrap()),
+            checksum(b"hello world")
+        );
+        let mut reader = ChecksumReader::new(&bytes[..]);
+        reader.read_exact(&mut [0u8; 11]).unwrap();
+        assert!(reader.finish().is_ok());
+        bytes[0] ^= 1;
+        let mut reader = ChecksumReader::new(&bytes[..]);
+        reader.read_exact(&mut [0u8; 11]).unwrap();
+        assert!(reader.finish().is_err());
+    }
+}
@@ -0,0 +1,759 @@
+//! Compact serialization of sorted words, using Elias-Fano coding for prefixes and suffixes.
+//!
+//! The words are grouped in buckets sharing the same prefix.
+//! For each bucket, the payload contains the prefix (as an element of the Elias-Fano sequence of prefixes),
+//! the size of the bucket in Elias gamma coding, and the suffixes as an Elias-Fano sequence.
+//! Elias-Fano sequences are interleaved: each element is stored as the unary gap of its high part followed by its low bits.
+//! The file ends with a checksum of the header and the payload.
+
+use crate::checksum::invalid_data;
+use itertools::Itertools;
+use std::io::{self, Read, Write};
+
+/// Magic bytes identifying the compact format.
+pub(crate) const MAGIC: [u8; 4] = *b"CBLC";
+/// Version of the compact format.
+pub(crate) const VERSION: u8 = 2;
+
+/// Serialization format used to save a [`CBL`](crate::CBL).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Direct serialization of the data structure with bincode.
+    #[default]
+    Bincode,
+    /// Elias-Fano coding of the buckets.
+    Compact,
+    /// Elias-Fano coding of the buckets, compressed with zstd at the given level.
+    /// Requires the `zstd` feature.
+    Zstd(i32),
+}
+
+/// Compression applied to the payload of the compact format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Compression {
+    None = 0,
+    Zstd = 1,
+}
+
+impl Compression {
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::None),
+            1 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Header of the compact format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub compression: Compression,
+    pub canonical: bool,
+    pub k: usize,
+    pub prefix_bits: usize,
+    pub count: usize,
+}
+
+impl Header {
+    pub(crate) const BYTES: usize = MAGIC.len() + 5 + 8;
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::BYTES] {
+        let mut bytes = [0u8; Self::BYTES];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.compression as u8;
+        bytes[6] = self.canonical as u8;
+        bytes[7] = self.k as u8;
+        bytes[8] = self.prefix_bits as u8;
+        bytes[9..].copy_from_slice(&(self.count as u64).to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::BYTES || bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return None;
+        }
+        Some(Self {
+            compression: Compression::from_byte(bytes[5])?,
+            canonical: match bytes[6] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+            k: bytes[7] as usize,
+            prefix_bits: bytes[8] as usize,
+            count: u64::from_le_bytes(bytes[9..Self::BYTES].try_into().unwrap()) as usize,
+        })
+    }
+}
+
+/// Writes integers of arbitrary width into a stream of bits.
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            buffer: 0,
+            len: 0,
+        }
+    }
+
+    /// Writes the `width` lowest bits of `value`.
+    pub fn write_bits(&mut self, mut value: u128, mut width: usize) {
+        while width > 0 {
+            let take = width.min(32);
+            self.buffer |= ((value & ((1 << take) - 1)) as u64) << self.len;
+            self.len += take;
+            value >>= take;
+            width -= take;
+            while self.len >= 8 {
+                self.bytes.push(self.buffer as u8);
+                self.buffer >>= 8;
+                self.len -= 8;
+            }
+        }
+    }
+
+    /// Writes `n` in unary, i.e. `n` zeros followed by a one.
+    pub fn write_unary(&mut self, mut n: usize) {
+        while n >= 32 {
+            self.write_bits(0, 32);
+            n -= 32;
+        }
+        self.write_bits(1 << n, n + 1);
+    }
+
+    /// Writes `n ≥ 1` in Elias gamma coding.
+    pub fn write_gamma(&mut self, n: usize) {
+        debug_assert!(n > 0);
+        let bits = n.ilog2() as usize;
+        self.write_unary(bits);
+        self.write_bits(n as u128, bits);
+    }
+
+    /// Writes the complete bytes to `writer`, keeping the last partial byte.
+    pub fn flush_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.bytes)?;
+        self.bytes.clear();
+        Ok(())
+    }
+
+    /// Pads the last byte with zeros and returns the bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Stream of bits read by the [`Decoder`].
+pub(crate) trait ReadBits {
+    /// Reads an integer of `width` bits, returns `None` at the end of the stream.
+    fn read_bits(&mut self, width: usize) -> Option<u128>;
+
+    /// Reads an integer in unary, returns `None` at the end of the stream.
+    fn read_unary(&mut self) -> Option<usize>;
+
+    /// Returns the number of bits left in the stream, or an upper bound on it.
+    fn remaining_bits(&self) -> usize;
+
+    /// Reads an integer in Elias gamma coding, returns `None` at the end of the stream.
+    fn read_gamma(&mut self) -> Option<usize> {
+        let bits = self.read_unary()?;
+        if bits >= usize::BITS as usize {
+            return None;
+        }
+        let low = self.read_bits(bits)? as usize;
+        Some((1 << bits) | low)
+    }
+}
+
+/// Reads integers of arbitrary width from a stream of bits.
+pub(crate) struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+}
+
+impl ReadBits for BitReader<'_> {
+    fn read_bits(&mut self, width: usize) -> Option<u128> {
+        if width > 128 || self.pos + width > self.bytes.len() * 8 {
+            return None;
+        }
+        let mut value = 0u128;
+        let mut done = 0;
+        while done < width {
+            let offset = self.pos % 8;
+            let take = (width - done).min(8 - offset);
+            let bits = (self.bytes[self.pos / 8] >> offset) & (((1u16 << take) - 1) as u8);
+            value |= (bits as u128) << done;
+            done += take;
+            self.pos += take;
+        }
+        Some(value)
+    }
+
+    fn read_unary(&mut self) -> Option<usize> {
+        let mut n = 0;
+        loop {
+            let rest = *self.bytes.get(self.pos / 8)? >> (self.pos % 8);
+            if rest != 0 {
+                let zeros = rest.trailing_zeros() as usize;
+                self.pos += zeros + 1;
+                return Some(n + zeros);
+            }
+            let skip = 8 - self.pos % 8;
+            n += skip;
+            self.pos += skip;
+        }
+    }
+
+    #[inline]
+    fn remaining_bits(&self) -> usize {
+        self.bytes.len() * 8 - self.pos
+    }
+}
+
+/// Reads integers of arbitrary width from a stream of bits pulled from a reader, one byte at a time.
+pub(crate) struct StreamBitReader<R: Read> {
+    reader: R,
+    byte: u8,
+    /// Number of bits of `byte` already read.
+    offset: usize,
+    /// Upper bound on the number of bits left.
+    remaining: usize,
+}
+
+impl<R: Read> StreamBitReader<R> {
+    /// Reads at most `max_bytes` bytes from `reader`.
+    pub fn new(reader: R, max_bytes: usize) -> Self {
+        Self {
+            reader,
+            byte: 0,
+            offset: 8,
+            remaining: max_bytes.saturating_mul(8),
+        }
+    }
+
+    /// Returns the reader, positioned after the last byte read.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Loads the next byte if the current one has been read entirely.
+    #[inline]
+    fn load(&mut self) -> Option<()> {
+        if self.offset == 8 {
+            let mut byte = [0u8];
+            self.reader.read_exact(&mut byte).ok()?;
+            self.byte = byte[0];
+            self.offset = 0;
+        }
+        Some(())
+    }
+}
+
+impl<R: Read> ReadBits for StreamBitReader<R> {
+    fn read_bits(&mut self, width: usize) -> Option<u128> {
+        if width > 128 || width > self.remaining {
+            return None;
+        }
+        let mut value = 0u128;
+        let mut done = 0;
+        while done < width {
+            self.load()?;
+            let take = (width - done).min(8 - self.offset);
+            let bits = (self.byte >> self.offset) & (((1u16 << take) - 1) as u8);
+            value |= (bits as u128) << done;
+            done += take;
+            self.offset += take;
+        }
+        self.remaining -= width;
+        Some(value)
+    }
+
+    fn read_unary(&mut self) -> Option<usize> {
+        let mut n = 0;
+        loop {
+            self.load()?;
+            let rest = self.byte >> self.offset;
+            if rest != 0 {
+                let zeros = rest.trailing_zeros() as usize;
+                self.offset += zeros + 1;
+                self.remaining = self.remaining.checked_sub(zeros + 1)?;
+                return Some(n + zeros);
+            }
+            let skip = 8 - self.offset;
+            n += skip;
+            self.offset = 8;
+            self.remaining = self.remaining.checked_sub(skip)?;
+        }
+    }
+
+    #[inline]
+    fn remaining_bits(&self) -> usize {
+        self.remaining
+    }
+}
+
+/// Applies the given compression to a payload.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(crate) fn compress(payload: Vec<u8>, compression: Compression, level: i32) -> Vec<u8> {
+    match compression {
+        Compression::None => payload,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            zstd::encode_all(payload.as_slice(), level).expect("Failed to compress the index")
+        }
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => panic!("zstd compression requires the zstd feature"),
+    }
+}
+
+/// Reverts the given compression of a payload, failing if the result would exceed `max_len` bytes.
+pub(crate) fn decompress(
+    payload: Vec<u8>,
+    compression: Compression,
+    max_len: usize,
+) -> std::io::Result<Vec<u8>> {
+    let payload = match compression {
+        Compression::None => payload,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            use std::io::Read;
+            let mut bytes = Vec::new();
+            zstd::stream::read::Decoder::new(payload.as_slice())?
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut bytes)?;
+            bytes
+        }
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "zstd decompression requires the zstd feature",
+            ))
+        }
+    };
+    if payload.len() > max_len {
+        return Err(invalid_data(
+            "the payload is larger than its number of words allows",
+        ));
+    }
+    Ok(payload)
+}
+
+/// Writer applying a compression to the payload written through it.
+pub(crate) enum Compressor<W: Write> {
+    None(W),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Compressor<W> {
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    pub fn new(writer: W, compression: Compression, level: i32) -> io::Result<Self> {
+        match compression {
+            Compression::None => Ok(Self::None(writer)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Self::Zstd(zstd::stream::write::Encoder::new(
+                writer, level,
+            )?)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "zstd compression requires the zstd feature",
+            )),
+        }
+    }
+
+    /// Ends the compressed stream and returns the writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(writer) => Ok(writer),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Reader reverting the compression of the payload read through it.
+pub(crate) enum Decompressor<R: Read> {
+    None(R),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, io::BufReader<R>>),
+}
+
+impl<R: Read> Decompressor<R> {
+    pub fn new(reader: R, compression: Compression) -> io::Result<Self> {
+        match compression {
+            Compression::None => Ok(Self::None(reader)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Self::Zstd(zstd::stream::read::Decoder::new(reader)?)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "zstd decompression requires the zstd feature",
+            )),
+        }
+    }
+
+    /// Returns the reader, positioned after the last compressed byte read.
+    pub fn into_inner(self) -> R {
+        match self {
+            Self::None(reader) => reader,
+            #[cfg(feature = "zstd")]
+            Self::Zstd(decoder) => decoder.finish().into_inner(),
+        }
+    }
+}
+
+impl<R: Read> Read for Decompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::None(reader) => reader.read(buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Upper bound on the size in bytes of the payload encoding `count` words.
+pub(crate) fn max_payload_bytes(count: usize, prefix_bits: usize, suffix_bits: usize) -> usize {
+    // the gaps of an Elias-Fano sequence of n elements take at most 3n bits in unary,
+    // and each bucket also stores its size in gamma coding
+    let word_bits = prefix_bits + suffix_bits + 2 * usize::BITS as usize + 7;
+    count
+        .saturating_mul(word_bits)
+        .saturating_add(2 * usize::BITS as usize + 1)
+        / 8
+        + 1
+}
+
+/// Number of low bits of an Elias-Fano sequence of `n` elements in a universe of `universe_bits` bits.
+#[inline]
+fn low_bits(n: usize, universe_bits: usize) -> usize {
+    universe_bits.saturating_sub(n.next_power_of_two().ilog2() as usize)
+}
+
+/// Encodes words sorted in increasing order, without duplicates.
+pub(crate) fn encode<I: IntoIterator<Item = u128>>(
+    words: I,
+    prefix_bits: usize,
+    suffix_bits: usize,
+) -> Vec<u8> {
+    let suffix_mask = (1u128 << suffix_bits) - 1;
+    let buckets = words
+        .into_iter()
+        .map(|word| ((word >> suffix_bits) as usize, word & suffix_mask))
+        .chunk_by(|&(prefix, _)| prefix);
+    let buckets = buckets
+        .into_iter()
+        .map(|(prefix, bucket)| (prefix, bucket.map(|(_, suffix)| suffix).collect_vec()))
+        .collect_vec();
+    let mut encoder = Encoder::new(Vec::new(), buckets.len(), prefix_bits, suffix_bits);
+    for (prefix, suffixes) in buckets {
+        encoder
+            .push_bucket(prefix, &suffixes)
+            .expect("Failed to write to a vector");
+    }
+    encoder.finish().expect("Failed to write to a vector")
+}
+
+/// Encodes the buckets of a payload one at a time, writing the bytes as they are complete.
+pub(crate) struct Encoder<W: Write> {
+    writer: W,
+    bits: BitWriter,
+    suffix_bits: usize,
+    /// Number of buckets left to encode.
+    buckets: usize,
+    prefix_low_bits: usize,
+    prefix_high: usize,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Starts a payload made of `buckets` buckets.
+    pub fn new(writer: W, buckets: usize, prefix_bits: usize, suffix_bits: usize) -> Self {
+        let mut bits = BitWriter::new();
+        bits.write_gamma(buckets + 1);
+        Self {
+            writer,
+            bits,
+            suffix_bits,
+            buckets,
+            prefix_low_bits: low_bits(buckets, prefix_bits),
+            prefix_high: 0,
+        }
+    }
+
+    /// Encodes the next bucket, whose prefix must be larger than the previous ones and whose suffixes must be sorted.
+    pub fn push_bucket(&mut self, prefix: usize, suffixes: &[u128]) -> io::Result<()> {
+        assert!(self.buckets > 0, "More buckets than announced");
+        self.buckets -= 1;
+        let writer = &mut self.bits;
+        writer.write_unary((prefix >> self.prefix_low_bits) - self.prefix_high);
+        writer.write_bits(prefix as u128, self.prefix_low_bits);
+        self.prefix_high = prefix >> self.prefix_low_bits;
+        writer.write_gamma(suffixes.len());
+        let suffix_low_bits = low_bits(suffixes.len(), self.suffix_bits);
+        let mut suffix_high = 0;
+        for &suffix in suffixes {
+            writer.write_unary(((suffix >> suffix_low_bits) - suffix_high) as usize);
+            writer.write_bits(suffix, suffix_low_bits);
+            suffix_high = suffix >> suffix_low_bits;
+        }
+        writer.flush_to(&mut self.writer)
+    }
+
+    /// Writes the last byte of the payload and returns the writer.
+    pub fn finish(self) -> io::Result<W> {
+        assert_eq!(self.buckets, 0, "Fewer buckets than announced");
+        let mut writer = self.writer;
+        writer.write_all(&self.bits.finish())?;
+        Ok(writer)
+    }
+}
+
+/// Iterator decoding the words of a compact payload.
+///
+/// It stops at the first inconsistency of the payload, which is then reported by [`error`](Self::error).
+pub(crate) struct Decoder<B: ReadBits> {
+    reader: B,
+    prefix_bits: usize,
+    suffix_bits: usize,
+    buckets: usize,
+    prefix_low_bits: usize,
+    prefix_high: usize,
+    prefix: usize,
+    remaining: usize,
+    suffix_low_bits: usize,
+    suffix_high: u128,
+    last: Option<u128>,
+    error: Option<&'static str>,
+}
+
+const TRUNCATED: &str = "the payload is truncated";
+
+impl<'a> Decoder<BitReader<'a>> {
+    pub fn new(
+        bytes: &'a [u8],
+        prefix_bits: usize,
+        suffix_bits: usize,
+    ) -> Result<Self, &'static str> {
+        Self::from_bits(BitReader::new(bytes), prefix_bits, suffix_bits)
+    }
+}
+
+impl<B: ReadBits> Decoder<B> {
+    pub fn from_bits(
+        mut reader: B,
+        prefix_bits: usize,
+        suffix_bits: usize,
+    ) -> Result<Self, &'static str> {
+        let buckets = reader.read_gamma().ok_or(TRUNCATED)? - 1;
+        // every bucket takes at least two bits, this also bounds the number of buckets
+        if buckets > reader.remaining_bits() / 2 || buckets > 1 << prefix_bits {
+            return Err("the number of buckets is invalid");
+        }
+        Ok(Self {
+            reader,
+            prefix_bits,
+            suffix_bits,
+            buckets,
+            prefix_low_bits: low_bits(buckets, prefix_bits),
+            prefix_high: 0,
+            prefix: 0,
+            remaining: 0,
+            suffix_low_bits: 0,
+            suffix_high: 0,
+            last: None,
+            error: None,
+        })
+    }
+
+    /// Returns the inconsistency that stopped the decoding, if any.
+    #[inline]
+    pub fn error(&self) -> Option<&'static str> {
+        self.error
+    }
+
+    /// Returns the stream of bits, positioned after the last bit decoded.
+    pub fn into_inner(self) -> B {
+        self.reader
+    }
+
+    fn decode_next(&mut self) -> Result<Option<u128>, &'static str> {
+        if self.remaining == 0 {
+            if self.buckets == 0 {
+                return Ok(None);
+            }
+            self.buckets -= 1;
+            self.prefix_high += self.reader.read_unary().ok_or(TRUNCATED)?;
+            if self.prefix_high >> (self.prefix_bits - self.prefix_low_bits) != 0 {
+                return Err("a prefix is too large");
+            }
+            let low = self
+                .reader
+                .read_bits(self.prefix_low_bits)
+                .ok_or(TRUNCATED)? as usize;
+            self.prefix = (self.prefix_high << self.prefix_low_bits) | low;
+            self.remaining = self.reader.read_gamma().ok_or(TRUNCATED)?;
+            // every word takes at least one bit
+            if self.remaining > self.reader.remaining_bits() {
+                return Err(TRUNCATED);
+            }
+            self.suffix_low_bits = low_bits(self.remaining, self.suffix_bits);
+            self.suffix_high = 0;
+        }
+        self.remaining -= 1;
+        self.suffix_high += self.reader.read_unary().ok_or(TRUNCATED)? as u128;
+        if self
+            .suffix_high
+            .checked_shr((self.suffix_bits - self.suffix_low_bits) as u32)
+            .unwrap_or(0)
+            != 0
+        {
+            return Err("a suffix is too large");
+        }
+        let low = self
+            .reader
+            .read_bits(self.suffix_low_bits)
+            .ok_or(TRUNCATED)?;
+        let suffix = (self.suffix_high << self.suffix_low_bits) | low;
+        let word = ((self.prefix as u128) << self.suffix_bits) | suffix;
+        if self.last.is_some_and(|last| last >= word) {
+            return Err("the words are not sorted");
+        }
+        self.last = Some(word);
+        Ok(Some(word))
+    }
+}
+
+impl<B: ReadBits> Iterator for Decoder<B> {
+    type Item = u128;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        self.decode_next().unwrap_or_else(|error| {
+            self.error = Some(error);
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const N: usize = 100_000;
+
+    #[test]
+    fn test_bits() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b101, 3);
+        writer.write_unary(40);
+        writer.write_gamma(1);
+        writer.write_gamma(1234);
+        writer.write_bits(u128::MAX >> 1, 127);
+        writer.write_unary(0);
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bits(3), Some(0b101));
+        assert_eq!(reader.read_unary(), Some(40));
+        assert_eq!(reader.read_gamma(), Some(1));
+        assert_eq!(reader.read_gamma(), Some(1234));
+        assert_eq!(reader.read_bits(127), Some(u128::MAX >> 1));
+        assert_eq!(reader.read_unary(), Some(0));
+        assert_eq!(reader.read_bits(8), None);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        const PREFIX_BITS: usize = 16;
+        const SUFFIX_BITS: usize = 40;
+        let mut rng = StdRng::seed_from_u64(42);
+        let words = (0..N)
+            .map(|_| rng.gen::<u128>() >> (128 - PREFIX_BITS - SUFFIX_BITS))
+            .sorted()
+            .dedup()
+            .collect_vec();
+        let bytes = encode(words.iter().copied(), PREFIX_BITS, SUFFIX_BITS);
+        assert!(bytes.len() < words.len() * (PREFIX_BITS + SUFFIX_BITS) / 8);
+        let decoded = Decoder::new(&bytes, PREFIX_BITS, SUFFIX_BITS)
+            .unwrap()
+            .collect_vec();
+        assert_eq!(decoded, words);
+        assert!(bytes.len() <= max_payload_bytes(words.len(), PREFIX_BITS, SUFFIX_BITS));
+        let empty = encode([], PREFIX_BITS, SUFFIX_BITS);
+        assert_eq!(
+            Decoder::new(&empty, PREFIX_BITS, SUFFIX_BITS)
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_decode_corrupted() {
+        const PREFIX_BITS: usize = 16;
+        const SUFFIX_BITS: usize = 40;
+        let mut rng = StdRng::seed_from_u64(42);
+        let words = (0..1000)
+            .map(|_| rng.gen::<u128>() >> (128 - PREFIX_BITS - SUFFIX_BITS))
+            .sorted()
+            .dedup()
+            .collect_vec();
+        let bytes = encode(words.iter().copied(), PREFIX_BITS, SUFFIX_BITS);
+        let mut decoder =
+            Decoder::new(&bytes[..bytes.len() / 2], PREFIX_BITS, SUFFIX_BITS).unwrap();
+        assert!(decoder.by_ref().count() < words.len());
+        assert_eq!(decoder.error(), Some(TRUNCATED));
+        // random payloads are decoded without panicking
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..64);
+            let garbage = (0..len).map(|_| rng.gen::<u8>()).collect_vec();
+            if let Ok(mut decoder) = Decoder::new(&garbage, PREFIX_BITS, SUFFIX_BITS) {
+                let decoded = decoder.by_ref().collect_vec();
+                assert!(decoded.windows(2).all(|pair| pair[0] < pair[1]));
+                assert!(decoded
+                    .iter()
+                    .all(|&word| word >> (PREFIX_BITS + SUFFIX_BITS) == 0));
+            }
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! A set of *k*-mers that can be queried and modified by several threads at the same time.
+//!
+//! The prefix space is split into ranges of equal size, and each range is stored in its own shard protected by a lock.
+//! Threads only contend when they touch the same shard.
+
+use crate::cbl::*;
+use crate::kmer::{Base, IntKmer};
+use crate::wordset::WordSet;
+use core::marker::PhantomData;
+use itertools::Itertools;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A [`CBL`] split by prefix range into shards, each behind its own lock.
+///
+/// Every method takes `&self`, so the set can be shared between threads (e.g. with an `Arc`).
+/// Note that each shard allocates its own prefix bitvector once it grows, so a few shards per thread are enough.
+///
+/// # Type Parameters
+/// - `K`: the length of the *k*-mers, it must be ≤ 59.
+/// - `T`: the integer type used to store *k*-mers, it must be large enough to store $2k + \lg(2k)$ bits.
+/// - `PREFIX_BITS` (optional): the size of the prefixes in bits.
+pub struct ConcurrentCBL<const K: usize, T: Base, const PREFIX_BITS: usize = 24>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    canonical: bool,
+    shards: Vec<RwLock<WordSet<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>>>,
+    _marker: PhantomData<T>,
+}
+
+macro_rules! impl_concurrent {
+    ($T:ty) => {
+        impl<const K: usize, const PREFIX_BITS: usize> ConcurrentCBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Creates an empty [`ConcurrentCBL`] with `num_shards` shards.
+            pub fn new(num_shards: usize) -> Self {
+                Self::from_cbl(&CBL::<K, $T, PREFIX_BITS>::new(), num_shards)
+            }
+
+            /// Creates an empty [`ConcurrentCBL`] for canonical *k*-mers with `num_shards` shards.
+            pub fn new_canonical(num_shards: usize) -> Self {
+                Self::from_cbl(&CBL::<K, $T, PREFIX_BITS>::new_canonical(), num_shards)
+            }
+
+            /// Splits a [`CBL`] into `num_shards` shards.
+            /// The shards share the buckets of the [`CBL`], which are only copied when they are modified,
+            /// except for the shards small enough to store their *k*-mers in a single sorted vector.
+            pub fn from_cbl(cbl: &CBL<K, $T, PREFIX_BITS>, num_shards: usize) -> Self {
+                assert!(
+                    num_shards > 0 && num_shards <= 1 << PREFIX_BITS,
+                    "The number of shards must be between 1 and 2^{PREFIX_BITS}"
+                );
+                Self {
+                    canonical: cbl.is_canonical(),
+                    shards: cbl
+                        .wordset()
+                        .split_by_prefix(num_shards)
+                        .into_iter()
+                        .map(RwLock::new)
+                        .collect(),
+                    _marker: PhantomData,
+                }
+            }
+
+            /// Joins the shards into a single [`CBL`], sharing their buckets.
+            /// The shards are read one after the other, so each of them is consistent
+            /// but concurrent updates may be reflected in some shards and not in others.
+            pub fn to_cbl(&self) -> CBL<K, $T, PREFIX_BITS> {
+                let shards = (0..self.num_shards())
+                    .map(|shard| self.read(shard).clone())
+                    .collect_vec();
+                CBL::<K, $T, PREFIX_BITS>::new_with_wordset(
+                    WordSet::concat(&shards),
+                    self.canonical,
+                )
+            }
+
+            /// Joins the shards into a single [`CBL`].
+            pub fn into_cbl(self) -> CBL<K, $T, PREFIX_BITS> {
+                let shards = self
+                    .shards
+                    .into_iter()
+                    .map(|shard| {
+                        shard
+                            .into_inner()
+                            .unwrap_or_else(|error| error.into_inner())
+                    })
+                    .collect_vec();
+                CBL::<K, $T, PREFIX_BITS>::new_with_wordset(
+                    WordSet::concat(&shards),
+                    self.canonical,
+                )
+            }
+
+            /// Returns the number of shards.
+            #[inline]
+            pub fn num_shards(&self) -> usize {
+                self.shards.len()
+            }
+
+            /// Returns `true` if the set stores canonical *k*-mers.
+            #[inline]
+            pub fn is_canonical(&self) -> bool {
+                self.canonical
+            }
+
+            /// Returns the number of *k*-mers in the set.
+            pub fn count(&self) -> usize {
+                (0..self.num_shards())
+                    .map(|shard| self.read(shard).count())
+                    .sum()
+            }
+
+            /// Returns `true` if there are no *k*-mers in the set.
+            pub fn is_empty(&self) -> bool {
+                (0..self.num_shards()).all(|shard| self.read(shard).is_empty())
+            }
+
+            /// Locks a shard for reading.
+            /// A thread that panicked while holding the lock left the shard in a valid state, so the poisoning is ignored.
+            #[inline]
+            fn read(
+                &self,
+                shard: usize,
+            ) -> RwLockReadGuard<'_, WordSet<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>>
+            {
+                self.shards[shard]
+                    .read()
+                    .unwrap_or_else(|error| error.into_inner())
+            }
+
+            /// Locks a shard for writing.
+            #[inline]
+            fn write(
+                &self,
+                shard: usize,
+            ) -> RwLockWriteGuard<'_, WordSet<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>>
+            {
+                self.shards[shard]
+                    .write()
+                    .unwrap_or_else(|error| error.into_inner())
+            }
+
+            /// Returns an empty [`CBL`] used to transform *k*-mers and sequences into words.
+            #[inline]
+            fn encoder(&self) -> CBL<K, $T, PREFIX_BITS> {
+                if self.canonical {
+                    CBL::<K, $T, PREFIX_BITS>::new_canonical()
+                } else {
+                    CBL::<K, $T, PREFIX_BITS>::new()
+                }
+            }
+
+            /// Returns the shard containing a word.
+            #[inline]
+            fn word_shard(&self, word: $T) -> usize {
+                (CBL::<K, $T, PREFIX_BITS>::word_prefix(word) * self.num_shards()) >> PREFIX_BITS
+            }
+
+            /// Groups the indices of the words by shard.
+            fn group_by_shard(&self, words: &[$T]) -> Vec<(usize, Vec<usize>)> {
+                (0..words.len())
+                    .sorted_unstable_by_key(|&i| self.word_shard(words[i]))
+                    .chunk_by(|&i| self.word_shard(words[i]))
+                    .into_iter()
+                    .map(|(shard, indices)| (shard, indices.collect()))
+                    .collect()
+            }
+
+            /// Returns `true` if the set contains the given *k*-mer, the *k*-mer must be packed into an [`IntKmer`].
+            pub fn contains(&self, kmer: IntKmer<K, $T>) -> bool {
+                let word = self.encoder().get_word(kmer);
+                self.read(self.word_shard(word)).contains(word)
+            }
+
+            /// Adds a *k*-mer to the set, the *k*-mer must be packed into an [`IntKmer`].
+            /// Returns `true` the *k*-mer was absent from the set.
+            pub fn insert(&self, kmer: IntKmer<K, $T>) -> bool {
+                let word = self.encoder().get_word(kmer);
+                self.write(self.word_shard(word)).insert(word)
+            }
+
+            /// Removes a *k*-mer from the set, the *k*-mer must be packed into an [`IntKmer`].
+            /// Returns `true` the *k*-mer was present in the set.
+            pub fn remove(&self, kmer: IntKmer<K, $T>) -> bool {
+                let word = self.encoder().get_word(kmer);
+                self.write(self.word_shard(word)).remove(word)
+            }
+
+            /// For each *k*-mer of a sequence, returns `true` if it is contained in the set.
+            /// The results are in the same order as for [`CBL::contains_seq`].
+            pub fn contains_seq(&self, seq: &[u8]) -> Vec<bool> {
+                let words = self.encoder().seq_words(seq);
+                let mut res = vec![false; words.len()];
+                for (shard, indices) in self.group_by_shard(&words) {
+                    let shard_words = indices.iter().map(|&i| words[i]).collect_vec();
+                    let contained = self.read(shard).contains_batch(&shard_words);
+                    for (i, b) in indices.into_iter().zip(contained) {
+                        res[i] = b;
+                    }
+                }
+                res
+            }
+
+            /// Adds all the *k*-mers of a sequence to the set, locking one shard at a time.
+            pub fn insert_seq(&self, seq: &[u8]) {
+                let words = self.encoder().seq_words(seq);
+                for (shard, indices) in self.group_by_shard(&words) {
+                    let shard_words = indices.iter().map(|&i| words[i]).collect_vec();
+                    self.write(shard).insert_batch(&shard_words);
+                }
+            }
+
+            /// Removes all the *k*-mers of a sequence from the set, locking one shard at a time.
+            pub fn remove_seq(&self, seq: &[u8]) {
+                let words = self.encoder().seq_words(seq);
+                for (shard, indices) in self.group_by_shard(&words) {
+                    let shard_words = indices.iter().map(|&i| words[i]).collect_vec();
+                    self.write(shard).remove_batch(&shard_words);
+                }
+            }
+
+            /// Returns an iterator over the *k*-mers of the set, shard by shard.
+            /// Each shard is copied (without its buckets, which are shared) when the iterator reaches it,
+            /// so the *k*-mers of a shard are consistent with each other but concurrent updates of the other shards may be missed.
+            pub fn iter(&self) -> impl Iterator<Item = IntKmer<K, $T>> + '_ {
+                (0..self.num_shards()).flat_map(move |shard| {
+                    let set = self.read(shard).clone();
+                    set.iter::<$T>()
+                        .map(CBL::<K, $T, PREFIX_BITS>::recover_kmer)
+                        .collect_vec()
+                })
+            }
+        }
+    };
+}
+
+impl_concurrent!(u32);
+impl_concurrent!(u64);
+impl_concurrent!(u128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitvector::Bitvector;
+    use crate::tiered::Tiered;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const K: usize = 25;
+    type T = u64;
+    const N: usize = 100_000;
+    const PREFIX_BITS: usize = 24;
+    const THREADS: usize = 4;
+
+    fn assert_send_sync<S: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        // the FFI types (`UniquePtr<RankBV>` and `UniquePtr<TieredVec32>`) are neither `Send` nor `Sync`,
+        // the wrappers owning them are
+        assert_send_sync::<Bitvector>();
+        assert_send_sync::<Tiered>();
+        assert_send_sync::<WordSet<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>>();
+        assert_send_sync::<CBL<K, T>>();
+        assert_send_sync::<ConcurrentCBL<K, T>>();
+        // a bitvector and a tiered vector built in one thread can be used in another one
+        let mut bv = Bitvector::new_with_bitlength(16);
+        let mut tiered = Tiered::new();
+        for i in 0..100 {
+            bv.insert(3 * i);
+            tiered.insert(i, i as u32);
+        }
+        let (bv, tiered) = std::thread::spawn(move || {
+            assert!((0..100).all(|i| bv.contains(3 * i) && bv.rank(3 * i) == i));
+            assert!((0..100).all(|i| tiered.get(i) == i as u32));
+            tiered.remove(0);
+            (bv, tiered)
+        })
+        .join()
+        .unwrap();
+        assert!(bv.contains(297));
+        assert_eq!(tiered.len(), 99);
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        // a bitvector and a tiered vector can be read from several threads at the same time
+        let mut bv = Bitvector::new_with_bitlength(20);
+        let mut tiered = Tiered::new();
+        for i in 0..100_000 {
+            bv.insert(7 * i);
+            tiered.insert(i, (3 * i) as u32);
+        }
+        std::thread::scope(|scope| {
+            for t in 0..THREADS {
+                let (bv, tiered) = (&bv, &tiered);
+                scope.spawn(move || {
+                    for _ in 0..4 {
+                        assert_eq!(bv.count(), 100_000);
+                        assert_eq!(tiered.len(), 100_000);
+                        // the threads read the same words, starting at different places
+                        for i in (0..100_000).map(|i| (i + t * 25_000) % 100_000) {
+                            assert!(bv.contains(7 * i) && !bv.contains(7 * i + 1));
+                            assert_eq!(bv.rank(7 * i), i);
+                            assert_eq!(tiered.get(i), (3 * i) as u32);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let seqs = (0..THREADS)
+            .map(|_| {
+                (0..N)
+                    .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+                    .collect_vec()
+            })
+            .collect_vec();
+        let mut expected = CBL::<K, T>::new_canonical();
+        for seq in seqs.iter() {
+            expected.insert_seq(seq);
+            expected.remove_seq(&seq[..(N / 4)]);
+        }
+        let cbl = ConcurrentCBL::<K, T>::new_canonical(16);
+        std::thread::scope(|scope| {
+            for seq in seqs.iter() {
+                let cbl = &cbl;
+                scope.spawn(move || {
+                    cbl.insert_seq(seq);
+                    assert!(cbl.contains_seq(seq).into_iter().all(|b| b));
+                });
+            }
+        });
+        std::thread::scope(|scope| {
+            for seq in seqs.iter() {
+                let cbl = &cbl;
+                scope.spawn(move || cbl.remove_seq(&seq[..(N / 4)]));
+            }
+        });
+        let mut joined = cbl.to_cbl();
+        assert_eq!(cbl.count(), expected.count());
+        assert!(joined.validate().is_ok());
+        assert!(joined == expected);
+        assert!(cbl.iter().eq(expected.iter()));
+        let split = ConcurrentCBL::from_cbl(&joined, 7);
+        assert_eq!(split.count(), expected.count());
+        assert!(split.into_cbl() == expected);
+        for seq in seqs.iter() {
+            assert_eq!(joined.contains_seq(seq), cbl.contains_seq(seq));
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Patches turning a version of an index into another one.
+//!
+//! A patch file starts with a header containing the content hashes of both versions (see [`CBL::content_hash`]),
+//! followed by the added and removed *k*-mers, each saved in the compact format and preceded by its length in bytes.
+//! The file ends with a checksum of its content.
+
+use crate::cbl::*;
+use crate::checksum::{checksum, invalid_data, ChecksumWriter, CHECKSUM_BYTES};
+use crate::kmer::Base;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Magic bytes identifying a patch.
+const MAGIC: [u8; 4] = *b"CBLP";
+/// Version of the patch format.
+const VERSION: u8 = 1;
+/// Size in bytes of the header of a patch.
+const HEADER_BYTES: usize = MAGIC.len() + 1 + 2 * 8;
+
+/// The changes turning a version of a [`CBL`] into another one.
+///
+/// # Type Parameters
+/// - `K`: the length of the *k*-mers, it must be ≤ 59.
+/// - `T`: the integer type used to store *k*-mers, it must be large enough to store $2k + \lg(2k)$ bits.
+/// - `PREFIX_BITS` (optional): the size of the prefixes in bits.
+pub struct Delta<const K: usize, T: Base, const PREFIX_BITS: usize = 24>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    added: CBL<K, T, PREFIX_BITS>,
+    removed: CBL<K, T, PREFIX_BITS>,
+    base_hash: u64,
+    target_hash: u64,
+}
+
+macro_rules! impl_delta {
+    ($T:ty) => {
+        impl<const K: usize, const PREFIX_BITS: usize> Delta<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Computes the changes turning `old` into `new`, bucket by bucket.
+            pub fn between(
+                old: &mut CBL<K, $T, PREFIX_BITS>,
+                new: &mut CBL<K, $T, PREFIX_BITS>,
+            ) -> Self {
+                Self {
+                    base_hash: old.content_hash(),
+                    target_hash: new.content_hash(),
+                    added: &mut *new - &mut *old,
+                    removed: old - new,
+                }
+            }
+
+            /// Returns the *k*-mers added by the patch.
+            #[inline]
+            pub fn added(&self) -> &CBL<K, $T, PREFIX_BITS> {
+                &self.added
+            }
+
+            /// Returns the *k*-mers removed by the patch.
+            #[inline]
+            pub fn removed(&self) -> &CBL<K, $T, PREFIX_BITS> {
+                &self.removed
+            }
+
+            /// Returns the content hash of the set the patch applies to.
+            #[inline]
+            pub fn base_hash(&self) -> u64 {
+                self.base_hash
+            }
+
+            /// Returns the content hash of the set obtained after applying the patch.
+            #[inline]
+            pub fn target_hash(&self) -> u64 {
+                self.target_hash
+            }
+
+            /// Returns `true` if the patch does not change anything.
+            #[inline]
+            pub fn is_empty(&self) -> bool {
+                self.added.is_empty() && self.removed.is_empty()
+            }
+
+            /// Applies the patch to a set, checking the content hash of the set before and after.
+            /// The set is left unchanged if one of the hashes does not match.
+            pub fn apply(&mut self, cbl: &mut CBL<K, $T, PREFIX_BITS>) -> Result<(), String> {
+                if cbl.is_canonical() != self.added.is_canonical() {
+                    return Err("the patch and the index differ in canonicity".to_string());
+                }
+                let hash = cbl.content_hash();
+                if hash != self.base_hash {
+                    return Err(format!(
+                        "the patch applies to the index of hash {:#018x}, not {hash:#018x}",
+                        self.base_hash
+                    ));
+                }
+                *cbl |= &mut self.added;
+                *cbl -= &mut self.removed;
+                let hash = cbl.content_hash();
+                if hash != self.target_hash {
+                    // the added k-mers were absent and the removed ones present, this restores the set
+                    *cbl -= &mut self.added;
+                    *cbl |= &mut self.removed;
+                    return Err(format!(
+                        "the patched index has hash {hash:#018x} instead of {:#018x}",
+                        self.target_hash
+                    ));
+                }
+                Ok(())
+            }
+
+            /// Saves the patch to a file.
+            pub fn save_to_file<P: AsRef<Path> + Copy>(&self, path: P) {
+                let patch_file = File::create(path).unwrap_or_else(|_| {
+                    panic!("Failed to create {}", path.as_ref().to_str().unwrap())
+                });
+                self.write_to(BufWriter::new(patch_file))
+                    .unwrap_or_else(|_| {
+                        panic!(
+                            "Failed to write patch to {}",
+                            path.as_ref().to_str().unwrap()
+                        )
+                    });
+            }
+
+            fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+                let mut writer = ChecksumWriter::new(writer);
+                writer.write_all(&MAGIC)?;
+                writer.write_all(&[VERSION])?;
+                writer.write_all(&self.base_hash.to_le_bytes())?;
+                writer.write_all(&self.target_hash.to_le_bytes())?;
+                for cbl in [&self.added, &self.removed] {
+                    let mut bytes = Vec::new();
+                    cbl.write_to(&mut bytes, Format::Compact)?;
+                    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+                    writer.write_all(&bytes)?;
+                }
+                writer.finish().map(|_| ())
+            }
+
+            /// Loads a patch from a file.
+            /// It panics if the file cannot be loaded, see [`try_load_from_file`](Self::try_load_from_file) to handle errors.
+            pub fn load_from_file<P: AsRef<Path> + Copy>(path: P) -> Self {
+                Self::try_load_from_file(path).unwrap_or_else(|error| {
+                    panic!(
+                        "Failed to load patch from {}: {error}",
+                        path.as_ref().to_str().unwrap()
+                    )
+                })
+            }
+
+            /// Loads a patch from a file, rejecting truncated or corrupted files and patches built with other parameters.
+            pub fn try_load_from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+                Self::from_bytes(&std::fs::read(path)?)
+            }
+
+            fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+                let truncated = || invalid_data("the patch is truncated");
+                let content_len = bytes
+                    .len()
+                    .checked_sub(CHECKSUM_BYTES)
+                    .filter(|&len| len >= HEADER_BYTES)
+                    .ok_or_else(truncated)?;
+                let (content, trailer) = bytes.split_at(content_len);
+                if u64::from_le_bytes(trailer.try_into().unwrap()) != checksum(content) {
+                    return Err(invalid_data("checksum mismatch"));
+                }
+                if content[..4] != MAGIC || content[4] != VERSION {
+                    return Err(invalid_data("invalid patch header"));
+                }
+                let base_hash = u64::from_le_bytes(content[5..13].try_into().unwrap());
+                let target_hash = u64::from_le_bytes(content[13..21].try_into().unwrap());
+                let mut rest = &content[HEADER_BYTES..];
+                let mut read_set = || {
+                    let len = rest
+                        .get(..8)
+                        .map(|len| u64::from_le_bytes(len.try_into().unwrap()))
+                        .filter(|&len| len <= (rest.len() - 8) as u64)
+                        .ok_or_else(truncated)? as usize;
+                    let set = CBL::<K, $T, PREFIX_BITS>::read_from(&rest[8..8 + len], len as u64);
+                    rest = &rest[8 + len..];
+                    set
+                };
+                let added = read_set()?;
+                let removed = read_set()?;
+                if !rest.is_empty() {
+                    return Err(invalid_data("unexpected bytes at the end of the patch"));
+                }
+                if added.is_canonical() != removed.is_canonical() {
+                    return Err(invalid_data(
+                        "the patch mixes canonical and non-canonical k-mers",
+                    ));
+                }
+                Ok(Self {
+                    added,
+                    removed,
+                    base_hash,
+                    target_hash,
+                })
+            }
+        }
+    };
+}
+
+impl_delta!(u32);
+impl_delta!(u64);
+impl_delta!(u128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const K: usize = 25;
+    type T = u64;
+    const N: usize = 100_000;
+
+    #[test]
+    fn test_delta() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = (0..N)
+            .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+            .collect::<Vec<_>>();
+        let mut old = CBL::<K, T>::new_canonical();
+        old.insert_seq(&nucs[..(2 * N / 3)]);
+        let mut new = CBL::<K, T>::new_canonical();
+        new.insert_seq(&nucs[(N / 3)..]);
+        let mut delta = Delta::between(&mut old, &mut new);
+        assert_eq!(delta.added().count(), (&mut new - &mut old).count());
+        assert_eq!(delta.removed().count(), (&mut old - &mut new).count());
+        let path = std::env::temp_dir().join(format!("cbl-test-delta-{}", std::process::id()));
+        delta.save_to_file(&path);
+        let mut patch = Delta::<K, T>::load_from_file(&path);
+        let mut patched = old.clone();
+        patch.apply(&mut patched).unwrap();
+        assert!(patched == new);
+        // the patch only applies to the old version
+        assert!(patch.apply(&mut patched).is_err());
+        assert!(patched == new);
+        assert!(delta.apply(&mut old).is_ok());
+        assert!(Delta::between(&mut old, &mut new).is_empty());
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(Delta::<K, T>::from_bytes(&bytes).is_err());
+        assert!(Delta::<K, T>::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
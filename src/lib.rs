@@ -2,13 +2,24 @@
 #![feature(generic_const_exprs)]
 
 pub(crate) mod bitvector;
+pub mod builder;
 pub mod cbl;
+pub(crate) mod checksum;
+pub(crate) mod compact;
+pub mod concurrent;
+pub mod delta;
 pub(crate) mod ffi;
 pub mod kmer;
 pub mod necklace;
+pub mod sharded;
 pub(crate) mod sliced_int;
+pub mod snapshot;
+pub mod stream;
+pub(crate) mod tiered;
+pub mod transaction;
 pub(crate) mod trie;
 pub(crate) mod trievec;
+pub mod wal;
 pub(crate) mod wordset;
 
 pub use cbl::CBL;
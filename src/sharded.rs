@@ -0,0 +1,611 @@
+//! Indexes split into shards stored in a directory and loaded on demand.
+
+use crate::cbl::*;
+use crate::kmer::{Base, IntKmer};
+use crate::wal::sync_parent_dir;
+use bincode::{DefaultOptions, Options};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILENAME: &str = "manifest.cbl";
+
+/// Description of a sharded index, stored next to its shards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Length of the *k*-mers.
+    pub k: usize,
+    /// Size of the prefixes in bits.
+    pub prefix_bits: usize,
+    /// `true` if the index stores canonical *k*-mers.
+    pub canonical: bool,
+    /// Number of *k*-mers in each shard.
+    pub counts: Vec<usize>,
+}
+
+impl Manifest {
+    /// Returns the number of shards.
+    #[inline]
+    pub fn num_shards(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Saves the manifest in a directory.
+    /// It is written to a temporary file which then replaces the previous manifest.
+    pub fn save_to_dir<P: AsRef<Path>>(&self, dir: P) {
+        let path = dir.as_ref().join(MANIFEST_FILENAME);
+        replace_file(&path, |file| {
+            let mut writer = BufWriter::new(file);
+            DefaultOptions::new()
+                .with_varint_encoding()
+                .reject_trailing_bytes()
+                .serialize_into(&mut writer, self)
+                .unwrap_or_else(|_| {
+                    panic!("Failed to write manifest to {}", path.to_str().unwrap())
+                });
+            writer.flush().unwrap_or_else(|_| {
+                panic!("Failed to write manifest to {}", path.to_str().unwrap())
+            });
+        });
+    }
+
+    /// Loads the manifest of a directory.
+    pub fn load_from_dir<P: AsRef<Path>>(dir: P) -> Self {
+        let path = dir.as_ref().join(MANIFEST_FILENAME);
+        let file = File::open(&path)
+            .unwrap_or_else(|_| panic!("Failed to open {}", path.to_str().unwrap()));
+        let reader = BufReader::new(file);
+        DefaultOptions::new()
+            .with_varint_encoding()
+            .reject_trailing_bytes()
+            .deserialize_from(reader)
+            .unwrap_or_else(|_| panic!("Failed to load manifest from {}", path.to_str().unwrap()))
+    }
+}
+
+/// Returns `true` if the path is a directory containing a sharded index.
+pub fn is_sharded<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().join(MANIFEST_FILENAME).is_file()
+}
+
+/// Returns the path of a shard in a directory.
+fn shard_path<P: AsRef<Path>>(dir: P, shard: usize) -> PathBuf {
+    dir.as_ref().join(format!("shard_{shard}.cbl"))
+}
+
+/// Replaces a file by writing its new content to a temporary file next to it,
+/// and renaming it once it is synchronized, so that a crash leaves either the old or the new file.
+fn replace_file<F: FnOnce(&File)>(path: &Path, write: F) {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let file = File::create(&tmp_path)
+        .unwrap_or_else(|_| panic!("Failed to create {}", tmp_path.to_str().unwrap()));
+    write(&file);
+    file.sync_all()
+        .and_then(|_| rename(&tmp_path, path))
+        .and_then(|_| sync_parent_dir(path))
+        .unwrap_or_else(|_| panic!("Failed to replace {}", path.to_str().unwrap()));
+}
+
+/// A [`CBL`] split by prefix range into shards stored in a directory.
+///
+/// Shards are loaded when a query touches them, and at most `max_loaded` shards are kept in memory,
+/// the least recently used ones being dropped first.
+/// Modified shards are written back to the directory, followed by the manifest, when they are dropped,
+/// and the ones still in memory are written with [`flush`](Self::flush).
+/// Shards and manifest are replaced atomically, so a crash leaves every file either in its old or in its new version.
+///
+/// # Type Parameters
+/// - `K`: the length of the *k*-mers, it must be ≤ 59.
+/// - `T`: the integer type used to store *k*-mers, it must be large enough to store $2k + \lg(2k)$ bits.
+/// - `PREFIX_BITS` (optional): the size of the prefixes in bits.
+pub struct ShardedCBL<const K: usize, T: Base, const PREFIX_BITS: usize = 24>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    dir: PathBuf,
+    manifest: Manifest,
+    encoder: CBL<K, T, PREFIX_BITS>,
+    shards: Vec<Option<CBL<K, T, PREFIX_BITS>>>,
+    dirty: Vec<bool>,
+    lru: VecDeque<usize>,
+    max_loaded: usize,
+}
+
+macro_rules! impl_sharded {
+    ($T:ty) => {
+        impl<const K: usize, const PREFIX_BITS: usize> ShardedCBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Splits a [`CBL`] into `num_shards` shards written in `dir`.
+            pub fn write<P: AsRef<Path>>(cbl: &CBL<K, $T, PREFIX_BITS>, num_shards: usize, dir: P) {
+                assert!(
+                    num_shards > 0 && num_shards <= 1 << PREFIX_BITS,
+                    "The number of shards must be between 1 and 2^{PREFIX_BITS}"
+                );
+                let dir = dir.as_ref();
+                create_dir_all(dir)
+                    .unwrap_or_else(|_| panic!("Failed to create {}", dir.to_str().unwrap()));
+                let mut words = cbl.words().peekable();
+                let mut counts = Vec::with_capacity(num_shards);
+                for shard in 0..num_shards {
+                    let part = CBL::<K, $T, PREFIX_BITS>::from_sorted_words(
+                        words.peeking_take_while(|&word| {
+                            Self::word_shard(word, num_shards) == shard
+                        }),
+                        cbl.is_canonical(),
+                    );
+                    part.save_to_file(&shard_path(dir, shard));
+                    counts.push(part.count());
+                }
+                Manifest {
+                    k: K,
+                    prefix_bits: PREFIX_BITS,
+                    canonical: cbl.is_canonical(),
+                    counts,
+                }
+                .save_to_dir(dir);
+            }
+
+            /// Opens the sharded index stored in `dir`, keeping at most `max_loaded` shards in memory.
+            pub fn open<P: AsRef<Path>>(dir: P, max_loaded: usize) -> Self {
+                let dir = dir.as_ref().to_path_buf();
+                let manifest = Manifest::load_from_dir(&dir);
+                assert_eq!(
+                    manifest.k,
+                    K,
+                    "The index of {} was built for K={}",
+                    dir.to_str().unwrap(),
+                    manifest.k
+                );
+                assert_eq!(
+                    manifest.prefix_bits,
+                    PREFIX_BITS,
+                    "The index of {} was built for PREFIX_BITS={}",
+                    dir.to_str().unwrap(),
+                    manifest.prefix_bits
+                );
+                let encoder = if manifest.canonical {
+                    CBL::<K, $T, PREFIX_BITS>::new_canonical()
+                } else {
+                    CBL::<K, $T, PREFIX_BITS>::new()
+                };
+                let num_shards = manifest.num_shards();
+                Self {
+                    dir,
+                    manifest,
+                    encoder,
+                    shards: (0..num_shards).map(|_| None).collect(),
+                    dirty: vec![false; num_shards],
+                    lru: VecDeque::new(),
+                    max_loaded: max_loaded.max(1),
+                }
+            }
+
+            /// Returns the manifest of the index.
+            #[inline]
+            pub fn manifest(&self) -> &Manifest {
+                &self.manifest
+            }
+
+            /// Returns the number of shards.
+            #[inline]
+            pub fn num_shards(&self) -> usize {
+                self.manifest.num_shards()
+            }
+
+            /// Returns the number of shards currently in memory.
+            #[inline]
+            pub fn num_loaded(&self) -> usize {
+                self.lru.len()
+            }
+
+            /// Returns `true` if the index stores canonical *k*-mers.
+            #[inline]
+            pub fn is_canonical(&self) -> bool {
+                self.manifest.canonical
+            }
+
+            /// Counts the number of *k*-mers in the index, without loading any shard.
+            #[inline]
+            pub fn count(&self) -> usize {
+                self.manifest.counts.iter().sum()
+            }
+
+            /// Returns `true` if there are no *k*-mers in the index.
+            #[inline]
+            pub fn is_empty(&self) -> bool {
+                self.count() == 0
+            }
+
+            /// Returns the shard containing a word.
+            #[inline]
+            fn word_shard(word: $T, num_shards: usize) -> usize {
+                (CBL::<K, $T, PREFIX_BITS>::word_prefix(word) * num_shards) >> PREFIX_BITS
+            }
+
+            /// Returns a shard, loading it if necessary.
+            pub fn shard(&mut self, shard: usize) -> &CBL<K, $T, PREFIX_BITS> {
+                self.load(shard);
+                self.shards[shard].as_ref().unwrap()
+            }
+
+            /// Returns a shard that will be modified, loading it if necessary.
+            fn shard_mut(&mut self, shard: usize) -> &mut CBL<K, $T, PREFIX_BITS> {
+                self.load(shard);
+                self.dirty[shard] = true;
+                self.shards[shard].as_mut().unwrap()
+            }
+
+            /// Loads a shard and marks it as the most recently used one.
+            fn load(&mut self, shard: usize) {
+                if self.shards[shard].is_some() {
+                    let pos = self.lru.iter().position(|&s| s == shard).unwrap();
+                    self.lru.remove(pos);
+                    self.lru.push_back(shard);
+                    return;
+                }
+                while self.lru.len() >= self.max_loaded {
+                    // unmodified shards are dropped first, as they need not be written back
+                    let pos = self.lru.iter().position(|&s| !self.dirty[s]).unwrap_or(0);
+                    let evicted = self.lru.remove(pos).unwrap();
+                    if self.dirty[evicted] {
+                        self.write_shard(evicted);
+                        self.manifest.save_to_dir(&self.dir);
+                    }
+                    self.shards[evicted] = None;
+                }
+                self.shards[shard] = Some(CBL::<K, $T, PREFIX_BITS>::load_from_file(&shard_path(
+                    &self.dir, shard,
+                )));
+                self.lru.push_back(shard);
+            }
+
+            /// Writes a modified shard to the directory of the index and marks it as unmodified.
+            fn write_shard(&mut self, shard: usize) {
+                let cbl = self.shards[shard].as_ref().unwrap();
+                replace_file(&shard_path(&self.dir, shard), |file| {
+                    cbl.write_to(BufWriter::new(file), Format::Bincode)
+                        .unwrap_or_else(|_| panic!("Failed to write shard {shard}"));
+                });
+                self.dirty[shard] = false;
+            }
+
+            /// Returns a copy of a shard, without keeping it in memory if it was not loaded.
+            fn read_shard(&self, shard: usize) -> CBL<K, $T, PREFIX_BITS> {
+                match &self.shards[shard] {
+                    Some(cbl) => cbl.clone(),
+                    None => {
+                        CBL::<K, $T, PREFIX_BITS>::load_from_file(&shard_path(&self.dir, shard))
+                    }
+                }
+            }
+
+            /// Groups the indices of the words by shard.
+            fn group_by_shard(&self, words: &[$T]) -> Vec<(usize, Vec<usize>)> {
+                let num_shards = self.num_shards();
+                (0..words.len())
+                    .sorted_unstable_by_key(|&i| Self::word_shard(words[i], num_shards))
+                    .chunk_by(|&i| Self::word_shard(words[i], num_shards))
+                    .into_iter()
+                    .map(|(shard, indices)| (shard, indices.collect()))
+                    .collect()
+            }
+
+            /// Returns `true` if the index contains the given *k*-mer, the *k*-mer must be packed into an [`IntKmer`].
+            pub fn contains(&mut self, kmer: IntKmer<K, $T>) -> bool {
+                let word = self.encoder.get_word(kmer);
+                let shard = Self::word_shard(word, self.num_shards());
+                self.shard(shard).contains_words(&[word])[0]
+            }
+
+            /// Adds a *k*-mer to the index, the *k*-mer must be packed into an [`IntKmer`].
+            pub fn insert(&mut self, kmer: IntKmer<K, $T>) {
+                let word = self.encoder.get_word(kmer);
+                let shard = Self::word_shard(word, self.num_shards());
+                let cbl = self.shard_mut(shard);
+                cbl.insert_words(&[word]);
+                let count = cbl.count();
+                self.manifest.counts[shard] = count;
+            }
+
+            /// Removes a *k*-mer from the index, the *k*-mer must be packed into an [`IntKmer`].
+            pub fn remove(&mut self, kmer: IntKmer<K, $T>) {
+                let word = self.encoder.get_word(kmer);
+                let shard = Self::word_shard(word, self.num_shards());
+                let cbl = self.shard_mut(shard);
+                cbl.remove_words(&[word]);
+                let count = cbl.count();
+                self.manifest.counts[shard] = count;
+            }
+
+            /// For each *k*-mer of a sequence, returns `true` if it is contained in the index.
+            /// The results are in the same order as for [`CBL::contains_seq`].
+            pub fn contains_seq(&mut self, seq: &[u8]) -> Vec<bool> {
+                let words = self.encoder.seq_words(seq);
+                let mut res = vec![false; words.len()];
+                for (shard, indices) in self.group_by_shard(&words) {
+                    let shard_words = indices.iter().map(|&i| words[i]).collect_vec();
+                    let contained = self.shard(shard).contains_words(&shard_words);
+                    for (i, b) in indices.into_iter().zip(contained) {
+                        res[i] = b;
+                    }
+                }
+                res
+            }
+
+            /// Adds all the *k*-mers of a sequence to the index.
+            pub fn insert_seq(&mut self, seq: &[u8]) {
+                let words = self.encoder.seq_words(seq);
+                for (shard, indices) in self.group_by_shard(&words) {
+                    let shard_words = indices.iter().map(|&i| words[i]).collect_vec();
+                    let cbl = self.shard_mut(shard);
+                    cbl.insert_words(&shard_words);
+                    let count = cbl.count();
+                    self.manifest.counts[shard] = count;
+                }
+            }
+
+            /// Removes all the *k*-mers of a sequence from the index.
+            pub fn remove_seq(&mut self, seq: &[u8]) {
+                let words = self.encoder.seq_words(seq);
+                for (shard, indices) in self.group_by_shard(&words) {
+                    let shard_words = indices.iter().map(|&i| words[i]).collect_vec();
+                    let cbl = self.shard_mut(shard);
+                    cbl.remove_words(&shard_words);
+                    let count = cbl.count();
+                    self.manifest.counts[shard] = count;
+                }
+            }
+
+            /// Writes the modified shards and then the manifest to the directory of the index.
+            pub fn flush(&mut self) {
+                for shard in 0..self.num_shards() {
+                    if self.dirty[shard] {
+                        self.write_shard(shard);
+                    }
+                }
+                self.manifest.save_to_dir(&self.dir);
+            }
+
+            /// Writes a copy of the index to another directory, one shard at a time.
+            pub fn save_to_dir<P: AsRef<Path>>(&mut self, dir: P) {
+                let dir = dir.as_ref();
+                if dir == self.dir {
+                    return self.flush();
+                }
+                create_dir_all(dir)
+                    .unwrap_or_else(|_| panic!("Failed to create {}", dir.to_str().unwrap()));
+                for shard in 0..self.num_shards() {
+                    self.read_shard(shard).save_to_file(&shard_path(dir, shard));
+                }
+                self.manifest.save_to_dir(dir);
+            }
+
+            /// Loads all the shards into a single [`CBL`].
+            pub fn to_cbl(&self) -> CBL<K, $T, PREFIX_BITS> {
+                let shards = (0..self.num_shards()).map(|shard| self.read_shard(shard));
+                let mut words = Vec::with_capacity(self.count());
+                for shard in shards {
+                    words.extend(shard.words());
+                }
+                CBL::<K, $T, PREFIX_BITS>::from_sorted_words(words, self.is_canonical())
+            }
+
+            /// Checks the consistency of every shard and its agreement with the manifest.
+            /// Returns the list of violated invariants, if any.
+            pub fn validate(&self) -> Result<(), Vec<String>> {
+                let mut errors = Vec::new();
+                let num_shards = self.num_shards();
+                for shard in 0..num_shards {
+                    let cbl = self.read_shard(shard);
+                    if let Err(shard_errors) = cbl.validate() {
+                        errors.extend(
+                            shard_errors
+                                .into_iter()
+                                .map(|error| format!("shard {shard}: {error}")),
+                        );
+                        continue;
+                    }
+                    if cbl.is_canonical() != self.is_canonical() {
+                        errors.push(format!(
+                            "shard {shard}: canonical is {} but the manifest says {}",
+                            cbl.is_canonical(),
+                            self.is_canonical()
+                        ));
+                    }
+                    if cbl.count() != self.manifest.counts[shard] {
+                        errors.push(format!(
+                            "shard {shard}: it contains {} k-mers but the manifest says {}",
+                            cbl.count(),
+                            self.manifest.counts[shard]
+                        ));
+                    }
+                    if cbl
+                        .words()
+                        .any(|word| Self::word_shard(word, num_shards) != shard)
+                    {
+                        errors.push(format!(
+                            "shard {shard}: it contains k-mers of another shard"
+                        ));
+                    }
+                }
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(errors)
+                }
+            }
+
+            /// Applies a set operation shard by shard and writes the result to `dir`.
+            /// Both indexes must have the same number of shards.
+            pub fn apply_to_dir<P: AsRef<Path>, F>(&self, other: &Self, dir: P, mut op: F)
+            where
+                F: FnMut(&mut CBL<K, $T, PREFIX_BITS>, &mut CBL<K, $T, PREFIX_BITS>),
+            {
+                assert_eq!(
+                    self.num_shards(),
+                    other.num_shards(),
+                    "The indexes must have the same number of shards"
+                );
+                let dir = dir.as_ref();
+                create_dir_all(dir)
+                    .unwrap_or_else(|_| panic!("Failed to create {}", dir.to_str().unwrap()));
+                let mut counts = Vec::with_capacity(self.num_shards());
+                for shard in 0..self.num_shards() {
+                    let mut cbl = self.read_shard(shard);
+                    let mut cbl2 = other.read_shard(shard);
+                    op(&mut cbl, &mut cbl2);
+                    cbl.save_to_file(&shard_path(dir, shard));
+                    counts.push(cbl.count());
+                }
+                Manifest {
+                    counts,
+                    ..self.manifest.clone()
+                }
+                .save_to_dir(dir);
+            }
+
+            /// Merges multiple indexes shard by shard and writes the result to `dir`.
+            /// All indexes must have the same number of shards.
+            pub fn merge_to_dir<P: AsRef<Path>>(indexes: &[&Self], dir: P) {
+                Self::apply_all_to_dir(indexes, dir, CBL::<K, $T, PREFIX_BITS>::merge);
+            }
+
+            /// Applies an operation on multiple indexes shard by shard and writes the result to `dir`.
+            /// All indexes must have the same number of shards.
+            pub fn apply_all_to_dir<P: AsRef<Path>, F>(indexes: &[&Self], dir: P, mut op: F)
+            where
+                F: FnMut(Vec<&mut CBL<K, $T, PREFIX_BITS>>) -> CBL<K, $T, PREFIX_BITS>,
+            {
+                assert!(!indexes.is_empty(), "There must be at least one index");
+                let num_shards = indexes[0].num_shards();
+                assert!(
+                    indexes.iter().all(|index| index.num_shards() == num_shards),
+                    "The indexes must have the same number of shards"
+                );
+                let dir = dir.as_ref();
+                create_dir_all(dir)
+                    .unwrap_or_else(|_| panic!("Failed to create {}", dir.to_str().unwrap()));
+                let mut counts = Vec::with_capacity(num_shards);
+                for shard in 0..num_shards {
+                    let mut cbls = indexes
+                        .iter()
+                        .map(|index| index.read_shard(shard))
+                        .collect_vec();
+                    let cbl = op(cbls.iter_mut().collect());
+                    cbl.save_to_file(&shard_path(dir, shard));
+                    counts.push(cbl.count());
+                }
+                Manifest {
+                    counts,
+                    ..indexes[0].manifest.clone()
+                }
+                .save_to_dir(dir);
+            }
+        }
+    };
+}
+
+impl_sharded!(u32);
+impl_sharded!(u64);
+impl_sharded!(u128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kmer::Kmer;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+
+    const K: usize = 25;
+    type T = u64;
+    type KmerT = IntKmer<K, T>;
+    const N: usize = 100_000;
+
+    fn random_nucs(rng: &mut StdRng) -> Vec<u8> {
+        (0..N)
+            .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+            .collect_vec()
+    }
+
+    #[test]
+    fn test_sharded() {
+        let dir = temp_dir().join(format!("cbl-test-sharded-{}", std::process::id()));
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = random_nucs(&mut rng);
+        let nucs2 = random_nucs(&mut rng);
+        let mut set = CBL::<K, T>::new_canonical();
+        set.insert_seq(&nucs);
+        ShardedCBL::<K, T>::write(&set, 16, &dir);
+        assert!(is_sharded(&dir));
+
+        let mut sharded = ShardedCBL::<K, T>::open(&dir, 4);
+        assert_eq!(sharded.num_shards(), 16);
+        assert!(sharded.is_canonical());
+        assert_eq!(sharded.count(), set.count());
+        assert_eq!(sharded.num_loaded(), 0);
+        let kmer = KmerT::from_nucs(&nucs[..K]);
+        assert!(sharded.contains(kmer));
+        assert_eq!(sharded.num_loaded(), 1);
+        assert_eq!(sharded.contains_seq(&nucs), set.contains_seq(&nucs));
+        assert!(sharded.num_loaded() <= 4);
+
+        sharded.insert_seq(&nucs2);
+        set.insert_seq(&nucs2);
+        assert_eq!(sharded.count(), set.count());
+        // modified shards are written back when they are dropped
+        assert!(sharded.num_loaded() <= 4);
+        sharded.flush();
+        assert!(!dir.join(format!("{MANIFEST_FILENAME}.tmp")).exists());
+        let sharded = ShardedCBL::<K, T>::open(&dir, 4);
+        assert_eq!(sharded.count(), set.count());
+        assert_eq!(sharded.validate(), Ok(()));
+        assert_eq!(
+            sharded.to_cbl().iter().collect_vec(),
+            set.iter().collect_vec()
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_set_ops() {
+        let dir = temp_dir().join(format!("cbl-test-sharded-ops-{}", std::process::id()));
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = random_nucs(&mut rng);
+        let nucs2 = random_nucs(&mut rng);
+        let mut set = CBL::<K, T>::new();
+        let mut set2 = CBL::<K, T>::new();
+        set.insert_seq(&nucs);
+        set2.insert_seq(&nucs2);
+        set2.insert_seq(&nucs[..N / 2]);
+        ShardedCBL::<K, T>::write(&set, 8, dir.join("a"));
+        ShardedCBL::<K, T>::write(&set2, 8, dir.join("b"));
+        let sharded = ShardedCBL::<K, T>::open(dir.join("a"), 2);
+        let sharded2 = ShardedCBL::<K, T>::open(dir.join("b"), 2);
+
+        sharded.apply_to_dir(&sharded2, dir.join("inter"), |a, b| *a &= b);
+        let inter = ShardedCBL::<K, T>::open(dir.join("inter"), 2);
+        assert_eq!(inter.count(), (&mut set & &mut set2).count());
+
+        ShardedCBL::merge_to_dir(&[&sharded, &sharded2], dir.join("union"));
+        let union = ShardedCBL::<K, T>::open(dir.join("union"), 2);
+        assert_eq!(
+            union.to_cbl().iter().collect_vec(),
+            (&mut set | &mut set2).iter().collect_vec()
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,7 +5,10 @@
 use core::cmp::Ordering;
 use num_traits::sign::Unsigned;
 use num_traits::PrimInt;
-use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(transparent)]
@@ -122,7 +125,10 @@ impl<'de, const BYTES: usize> Visitor<'de> for SlicedIntVisitor<BYTES> {
         formatter.write_str("an integer sliced into bytes")
     }
 
-    fn visit_bytes<E: std::error::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        if bytes.len() != BYTES {
+            return Err(E::invalid_length(bytes.len(), &self));
+        }
         Ok(SlicedInt::from_le_bytes(bytes))
     }
 }
@@ -0,0 +1,145 @@
+//! Immutable snapshots of a [`CBL`], serving queries while the set is being modified.
+//!
+//! A snapshot shares the buckets and the prefix structures of the set it was taken from.
+//! When the set is modified afterwards, it only copies the buckets it touches,
+//! along with the prefix bitvector, the tiered vector and the array indexing the buckets on the first modification,
+//! which takes a time proportional to 2^`PREFIX_BITS` plus the number of buckets.
+
+use crate::cbl::*;
+use crate::kmer::Base;
+use core::ops::Deref;
+
+/// An immutable view of a [`CBL`] at the time it was taken, that can be shared between threads.
+///
+/// It dereferences to the [`CBL`] so that every read-only method is available,
+/// and provides the sequence queries that would otherwise need a mutable set.
+pub struct Snapshot<const K: usize, T: Base, const PREFIX_BITS: usize = 24>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    pub(crate) cbl: CBL<K, T, PREFIX_BITS>,
+}
+
+macro_rules! impl_snapshot {
+    ($T:ty) => {
+        impl<const K: usize, const PREFIX_BITS: usize> Snapshot<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Returns the words (necklace and position packed together) used to store the *k*-mers of a sequence.
+            pub fn seq_words(&self, seq: &[u8]) -> Vec<$T> {
+                // an empty set holds the necklace queues
+                let mut queues = if self.cbl.is_canonical() {
+                    CBL::<K, $T, PREFIX_BITS>::new_canonical()
+                } else {
+                    CBL::<K, $T, PREFIX_BITS>::new()
+                };
+                queues.seq_words(seq)
+            }
+
+            /// Returns `true` if the snapshot contains all the *k*-mers of a sequence.
+            pub fn contains_all(&self, seq: &[u8]) -> bool {
+                self.cbl
+                    .contains_words(&self.seq_words(seq))
+                    .into_iter()
+                    .all(|contained| contained)
+            }
+
+            /// For each *k*-mer of a sequence, returns `true` if it is contained in the snapshot.
+            /// The results are in the same order as [`CBL::contains_seq`].
+            pub fn contains_seq(&self, seq: &[u8]) -> Vec<bool> {
+                self.cbl.contains_words(&self.seq_words(seq))
+            }
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> Deref for Snapshot<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            type Target = CBL<K, $T, PREFIX_BITS>;
+
+            #[inline]
+            fn deref(&self) -> &Self::Target {
+                &self.cbl
+            }
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> Clone for Snapshot<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Shares the snapshot, nothing is copied.
+            fn clone(&self) -> Self {
+                Self {
+                    cbl: self.cbl.clone(),
+                }
+            }
+        }
+    };
+}
+
+impl_snapshot!(u32);
+impl_snapshot!(u64);
+impl_snapshot!(u128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const K: usize = 25;
+    type T = u64;
+    const N: usize = 100_000;
+
+    fn assert_send_sync<S: Send + Sync>() {}
+
+    #[test]
+    fn test_snapshot() {
+        assert_send_sync::<Snapshot<K, T>>();
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = (0..N)
+            .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+            .collect::<Vec<_>>();
+        let (first, second) = nucs.split_at(N / 2);
+        let mut cbl = CBL::<K, T>::new_canonical();
+        cbl.insert_seq(first);
+        let count = cbl.count();
+        let hash = cbl.content_hash();
+        let expected = cbl.contains_seq(second);
+        let snapshot = cbl.snapshot();
+        let expected = &expected;
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let snapshot = snapshot.clone();
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        assert!(snapshot.contains_all(first));
+                        assert_eq!(&snapshot.contains_seq(second), expected);
+                        assert_eq!(snapshot.count(), count);
+                    }
+                });
+            }
+            cbl.insert_seq(second);
+            cbl.remove_seq(&first[..(N / 4)]);
+        });
+        assert_eq!(snapshot.count(), count);
+        assert_eq!(snapshot.content_hash(), hash);
+        assert!(snapshot.validate().is_ok());
+        assert!(cbl.contains_all(second));
+        assert!(cbl.validate().is_ok());
+        assert_ne!(cbl.content_hash(), hash);
+    }
+}
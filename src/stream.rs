@@ -0,0 +1,604 @@
+//! Set operations between indexes saved in files, streamed bucket by bucket.
+//!
+//! The indexes are decoded in prefix order, one bucket at a time, and the buckets of the result are spooled
+//! to a temporary file until their number is known, since both formats store it before the buckets.
+//! The memory used is thus proportional to a bucket rather than to the sets,
+//! and the file written is the same as when saving the result of the in-memory operation.
+
+use crate::cbl::*;
+use crate::checksum::{invalid_data, ChecksumReader, ChecksumWriter, CHECKSUM_BYTES};
+use crate::compact::{
+    self, Compression, Compressor, Decoder, Decompressor, Header, StreamBitReader,
+};
+use crate::kmer::Base;
+use crate::sliced_int::SlicedInt;
+use crate::trievec::TrieVec;
+use crate::wordset::WordSet;
+use bincode::{DefaultOptions, Options};
+use iter_set_ops::merge_iters_detailed_by;
+use num_traits::cast::AsPrimitive;
+use num_traits::sign::Unsigned;
+use num_traits::PrimInt;
+use std::fs::{remove_file, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Take, Write};
+use std::path::{Path, PathBuf};
+
+/// Set operation computed by [`CBL::stream_op`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// *k*-mers contained in any of the indexes.
+    Union,
+    /// *k*-mers contained in all the indexes.
+    Intersection,
+    /// *k*-mers contained in the first index and in none of the others.
+    Difference,
+    /// *k*-mers contained in an odd number of indexes.
+    SymmetricDifference,
+}
+
+impl SetOp {
+    /// Returns `true` if a word found in the given indexes, as `(rank, word)` pairs, is in the result.
+    fn keeps<W>(self, details: &[(usize, W)], num_indexes: usize) -> bool {
+        match self {
+            Self::Union => true,
+            Self::Intersection => details.len() == num_indexes,
+            Self::Difference => details.len() == 1 && details[0].0 == 0,
+            Self::SymmetricDifference => details.len() % 2 == 1,
+        }
+    }
+}
+
+fn bincode_options() -> impl Options {
+    DefaultOptions::new()
+        .with_varint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// Content of an index file, without its checksum.
+type ContentReader = Take<ChecksumReader<BufReader<File>>>;
+
+/// Decoder of the payload of an index.
+enum Source<const BYTES: usize> {
+    Bincode {
+        reader: ContentReader,
+        /// Number of buckets left to read.
+        buckets: u64,
+        last_prefix: Option<u32>,
+    },
+    Compact(Decoder<StreamBitReader<BufReader<Decompressor<ContentReader>>>>),
+    Done,
+}
+
+/// Iterator over the words of an index file, in increasing order, keeping a single bucket in memory.
+///
+/// It stops at the first error, which is then returned by [`finish`](Self::finish)
+/// along with the errors found at the end of the file (count and checksum).
+pub(crate) struct IndexReader<const K: usize, T: Base, const PREFIX_BITS: usize>
+where
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+{
+    canonical: bool,
+    count: usize,
+    read: usize,
+    source: Source<{ suffix_bits::<K, PREFIX_BITS>().div_ceil(8) }>,
+    bucket: std::vec::IntoIter<T>,
+    result: Option<io::Result<()>>,
+}
+
+/// Buckets of the result of an operation, written to a temporary file until they can be written as an index.
+struct Spool<const K: usize, const PREFIX_BITS: usize>
+where
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+{
+    path: TempPath,
+    writer: BufWriter<File>,
+    prefix: u32,
+    suffixes: Vec<SlicedInt<{ suffix_bits::<K, PREFIX_BITS>().div_ceil(8) }>>,
+    count: usize,
+    buckets: u64,
+}
+
+/// Path of a temporary file, removed when dropped.
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+impl<const K: usize, const PREFIX_BITS: usize> Spool<K, PREFIX_BITS>
+where
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+{
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(Self {
+            path: TempPath(path),
+            writer,
+            prefix: 0,
+            suffixes: Vec::new(),
+            count: 0,
+            buckets: 0,
+        })
+    }
+
+    /// Adds a word, larger than the previous ones.
+    fn push<T: PrimInt + Unsigned + AsPrimitive<usize>>(&mut self, word: T) -> io::Result<()> {
+        let (prefix, suffix) =
+            WordSet::<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>::split_prefix_suffix(word);
+        if prefix as u32 != self.prefix {
+            self.flush_bucket()?;
+            self.prefix = prefix as u32;
+        }
+        self.suffixes.push(suffix);
+        Ok(())
+    }
+
+    /// Writes the current bucket as a serialized bucket of the bincode format.
+    fn flush_bucket(&mut self) -> io::Result<()> {
+        if self.suffixes.is_empty() {
+            return Ok(());
+        }
+        let container = TrieVec::from_sorted_iter(self.suffixes.drain(..));
+        bincode_options()
+            .serialize_into(&mut self.writer, &(self.prefix, &container))
+            .map_err(bincode_error)?;
+        self.count += container.len();
+        self.buckets += 1;
+        Ok(())
+    }
+
+    /// Writes the spooled buckets as an index in the given [`Format`], followed by a checksum of the bytes written.
+    /// Returns the number of words written.
+    fn write_to<W: Write>(
+        mut self,
+        writer: W,
+        canonical: bool,
+        format: Format,
+    ) -> io::Result<usize> {
+        self.flush_bucket()?;
+        self.writer.flush()?;
+        let mut spool = BufReader::new(File::open(&self.path.0)?);
+        let mut writer = ChecksumWriter::new(writer);
+        let (compression, level) = match format {
+            Format::Bincode => {
+                // same layout as the serialization of a set: its header, its canonicity, its number of words and its map of buckets
+                writer.write_all(&bincode_header(K, PREFIX_BITS))?;
+                let options = bincode_options();
+                options
+                    .serialize_into(&mut writer, &canonical)
+                    .map_err(bincode_error)?;
+                options
+                    .serialize_into(&mut writer, &self.count)
+                    .map_err(bincode_error)?;
+                options
+                    .serialize_into(&mut writer, &self.buckets)
+                    .map_err(bincode_error)?;
+                io::copy(&mut spool, &mut writer)?;
+                writer.finish()?;
+                return Ok(self.count);
+            }
+            Format::Compact => (Compression::None, 0),
+            Format::Zstd(level) => (Compression::Zstd, level),
+        };
+        let header = Header {
+            compression,
+            canonical,
+            k: K,
+            prefix_bits: PREFIX_BITS,
+            count: self.count,
+        };
+        writer.write_all(&header.to_bytes())?;
+        let mut encoder = compact::Encoder::new(
+            Compressor::new(&mut writer, compression, level)?,
+            self.buckets as usize,
+            PREFIX_BITS,
+            suffix_bits::<K, PREFIX_BITS>(),
+        );
+        for _ in 0..self.buckets {
+            let (prefix, mut container): (
+                u32,
+                TrieVec<{ suffix_bits::<K, PREFIX_BITS>().div_ceil(8) }>,
+            ) = bincode_options()
+                .deserialize_from(&mut spool)
+                .map_err(bincode_error)?;
+            let suffixes: Vec<u128> = container.iter_sorted().map(|suffix| suffix.get()).collect();
+            encoder.push_bucket(prefix as usize, &suffixes)?;
+        }
+        encoder.finish()?.finish()?;
+        writer.finish()?;
+        Ok(self.count)
+    }
+}
+
+macro_rules! impl_stream {
+    ($T:ty) => {
+        impl<const K: usize, const PREFIX_BITS: usize> IndexReader<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Opens an index file in any [`Format`] and reads its header.
+            /// Indexes saved in the unversioned bincode layout of older versions have no header to stream from,
+            /// their words are loaded at once instead.
+            pub(crate) fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+                let file = File::open(path)?;
+                let len = file.metadata()?.len();
+                let mut reader = BufReader::new(file);
+                let start = reader.fill_buf()?;
+                let (is_compact, is_bincode) = (
+                    start.starts_with(&compact::MAGIC),
+                    start.starts_with(&BINCODE_MAGIC),
+                );
+                if !is_compact && !is_bincode {
+                    let set = CBL::<K, $T, PREFIX_BITS>::read_from(reader, len)?;
+                    let words: Vec<$T> = set.words().collect();
+                    return Ok(Self {
+                        canonical: set.is_canonical(),
+                        count: words.len(),
+                        read: 0,
+                        source: Source::Done,
+                        bucket: words.into_iter(),
+                        result: None,
+                    });
+                }
+                let content_len = len
+                    .checked_sub(CHECKSUM_BYTES as u64)
+                    .ok_or_else(|| invalid_data("the index is truncated"))?;
+                let mut reader = ChecksumReader::new(reader).take(content_len);
+                let (canonical, count, source) = if is_compact {
+                    let mut bytes = [0u8; Header::BYTES];
+                    reader.read_exact(&mut bytes)?;
+                    let header = CBL::<K, $T, PREFIX_BITS>::read_header(&bytes)?;
+                    let suffix_bits = suffix_bits::<K, PREFIX_BITS>();
+                    let max_bytes = match header.compression {
+                        Compression::None => reader.limit() as usize,
+                        Compression::Zstd => {
+                            compact::max_payload_bytes(header.count, PREFIX_BITS, suffix_bits)
+                        }
+                    };
+                    let bits = StreamBitReader::new(
+                        BufReader::new(Decompressor::new(reader, header.compression)?),
+                        max_bytes,
+                    );
+                    let decoder =
+                        Decoder::from_bits(bits, PREFIX_BITS, suffix_bits).map_err(invalid_data)?;
+                    (header.canonical, header.count, Source::Compact(decoder))
+                } else {
+                    if let (_, Some((k, prefix_bits))) = read_bincode_header(&mut reader)? {
+                        CBL::<K, $T, PREFIX_BITS>::check_params(k, prefix_bits)?;
+                    }
+                    let options = bincode_options();
+                    let canonical: bool = options
+                        .deserialize_from(&mut reader)
+                        .map_err(bincode_error)?;
+                    let count: usize = options
+                        .deserialize_from(&mut reader)
+                        .map_err(bincode_error)?;
+                    let buckets: u64 = options
+                        .deserialize_from(&mut reader)
+                        .map_err(bincode_error)?;
+                    let source = Source::Bincode {
+                        reader,
+                        buckets,
+                        last_prefix: None,
+                    };
+                    (canonical, count, source)
+                };
+                Ok(Self {
+                    canonical,
+                    count,
+                    read: 0,
+                    source,
+                    bucket: Vec::new().into_iter(),
+                    result: None,
+                })
+            }
+
+            /// Returns `true` if the index stores canonical *k*-mers.
+            #[inline]
+            pub(crate) fn is_canonical(&self) -> bool {
+                self.canonical
+            }
+
+            fn next_word(&mut self) -> io::Result<Option<$T>> {
+                match &mut self.source {
+                    Source::Compact(decoder) => match decoder.next() {
+                        Some(word) => Ok(Some(word as $T)),
+                        None => match decoder.error() {
+                            Some(error) => Err(invalid_data(error)),
+                            None => Ok(None),
+                        },
+                    },
+                    Source::Bincode {
+                        reader,
+                        buckets,
+                        last_prefix,
+                    } => loop {
+                        if let Some(word) = self.bucket.next() {
+                            return Ok(Some(word));
+                        }
+                        if *buckets == 0 {
+                            return Ok(None);
+                        }
+                        *buckets -= 1;
+                        let (prefix, mut container): (
+                            u32,
+                            TrieVec<{ suffix_bits::<K, PREFIX_BITS>().div_ceil(8) }>,
+                        ) = bincode_options()
+                            .deserialize_from(&mut *reader)
+                            .map_err(bincode_error)?;
+                        if last_prefix.is_some_and(|last| last >= prefix) {
+                            return Err(invalid_data("the prefixes are not sorted"));
+                        }
+                        *last_prefix = Some(prefix);
+                        WordSet::<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>::check_bucket(
+                            prefix,
+                            &container,
+                        )
+                        .map_err(invalid_data)?;
+                        let bucket: Vec<$T> = container
+                            .iter_sorted()
+                            .map(|suffix| {
+                                WordSet::<PREFIX_BITS, { suffix_bits::<K, PREFIX_BITS>() }>::merge_prefix_suffix(
+                                    prefix as usize,
+                                    suffix,
+                                )
+                            })
+                            .collect();
+                        self.bucket = bucket.into_iter();
+                    },
+                    Source::Done => Ok(self.bucket.next()),
+                }
+            }
+
+            /// Checks the end of the file once all the words have been read: the number of words and the checksum.
+            fn finish_source(&mut self) -> io::Result<()> {
+                if self.read != self.count {
+                    return Err(invalid_data(format!(
+                        "the index contains {} k-mers but {} were expected",
+                        self.read, self.count
+                    )));
+                }
+                let reader = match core::mem::replace(&mut self.source, Source::Done) {
+                    Source::Compact(decoder) => {
+                        // the checksum covers the whole payload, including the bytes that were not decoded
+                        let mut reader = decoder.into_inner().into_inner().into_inner().into_inner();
+                        io::copy(&mut reader, &mut io::sink())?;
+                        reader
+                    }
+                    Source::Bincode { reader, .. } => reader,
+                    Source::Done => return Ok(()),
+                };
+                reader.into_inner().finish()
+            }
+
+            /// Reads the rest of the index and returns the first error found in it, if any.
+            pub(crate) fn finish(mut self) -> io::Result<()> {
+                self.by_ref().for_each(drop);
+                self.result.take().unwrap_or(Ok(()))
+            }
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> Iterator for IndexReader<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            type Item = $T;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                if self.result.is_some() {
+                    return None;
+                }
+                let res = match self.next_word() {
+                    Ok(Some(word)) => {
+                        if CBL::<K, $T, PREFIX_BITS>::split_necklace_pos(word).1 < 2 * K {
+                            self.read += 1;
+                            return Some(word);
+                        }
+                        Err(invalid_data(format!(
+                            "the word {word:#x} stores an invalid position"
+                        )))
+                    }
+                    Ok(None) => self.finish_source(),
+                    Err(error) => Err(error),
+                };
+                self.result = Some(res);
+                None
+            }
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> CBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Computes a set operation between index files and saves the result to `output` in the given [`Format`].
+            /// The indexes are streamed bucket by bucket instead of being loaded, see the [module documentation](crate::stream).
+            /// Returns the number of *k*-mers of the result.
+            pub fn stream_op<P: AsRef<Path>, Q: AsRef<Path>>(
+                op: SetOp,
+                inputs: &[P],
+                output: Q,
+                format: Format,
+            ) -> io::Result<usize> {
+                if inputs.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "there must be at least one index",
+                    ));
+                }
+                let mut readers = inputs
+                    .iter()
+                    .map(IndexReader::<K, $T, PREFIX_BITS>::open)
+                    .collect::<io::Result<Vec<_>>>()?;
+                let canonical = readers[0].is_canonical();
+                if readers.iter().any(|reader| reader.is_canonical() != canonical) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the indexes must all be canonical or all be non-canonical",
+                    ));
+                }
+                let mut spool_path = output.as_ref().as_os_str().to_owned();
+                spool_path.push(".tmp");
+                let mut spool = Spool::<K, PREFIX_BITS>::create(spool_path.into())?;
+                let num_indexes = readers.len();
+                for details in merge_iters_detailed_by(&mut readers, |x, y| x.cmp(y)) {
+                    if op.keeps(&details, num_indexes) {
+                        spool.push(details[0].1)?;
+                    }
+                }
+                // the output is only created once the indexes are entirely read, it can replace one of them
+                for reader in readers {
+                    reader.finish()?;
+                }
+                let file = File::create(output)?;
+                spool.write_to(BufWriter::new(file), canonical, format)
+            }
+
+            /// Predicts the layout of the set saved in an index file for each size of prefixes given in `prefix_bits`,
+            /// as [`predict_prefix_bits_sampled`](Self::predict_prefix_bits_sampled) on a sample keeping each *k*-mer with probability `fraction`.
+            /// The index is streamed instead of being loaded, and only the sampled words are counted.
+            pub fn predict_prefix_bits_from_file<P: AsRef<Path>, I: IntoIterator<Item = usize>>(
+                path: P,
+                fraction: f64,
+                prefix_bits: I,
+            ) -> io::Result<Vec<PrefixPrediction>> {
+                let mut reader = IndexReader::<K, $T, PREFIX_BITS>::open(path)?;
+                let predictions = Self::predict_from_words(
+                    reader.by_ref().filter(|&word| Self::is_sampled(word, fraction)),
+                    fraction,
+                    prefix_bits,
+                );
+                reader.finish()?;
+                Ok(predictions)
+            }
+        }
+    };
+}
+
+impl_stream!(u32);
+impl_stream!(u64);
+impl_stream!(u128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const K: usize = 25;
+    type T = u64;
+    const PREFIX_BITS: usize = 16;
+    const N: usize = 100_000;
+
+    #[test]
+    fn test_stream_op() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = (0..(3 * N))
+            .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+            .collect::<Vec<_>>();
+        let mut first = CBL::<K, T, PREFIX_BITS>::new_canonical();
+        let mut second = CBL::<K, T, PREFIX_BITS>::new_canonical();
+        let mut third = CBL::<K, T, PREFIX_BITS>::new_canonical();
+        first.insert_seq(&nucs[..(2 * N)]);
+        second.insert_seq(&nucs[N..]);
+        third.insert_seq(&nucs[(N / 2)..(N / 2 + 1000)]);
+
+        let dir = std::env::temp_dir();
+        let name = |s: &str| dir.join(format!("cbl-test-stream-{s}-{}", std::process::id()));
+        let mut formats = vec![Format::Bincode, Format::Compact];
+        if cfg!(feature = "zstd") {
+            formats.push(Format::Zstd(3));
+        }
+        let inputs = [name("first"), name("second"), name("third")];
+        let output = name("output");
+        let expected_path = name("expected");
+        for (i, &format) in formats.iter().enumerate() {
+            // the inputs are saved in different formats
+            first.save_to_file_with_format(&inputs[0], format);
+            second.save_to_file_with_format(&inputs[1], formats[(i + 1) % formats.len()]);
+            third.save_to_file_with_format(&inputs[2], Format::Bincode);
+            let cases = [
+                (SetOp::Union, &mut first.clone() | &mut second.clone(), 2),
+                (
+                    SetOp::Intersection,
+                    &mut first.clone() & &mut second.clone(),
+                    2,
+                ),
+                (
+                    SetOp::Difference,
+                    &mut first.clone() - &mut second.clone(),
+                    2,
+                ),
+                (
+                    SetOp::SymmetricDifference,
+                    &mut first.clone() ^ &mut second.clone(),
+                    2,
+                ),
+                (
+                    SetOp::Union,
+                    CBL::<K, T, PREFIX_BITS>::merge(vec![
+                        &mut first.clone(),
+                        &mut second.clone(),
+                        &mut third.clone(),
+                    ]),
+                    3,
+                ),
+                (
+                    SetOp::Difference,
+                    &mut (&mut first.clone() - &mut second.clone()) - &mut third.clone(),
+                    3,
+                ),
+            ];
+            for (op, expected, num_inputs) in cases {
+                let count =
+                    CBL::<K, T, PREFIX_BITS>::stream_op(op, &inputs[..num_inputs], &output, format)
+                        .unwrap();
+                assert_eq!(count, expected.count());
+                expected.save_to_file_with_format(&expected_path, format);
+                if format != Format::Zstd(3) {
+                    assert_eq!(
+                        std::fs::read(&output).unwrap(),
+                        std::fs::read(&expected_path).unwrap()
+                    );
+                }
+                let res = CBL::<K, T, PREFIX_BITS>::load_from_file(&output);
+                assert!(res == expected);
+            }
+        }
+
+        // corrupted inputs are rejected
+        first.save_to_file_with_format(&inputs[0], Format::Compact);
+        let mut bytes = std::fs::read(&inputs[0]).unwrap();
+        let len = bytes.len();
+        bytes[len / 2] ^= 1;
+        std::fs::write(&inputs[0], &bytes).unwrap();
+        assert!(CBL::<K, T, PREFIX_BITS>::stream_op(
+            SetOp::Union,
+            &inputs[..2],
+            &output,
+            Format::Bincode
+        )
+        .is_err());
+        // indexes built with other parameters are rejected
+        for format in formats {
+            first.save_to_file_with_format(&inputs[0], format);
+            let error = CBL::<K, T, 20>::stream_op(SetOp::Union, &inputs[..1], &output, format)
+                .err()
+                .unwrap();
+            assert!(error.to_string().contains("PREFIX_BITS=16"));
+        }
+        for path in inputs.iter().chain([&output, &expected_path]) {
+            let _ = remove_file(path);
+        }
+    }
+}
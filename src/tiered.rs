@@ -0,0 +1,117 @@
+use crate::ffi::TieredVec32 as TieredVec; // default tiered vector
+use crate::ffi::{UniquePtr, WithinUniquePtr};
+use std::sync::Arc;
+
+/// A tiered vector of container ids, shared between its clones until one of them is modified.
+pub struct Tiered {
+    vec: Arc<UniquePtr<TieredVec>>,
+}
+
+// SAFETY: `TieredVec` (cxx/tiered_vec.h) keeps its `Seq::Tiered` in a `mutable` member so that its const methods
+// can call the methods of `Seq::Tiered`, none of which is const-qualified.
+// Only `insert`, `update`, `remove` and `insert_sorted` write to it, through `Seq::Tiered::insert`,
+// `Seq::Tiered::remove`, `Seq::Tiered::insert_sorted` and `helper::replace`, and they are only called through
+// `&mut Tiered` once the vector is no longer shared (see `vec_mut`).
+// The other methods only read it: `len` and `is_empty` read `Seq::Tiered::size`, and `get`, `contains_sorted`,
+// `index_sorted` and `extend_from` (on the copied vector) go through `Seq::Tiered::operator[]`, which walks down
+// the layers to the element and returns a reference to it, without rotating or rebalancing any layer:
+// layers are only rotated by `Seq::Tiered::insert` and `Seq::Tiered::remove`.
+// Concurrent reads through `&Tiered` are therefore data-race free, see `test_concurrent_reads`.
+unsafe impl Send for Tiered {}
+unsafe impl Sync for Tiered {}
+
+impl Tiered {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            vec: Arc::new(TieredVec::new().within_unique_ptr()),
+        }
+    }
+
+    /// Creates a placeholder without allocating the underlying tiered vector.
+    #[inline]
+    pub fn null() -> Self {
+        Self {
+            vec: Arc::new(UniquePtr::null()),
+        }
+    }
+
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        self.vec.is_null()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> u32 {
+        self.vec.get(index)
+    }
+
+    #[inline]
+    pub fn insert(&mut self, index: usize, id: u32) {
+        self.vec_mut().insert(index, id);
+    }
+
+    #[inline]
+    pub fn update(&mut self, index: usize, id: u32) -> u32 {
+        self.vec_mut().update(index, id)
+    }
+
+    #[inline]
+    pub fn remove(&mut self, index: usize) {
+        self.vec_mut().remove(index);
+    }
+
+    /// Returns the underlying tiered vector to modify it, copying it first if it is shared with a clone.
+    #[inline]
+    fn vec_mut(&mut self) -> &UniquePtr<TieredVec> {
+        if Arc::get_mut(&mut self.vec).is_none() {
+            *self = self.deep_clone();
+        }
+        &self.vec
+    }
+
+    /// Copies the underlying tiered vector, in a single call appending its elements to an empty one.
+    fn deep_clone(&self) -> Self {
+        if self.is_null() {
+            return Self::null();
+        }
+        let res = Self::new();
+        res.vec.extend_from(&self.vec);
+        res
+    }
+}
+
+impl Clone for Tiered {
+    /// Shares the tiered vector with the clone, it is copied when one of them is modified.
+    fn clone(&self) -> Self {
+        Self {
+            vec: Arc::clone(&self.vec),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_tiered() {
+        let mut tiered = Tiered::new();
+        for i in 0..100 {
+            tiered.insert(i, i as u32);
+        }
+        let shared = tiered.clone();
+        tiered.remove(0);
+        tiered.update(0, 42);
+        assert_eq!(tiered.len(), 99);
+        assert_eq!(tiered.get(0), 42);
+        assert_eq!(shared.len(), 100);
+        assert_eq!(shared.get(0), 0);
+        assert_eq!(shared.get(1), 1);
+    }
+}
@@ -0,0 +1,252 @@
+//! Batches of changes to a [`CBL`] that are either kept as a whole or rolled back.
+//!
+//! A transaction logs the words it actually inserts or removes, bucket by bucket.
+//! Rolling back only touches these words, so its cost is proportional to the changes and not to the set.
+
+use crate::cbl::*;
+use crate::kmer::{Base, IntKmer};
+use core::ops::Deref;
+use std::collections::BTreeMap;
+
+/// Changes made to a [`CBL`] inside [`CBL::transaction`].
+///
+/// It dereferences to the [`CBL`] so that the read-only methods see the changes made so far.
+pub struct Transaction<'a, const K: usize, T: Base, const PREFIX_BITS: usize = 24>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    cbl: &'a mut CBL<K, T, PREFIX_BITS>,
+    /// Changed words of each bucket in order, with `true` for an insertion and `false` for a removal.
+    log: BTreeMap<usize, Vec<(T, bool)>>,
+}
+
+macro_rules! impl_transaction {
+    ($T:ty) => {
+        impl<'a, const K: usize, const PREFIX_BITS: usize> Transaction<'a, K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            pub(crate) fn new(cbl: &'a mut CBL<K, $T, PREFIX_BITS>) -> Self {
+                Self {
+                    cbl,
+                    log: BTreeMap::new(),
+                }
+            }
+
+            #[inline]
+            fn record(log: &mut BTreeMap<usize, Vec<($T, bool)>>, word: $T, inserted: bool) {
+                log.entry(CBL::<K, $T, PREFIX_BITS>::word_prefix(word))
+                    .or_default()
+                    .push((word, inserted));
+            }
+
+            /// Checks that a sequence contains at least one *k*-mer.
+            fn check_seq(seq: &[u8]) -> Result<(), String> {
+                if seq.len() < K {
+                    return Err(format!(
+                        "Sequence size ({}) is smaller than K ({})",
+                        seq.len(),
+                        K
+                    ));
+                }
+                Ok(())
+            }
+
+            /// Adds a *k*-mer to the set, the *k*-mer must be packed into an [`IntKmer`].
+            /// Returns `true` the *k*-mer was absent from the set.
+            pub fn insert(&mut self, kmer: IntKmer<K, $T>) -> bool {
+                let word = self.cbl.get_word(kmer);
+                let absent = self.cbl.insert(kmer);
+                if absent {
+                    Self::record(&mut self.log, word, true);
+                }
+                absent
+            }
+
+            /// Removes a *k*-mer from the set, the *k*-mer must be packed into an [`IntKmer`].
+            /// Returns `true` the *k*-mer was present in the set.
+            pub fn remove(&mut self, kmer: IntKmer<K, $T>) -> bool {
+                let word = self.cbl.get_word(kmer);
+                let present = self.cbl.remove(kmer);
+                if present {
+                    Self::record(&mut self.log, word, false);
+                }
+                present
+            }
+
+            /// Adds all the *k*-mers of a sequence to the set.
+            /// Returns the number of *k*-mers that were absent, or an error if the sequence is shorter than `K`.
+            pub fn insert_seq(&mut self, seq: &[u8]) -> Result<usize, String> {
+                Self::check_seq(seq)?;
+                let words = self.cbl.seq_words(seq);
+                let log = &mut self.log;
+                let mut count = 0;
+                self.cbl.insert_words_with(&words, |word| {
+                    Self::record(log, word, true);
+                    count += 1;
+                });
+                Ok(count)
+            }
+
+            /// Removes all the *k*-mers of a sequence from the set.
+            /// Returns the number of *k*-mers that were present, or an error if the sequence is shorter than `K`.
+            pub fn remove_seq(&mut self, seq: &[u8]) -> Result<usize, String> {
+                Self::check_seq(seq)?;
+                let words = self.cbl.seq_words(seq);
+                let log = &mut self.log;
+                let mut count = 0;
+                self.cbl.remove_words_with(&words, |word| {
+                    Self::record(log, word, false);
+                    count += 1;
+                });
+                Ok(count)
+            }
+
+            /// Returns the words added and removed by the transaction so far, sorted by bucket.
+            /// A word inserted and then removed (or the opposite) is not reported.
+            fn net_changes(&self) -> (Vec<$T>, Vec<$T>) {
+                let mut added = Vec::new();
+                let mut removed = Vec::new();
+                for changes in self.log.values() {
+                    let mut changes = changes.clone();
+                    // the sort is stable, the changes of a word stay in order
+                    changes.sort_by_key(|&(word, _)| word);
+                    for group in changes.chunk_by(|(w1, _), (w2, _)| w1 == w2) {
+                        // the changes of a word alternate, only an odd number of them has an effect
+                        if group.len() % 2 == 1 {
+                            let (word, inserted) = group[0];
+                            if inserted {
+                                added.push(word);
+                            } else {
+                                removed.push(word);
+                            }
+                        }
+                    }
+                }
+                (added, removed)
+            }
+
+            /// Returns the *k*-mers added and removed by the transaction so far.
+            pub fn changes(&self) -> (Vec<IntKmer<K, $T>>, Vec<IntKmer<K, $T>>) {
+                let (added, removed) = self.net_changes();
+                (
+                    added
+                        .into_iter()
+                        .map(CBL::<K, $T, PREFIX_BITS>::recover_kmer)
+                        .collect(),
+                    removed
+                        .into_iter()
+                        .map(CBL::<K, $T, PREFIX_BITS>::recover_kmer)
+                        .collect(),
+                )
+            }
+
+            /// Undoes the changes of the transaction.
+            pub(crate) fn rollback(self) {
+                let (added, removed) = self.net_changes();
+                self.cbl.remove_words(&added);
+                self.cbl.insert_words(&removed);
+            }
+        }
+
+        impl<const K: usize, const PREFIX_BITS: usize> Deref for Transaction<'_, K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            type Target = CBL<K, $T, PREFIX_BITS>;
+
+            #[inline]
+            fn deref(&self) -> &Self::Target {
+                self.cbl
+            }
+        }
+    };
+}
+
+impl_transaction!(u32);
+impl_transaction!(u64);
+impl_transaction!(u128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    const K: usize = 25;
+    type T = u64;
+    const N: usize = 10_000;
+
+    #[test]
+    fn test_transaction() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let nucs = (0..N)
+            .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+            .collect::<Vec<_>>();
+        let (first, second) = nucs.split_at(N / 2);
+        let mut cbl = CBL::<K, T>::new_canonical();
+        cbl.insert_seq(first);
+        let hash = cbl.content_hash();
+        let kmer = IntKmer::<K, T>::from_nucs(&second[..K]);
+
+        let mut other = CBL::<K, T>::new_canonical();
+        other.insert_seq(second);
+        let absent = (&mut other - &mut cbl.clone()).count();
+
+        let res: Result<(), String> = cbl.transaction(|tx| {
+            assert_eq!(tx.insert_seq(second)?, absent);
+            tx.remove_seq(&first[..(N / 4)])?;
+            assert!(!tx.insert(kmer));
+            assert!(tx.remove(kmer));
+            assert!(tx.insert(kmer));
+            assert!(tx.contains(kmer));
+            tx.insert_seq(&second[..(K - 1)])?;
+            Ok(())
+        });
+        assert!(res.is_err());
+        assert_eq!(cbl.content_hash(), hash);
+        assert!(cbl.validate().is_ok());
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            cbl.transaction(|tx| -> Result<(), String> {
+                tx.insert_seq(second)?;
+                panic!("failed ingestion");
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(cbl.content_hash(), hash);
+
+        let mut expected = cbl.clone();
+        expected.insert_seq(second);
+        expected.remove_seq(&first[..(N / 4)]);
+        let changes = cbl
+            .transaction(|tx| -> Result<_, String> {
+                tx.insert_seq(second)?;
+                tx.remove_seq(&first[..(N / 4)])?;
+                tx.remove(kmer);
+                tx.insert(kmer);
+                Ok(tx.changes())
+            })
+            .unwrap();
+        assert!(cbl == expected);
+        let (added, removed) = changes;
+        let mut patched = cbl.clone();
+        for &kmer in added.iter() {
+            assert!(patched.remove(kmer));
+        }
+        for &kmer in removed.iter() {
+            assert!(patched.insert(kmer));
+        }
+        assert_eq!(patched.content_hash(), hash);
+    }
+}
@@ -1,11 +1,14 @@
 #![allow(dead_code)]
 
 use crate::bitvector::{TinyBitvector, TinyBitvectorIterator};
-use core::mem::swap;
-use serde::{Deserialize, Serialize};
+use core::mem::{size_of, swap};
+use serde::{
+    de::{self, DeserializeSeed, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 use std::collections::VecDeque;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Trie<const BYTES: usize>(Box<TrieNode<BYTES>>);
 
 impl<const BYTES: usize> Trie<BYTES> {
@@ -34,6 +37,12 @@ impl<const BYTES: usize> Trie<BYTES> {
         self.0.contains(bytes)
     }
 
+    /// Returns the number of nodes that a lookup of `bytes` goes through.
+    #[inline(always)]
+    pub fn lookup_depth(&self, bytes: &[u8]) -> usize {
+        self.0.lookup_depth(bytes)
+    }
+
     #[inline(always)]
     pub fn insert(&mut self, bytes: &[u8]) -> bool {
         self.0.insert(bytes)
@@ -48,9 +57,34 @@ impl<const BYTES: usize> Trie<BYTES> {
     pub fn iter(&self) -> TrieIterator<'_, BYTES> {
         self.0.iter()
     }
+
+    #[inline(always)]
+    pub fn shrink_to_fit(&mut self) {
+        self.0.shrink_to_fit()
+    }
+
+    /// Returns the number of bytes allocated on the heap by the trie.
+    #[inline(always)]
+    pub fn heap_size(&self) -> usize {
+        let (nodes, children) = self.0.heap_size_parts();
+        nodes + children
+    }
+
+    /// Returns the number of bytes allocated on the heap for the nodes and for the arrays of children.
+    #[inline(always)]
+    pub fn heap_size_parts(&self) -> (usize, usize) {
+        self.0.heap_size_parts()
+    }
+
+    /// Checks that every inner node has one non-empty child per bit set and that leaves have no children.
+    /// Returns the number of elements in the trie.
+    #[inline]
+    pub fn validate(&self) -> Result<usize, String> {
+        self.0.validate()
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 struct TrieNode<const BYTES: usize> {
     bv: TinyBitvector,
     children: Vec<Trie<BYTES>>,
@@ -115,6 +149,21 @@ impl<const BYTES: usize> TrieNode<BYTES> {
         trie.bv.contains(index)
     }
 
+    pub fn lookup_depth(&self, bytes: &[u8]) -> usize {
+        assert_eq!(bytes.len(), BYTES, "The trie takes slices of {BYTES} bytes");
+        let mut trie = self;
+        let mut depth = 1;
+        for &index in &bytes[..BYTES - 1] {
+            if !trie.bv.contains(index) {
+                break;
+            }
+            let rank = trie.bv.rank(index);
+            trie = &trie.children[rank].0;
+            depth += 1;
+        }
+        depth
+    }
+
     pub fn insert(&mut self, bytes: &[u8]) -> bool {
         assert_eq!(bytes.len(), BYTES, "The trie takes slices of {BYTES} bytes");
         let mut trie = self;
@@ -143,7 +192,9 @@ impl<const BYTES: usize> TrieNode<BYTES> {
             trie = &mut trie.children[rank].0;
         }
         let index = bytes[BYTES - 1];
-        trie.bv.remove(index);
+        if !trie.bv.remove(index) {
+            return false;
+        }
         if !trie.bv.is_empty() {
             return true;
         }
@@ -161,6 +212,58 @@ impl<const BYTES: usize> TrieNode<BYTES> {
         true
     }
 
+    pub fn shrink_to_fit(&mut self) {
+        let mut stack = vec![self];
+        while let Some(trie) = stack.pop() {
+            trie.children.shrink_to_fit();
+            for child in trie.children.iter_mut() {
+                stack.push(&mut child.0);
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<usize, String> {
+        let mut count = 0;
+        let mut stack = vec![(self, 0)];
+        while let Some((trie, depth)) = stack.pop() {
+            if depth == BYTES - 1 {
+                if !trie.children.is_empty() {
+                    return Err(format!("a leaf at depth {depth} has children"));
+                }
+                count += trie.bv.count();
+                continue;
+            }
+            if trie.children.len() != trie.bv.count() {
+                return Err(format!(
+                    "a node at depth {depth} has {} children for {} bits set",
+                    trie.children.len(),
+                    trie.bv.count()
+                ));
+            }
+            for child in trie.children.iter() {
+                if child.is_empty() {
+                    return Err(format!("a node at depth {} is empty", depth + 1));
+                }
+                stack.push((&child.0, depth + 1));
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn heap_size_parts(&self) -> (usize, usize) {
+        let mut nodes = 0;
+        let mut children = 0;
+        let mut stack = vec![self];
+        while let Some(trie) = stack.pop() {
+            nodes += size_of::<Self>();
+            children += trie.children.capacity() * size_of::<Trie<BYTES>>();
+            for child in trie.children.iter() {
+                stack.push(&child.0);
+            }
+        }
+        (nodes, children)
+    }
+
     #[inline(always)]
     pub fn iter(&self) -> TrieIterator<'_, BYTES> {
         TrieIterator {
@@ -220,6 +323,103 @@ impl<'a, const BYTES: usize> Iterator for TrieIterator<'a, BYTES> {
     }
 }
 
+/// Deserializes a trie whose root is at the given depth.
+/// Nodes deeper than the last byte are rejected, so that corrupted inputs cannot cause an unbounded recursion.
+struct TrieSeed<const BYTES: usize> {
+    depth: usize,
+}
+
+impl<'de, const BYTES: usize> DeserializeSeed<'de> for TrieSeed<BYTES> {
+    type Value = Trie<BYTES>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        if self.depth >= BYTES {
+            return Err(de::Error::custom(format!(
+                "a trie node is deeper than {BYTES} bytes"
+            )));
+        }
+        deserializer.deserialize_newtype_struct("Trie", self)
+    }
+}
+
+impl<'de, const BYTES: usize> Visitor<'de> for TrieSeed<BYTES> {
+    type Value = Trie<BYTES>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a trie")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        let node = deserializer.deserialize_struct(
+            "TrieNode",
+            &["bv", "children"],
+            TrieNodeVisitor::<BYTES> { depth: self.depth },
+        )?;
+        Ok(Trie(Box::new(node)))
+    }
+}
+
+struct TrieNodeVisitor<const BYTES: usize> {
+    depth: usize,
+}
+
+impl<'de, const BYTES: usize> Visitor<'de> for TrieNodeVisitor<BYTES> {
+    type Value = TrieNode<BYTES>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a trie node")
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+        let bv = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let children = access
+            .next_element_seed(TrieChildrenSeed::<BYTES> {
+                depth: self.depth + 1,
+            })?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(TrieNode { bv, children })
+    }
+}
+
+struct TrieChildrenSeed<const BYTES: usize> {
+    depth: usize,
+}
+
+impl<'de, const BYTES: usize> DeserializeSeed<'de> for TrieChildrenSeed<BYTES> {
+    type Value = Vec<Trie<BYTES>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, const BYTES: usize> Visitor<'de> for TrieChildrenSeed<BYTES> {
+    type Value = Vec<Trie<BYTES>>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("the children of a trie node")
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+        let mut children = Vec::new();
+        while let Some(child) = access.next_element_seed(TrieSeed::<BYTES> { depth: self.depth })? {
+            children.push(child);
+        }
+        Ok(children)
+    }
+}
+
+impl<'de, const BYTES: usize> Deserialize<'de> for Trie<BYTES> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        TrieSeed::<BYTES> { depth: 0 }.deserialize(deserializer)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -259,4 +459,33 @@ mod tests {
         assert_eq!(iter.next(), Some([9, 9, 9]));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn test_trie_validate() {
+        let mut trie = Trie::<3>::new();
+        trie.insert(&[1, 2, 3]);
+        trie.insert(&[1, 2, 4]);
+        trie.insert(&[7, 7, 7]);
+        assert_eq!(trie.validate(), Ok(3));
+        assert!(!trie.remove(&[1, 2, 5]));
+        assert!(trie.remove(&[1, 2, 4]));
+        assert_eq!(trie.validate(), Ok(2));
+        trie.0.children.pop();
+        assert!(trie.validate().is_err());
+    }
+
+    #[test]
+    fn test_trie_serde() {
+        let mut trie = Trie::<3>::new();
+        trie.insert(&[1, 2, 3]);
+        trie.insert(&[7, 7, 7]);
+        let bytes = bincode::serialize(&trie).unwrap();
+        let res: Trie<3> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(
+            res.iter().collect::<Vec<_>>(),
+            trie.iter().collect::<Vec<_>>()
+        );
+        // the same bytes are too deep for a trie of 2 bytes
+        assert!(bincode::deserialize::<Trie<2>>(&bytes).is_err());
+    }
 }
@@ -1,20 +1,118 @@
 mod set_ops;
 
+use crate::bitvector::TinyBitvector;
 use crate::sliced_int::SlicedInt;
 use crate::trie::{Trie, TrieIterator};
+use crate::wordset::MemoryUsage;
+use core::mem::size_of;
 use core::slice::Iter;
-use serde::{Deserialize, Serialize};
+use itertools::Itertools;
+use serde::{
+    de::{self, DeserializeSeed, EnumAccess, SeqAccess, Unexpected, VariantAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 enum TrieOrVec<const BYTES: usize> {
     Vec(Vec<SlicedInt<BYTES>>),
     Trie(Trie<BYTES>, usize),
+    Split(Box<SplitBucket<BYTES>>),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A bucket split into sub-buckets according to the byte of index `depth` of the suffixes.
+#[derive(Debug, Clone, Serialize)]
+struct SplitBucket<const BYTES: usize> {
+    depth: usize,
+    bv: TinyBitvector,
+    children: Vec<TrieVec<BYTES>>,
+    len: usize,
+}
+
+impl<const BYTES: usize> SplitBucket<BYTES> {
+    fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            bv: TinyBitvector::new(),
+            children: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn contains(&self, x: &SlicedInt<BYTES>) -> bool {
+        let index = x.to_be_bytes()[self.depth];
+        if !self.bv.contains(index) {
+            return false;
+        }
+        self.children[self.bv.rank(index)].contains(x)
+    }
+
+    fn lookup_depth(&self, x: &SlicedInt<BYTES>) -> usize {
+        let index = x.to_be_bytes()[self.depth];
+        if !self.bv.contains(index) {
+            return 1;
+        }
+        // sub-buckets are never stored as vectors
+        1 + self.children[self.bv.rank(index)]
+            .lookup_depth(x)
+            .unwrap_or(0)
+    }
+
+    fn insert(&mut self, x: SlicedInt<BYTES>) -> bool {
+        let index = x.to_be_bytes()[self.depth];
+        let rank = self.bv.rank(index);
+        if self.bv.insert(index) {
+            // sub-buckets are never stored as vectors to keep them sorted
+            self.children
+                .insert(rank, TrieVec(TrieOrVec::Trie(Trie::new(), 0)));
+        }
+        let absent = self.children[rank].insert(x);
+        if absent {
+            self.len += 1;
+            self.children[rank].adapt_grow_at(self.depth + 1);
+        }
+        absent
+    }
+
+    fn remove(&mut self, x: &SlicedInt<BYTES>) -> bool {
+        let index = x.to_be_bytes()[self.depth];
+        if !self.bv.contains(index) {
+            return false;
+        }
+        let rank = self.bv.rank(index);
+        let present = self.children[rank].remove(x);
+        if present {
+            self.len -= 1;
+            if self.children[rank].is_empty() {
+                self.children.remove(rank);
+                self.bv.remove(index);
+            } else {
+                self.children[rank].adapt_shrink_at(self.depth + 1);
+            }
+        }
+        present
+    }
+}
+
+/// Representation of a bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum ContainerKind {
+    /// Vector of suffixes.
+    Vec,
+    /// Trie over the bytes of the suffixes.
+    Trie,
+    /// Sub-buckets indexed by a byte of the suffixes.
+    Split,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TrieVec<const BYTES: usize>(TrieOrVec<BYTES>);
 
 impl<const BYTES: usize> TrieVec<BYTES> {
+    /// Buckets larger than this are stored as tries.
+    pub const TRIE_THRESHOLD: usize = 1024;
+    /// Buckets larger than this are split into sub-buckets.
+    pub const SPLIT_THRESHOLD: usize = 1 << 16;
+
     #[inline]
     pub fn new() -> Self {
         Self(TrieOrVec::Vec(Vec::new()))
@@ -25,11 +123,136 @@ impl<const BYTES: usize> TrieVec<BYTES> {
         Self(TrieOrVec::Vec(vec![x]))
     }
 
+    /// Creates a bucket from sorted elements, in the canonical representation for its size.
+    pub fn from_sorted_iter<I: Iterator<Item = SlicedInt<BYTES>>>(it: I) -> Self {
+        let mut res = Self(TrieOrVec::Vec(it.collect()));
+        res.adapt_grow();
+        res
+    }
+
+    /// Returns `true` if the bucket is in the representation that [`from_sorted_iter`](Self::from_sorted_iter) would produce,
+    /// i.e. if it only depends on the content of the bucket.
+    #[inline]
+    pub fn is_canonical(&self) -> bool {
+        self.is_canonical_at(0)
+    }
+
+    fn is_canonical_at(&self, depth: usize) -> bool {
+        let len = self.len();
+        let split = len > Self::SPLIT_THRESHOLD && depth + 1 < BYTES;
+        match &self.0 {
+            TrieOrVec::Vec(vec) => depth == 0 && len <= Self::TRIE_THRESHOLD && vec.is_sorted(),
+            TrieOrVec::Trie(_, _) => !split && (depth > 0 || len > Self::TRIE_THRESHOLD),
+            TrieOrVec::Split(bucket) => {
+                split
+                    && bucket.depth == depth
+                    && bucket
+                        .children
+                        .iter()
+                        .all(|child| child.is_canonical_at(depth + 1))
+            }
+        }
+    }
+
+    /// Checks the structure of the bucket: lengths, size thresholds and sub-buckets.
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_at(0)
+    }
+
+    fn validate_at(&self, depth: usize) -> Result<(), String> {
+        let len = self.len();
+        let splittable = depth + 1 < BYTES;
+        match &self.0 {
+            TrieOrVec::Vec(vec) => {
+                if depth > 0 {
+                    return Err(format!("a sub-bucket at depth {depth} is a vector"));
+                }
+                if len > Self::TRIE_THRESHOLD {
+                    return Err(format!(
+                        "a vector stores {len} elements, more than {}",
+                        Self::TRIE_THRESHOLD
+                    ));
+                }
+                if vec.iter().sorted_unstable().dedup().count() != len {
+                    return Err("a vector contains duplicates".to_string());
+                }
+            }
+            TrieOrVec::Trie(trie, _) => {
+                if splittable && len > Self::SPLIT_THRESHOLD {
+                    return Err(format!(
+                        "a trie stores {len} elements, more than {}",
+                        Self::SPLIT_THRESHOLD
+                    ));
+                }
+                let count = trie.validate()?;
+                if count != len {
+                    return Err(format!(
+                        "a trie stores {count} elements but its length is {len}"
+                    ));
+                }
+            }
+            TrieOrVec::Split(split) => {
+                if split.depth != depth || !splittable {
+                    return Err(format!(
+                        "a bucket at depth {depth} is split on byte {}",
+                        split.depth
+                    ));
+                }
+                if split.children.len() != split.bv.count() {
+                    return Err(format!(
+                        "a split bucket has {} sub-buckets for {} bits set",
+                        split.children.len(),
+                        split.bv.count()
+                    ));
+                }
+                let count: usize = split.children.iter().map(|child| child.len()).sum();
+                if count != len {
+                    return Err(format!(
+                        "a split bucket stores {count} elements but its length is {len}"
+                    ));
+                }
+                for (index, child) in split.bv.iter().zip(split.children.iter()) {
+                    if child.is_empty() {
+                        return Err(format!("a sub-bucket at depth {} is empty", depth + 1));
+                    }
+                    child.validate_at(depth + 1)?;
+                    if child.iter().any(|x| x.to_be_bytes()[depth] != index) {
+                        return Err(format!(
+                            "a sub-bucket for byte {index} contains an element of another one"
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         match &self.0 {
             TrieOrVec::Vec(vec) => vec.len(),
             TrieOrVec::Trie(_, len) => *len,
+            TrieOrVec::Split(split) => split.len,
+        }
+    }
+
+    /// Returns the representation of the bucket.
+    #[inline]
+    pub fn kind(&self) -> ContainerKind {
+        match &self.0 {
+            TrieOrVec::Vec(_) => ContainerKind::Vec,
+            TrieOrVec::Trie(_, _) => ContainerKind::Trie,
+            TrieOrVec::Split(_) => ContainerKind::Split,
+        }
+    }
+
+    /// Returns the number of trie nodes, split levels included, that a lookup of `x` goes through,
+    /// or `None` if the bucket is stored as a vector.
+    pub fn lookup_depth(&self, x: &SlicedInt<BYTES>) -> Option<usize> {
+        match &self.0 {
+            TrieOrVec::Vec(_) => None,
+            TrieOrVec::Trie(trie, _) => Some(trie.lookup_depth(&x.to_be_bytes())),
+            TrieOrVec::Split(split) => Some(split.lookup_depth(x)),
         }
     }
 
@@ -38,6 +261,13 @@ impl<const BYTES: usize> TrieVec<BYTES> {
         match &self.0 {
             TrieOrVec::Vec(vec) => vec.len(),
             TrieOrVec::Trie(trie, _) => trie.count_nodes(),
+            TrieOrVec::Split(split) => {
+                1 + split
+                    .children
+                    .iter()
+                    .map(|child| child.count_nodes())
+                    .sum::<usize>()
+            }
         }
     }
 
@@ -46,6 +276,7 @@ impl<const BYTES: usize> TrieVec<BYTES> {
         match &self.0 {
             TrieOrVec::Vec(vec) => vec.is_empty(),
             TrieOrVec::Trie(_, len) => *len == 0,
+            TrieOrVec::Split(split) => split.len == 0,
         }
     }
 
@@ -55,17 +286,70 @@ impl<const BYTES: usize> TrieVec<BYTES> {
             TrieOrVec::Vec(vec) => {
                 vec.clear();
             }
-            TrieOrVec::Trie(_, _) => {
+            TrieOrVec::Trie(_, _) | TrieOrVec::Split(_) => {
                 self.0 = TrieOrVec::Vec(Vec::new());
             }
         }
     }
 
+    /// Releases the unused capacity of the bucket.
+    pub fn shrink_to_fit(&mut self) {
+        match &mut self.0 {
+            TrieOrVec::Vec(vec) => vec.shrink_to_fit(),
+            TrieOrVec::Trie(trie, _) => trie.shrink_to_fit(),
+            TrieOrVec::Split(split) => {
+                split.children.shrink_to_fit();
+                for child in split.children.iter_mut() {
+                    child.shrink_to_fit();
+                }
+            }
+        }
+    }
+
+    /// Returns the number of bytes allocated on the heap by the bucket.
+    pub fn heap_size(&self) -> usize {
+        match &self.0 {
+            TrieOrVec::Vec(vec) => vec.capacity() * size_of::<SlicedInt<BYTES>>(),
+            TrieOrVec::Trie(trie, _) => trie.heap_size(),
+            TrieOrVec::Split(split) => {
+                size_of::<SplitBucket<BYTES>>()
+                    + split.children.capacity() * size_of::<Self>()
+                    + split
+                        .children
+                        .iter()
+                        .map(|child| child.heap_size())
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// Adds the heap memory used by the bucket to `usage`.
+    pub fn add_memory_usage(&self, usage: &mut MemoryUsage) {
+        match &self.0 {
+            TrieOrVec::Vec(vec) => {
+                usage.vec_buckets += vec.capacity() * size_of::<SlicedInt<BYTES>>();
+            }
+            TrieOrVec::Trie(trie, _) => {
+                let (nodes, children) = trie.heap_size_parts();
+                usage.trie_nodes += nodes;
+                usage.trie_children += children;
+            }
+            TrieOrVec::Split(split) => {
+                usage.split_buckets +=
+                    size_of::<SplitBucket<BYTES>>() + split.children.capacity() * size_of::<Self>();
+                for child in split.children.iter() {
+                    child.add_memory_usage(usage);
+                }
+            }
+        }
+    }
+
     #[inline]
     pub fn contains(&self, x: &SlicedInt<BYTES>) -> bool {
         match &self.0 {
             TrieOrVec::Vec(vec) => vec.contains(x),
             TrieOrVec::Trie(trie, _) => trie.contains(&x.to_be_bytes()),
+            TrieOrVec::Split(split) => split.contains(x),
         }
     }
 
@@ -78,6 +362,7 @@ impl<const BYTES: usize> TrieVec<BYTES> {
                 }
                 absent
             }
+            TrieOrVec::Split(split) => split.insert(x),
             TrieOrVec::Vec(vec) => {
                 if !vec.contains(&x) {
                     vec.push(x);
@@ -97,6 +382,7 @@ impl<const BYTES: usize> TrieVec<BYTES> {
                 }
                 present
             }
+            TrieOrVec::Split(split) => split.remove(x),
             TrieOrVec::Vec(vec) => {
                 if let Some(i) = vec.iter().position(|y| y == x) {
                     vec.swap_remove(i);
@@ -129,7 +415,7 @@ impl<const BYTES: usize> TrieVec<BYTES> {
                     }
                 }
             }
-            TrieOrVec::Trie(_trie, _len) => {
+            TrieOrVec::Trie(_, _) | TrieOrVec::Split(_) => {
                 self.insert_iter(it);
             }
         }
@@ -161,32 +447,83 @@ impl<const BYTES: usize> TrieVec<BYTES> {
                     vec.swap_remove(i);
                 }
             }
-            TrieOrVec::Trie(_trie, _len) => {
+            TrieOrVec::Trie(_, _) | TrieOrVec::Split(_) => {
                 self.remove_iter(it);
             }
         }
     }
 
     pub fn as_trie(&mut self) {
-        if let TrieOrVec::Vec(vec) = &self.0 {
-            let mut trie = Trie::new();
-            for x in vec.iter() {
-                trie.insert(&x.to_be_bytes());
+        match &self.0 {
+            TrieOrVec::Vec(vec) => {
+                let mut trie = Trie::new();
+                for x in vec.iter() {
+                    trie.insert(&x.to_be_bytes());
+                }
+                self.0 = TrieOrVec::Trie(trie, vec.len());
+            }
+            TrieOrVec::Split(split) => {
+                let mut trie = Trie::new();
+                for x in self.iter() {
+                    trie.insert(&x.to_be_bytes());
+                }
+                self.0 = TrieOrVec::Trie(trie, split.len);
             }
-            self.0 = TrieOrVec::Trie(trie, vec.len());
+            TrieOrVec::Trie(_, _) => (),
         }
     }
 
     pub fn as_vec(&mut self) {
-        if let TrieOrVec::Trie(trie, _) = &self.0 {
-            let vec = trie
-                .iter()
-                .map(|bytes: [u8; BYTES]| SlicedInt::from_be_bytes(&bytes))
-                .collect();
+        if let TrieOrVec::Trie(_, _) | TrieOrVec::Split(_) = &self.0 {
+            let vec = self.iter().collect();
             self.0 = TrieOrVec::Vec(vec);
         }
     }
 
+    /// Splits the bucket into sub-buckets according to the byte of index `depth`.
+    pub fn as_split(&mut self, depth: usize) {
+        if let TrieOrVec::Split(_) = &self.0 {
+            return;
+        }
+        let mut split = SplitBucket::new(depth);
+        for x in self.iter() {
+            split.insert(x);
+        }
+        self.0 = TrieOrVec::Split(Box::new(split));
+    }
+
+    /// Adapts the representation of a bucket after insertions.
+    #[inline]
+    pub fn adapt_grow(&mut self) {
+        self.adapt_grow_at(0);
+    }
+
+    /// Adapts the representation of a bucket after deletions.
+    #[inline]
+    pub fn adapt_shrink(&mut self) {
+        self.adapt_shrink_at(0);
+    }
+
+    fn adapt_grow_at(&mut self, depth: usize) {
+        let len = self.len();
+        if len > Self::SPLIT_THRESHOLD && depth + 1 < BYTES {
+            self.as_split(depth);
+        } else if len > Self::TRIE_THRESHOLD {
+            if let TrieOrVec::Vec(_) = &self.0 {
+                self.as_trie();
+            }
+        }
+    }
+
+    fn adapt_shrink_at(&mut self, depth: usize) {
+        let len = self.len();
+        if depth == 0 && len <= Self::TRIE_THRESHOLD {
+            self.as_vec();
+        } else if len <= Self::SPLIT_THRESHOLD / 2 {
+            self.as_trie();
+        }
+    }
+
     #[inline]
     pub fn sort(&mut self) {
         if let TrieOrVec::Vec(vec) = &mut self.0 {
@@ -202,6 +539,18 @@ impl<const BYTES: usize> TrieVec<BYTES> {
         match &self.0 {
             TrieOrVec::Vec(vec) => TrieVecIterator::Vec(vec.iter()),
             TrieOrVec::Trie(trie, _) => TrieVecIterator::Trie(trie.iter()),
+            TrieOrVec::Split(split) => TrieVecIterator::Split(split.children.iter(), None),
+        }
+    }
+
+    /// Returns an iterator over the elements in increasing order, sorting a copy of the vector if needed.
+    #[inline]
+    pub fn iter_ordered(&self) -> TrieVecIterator<'_, BYTES> {
+        match &self.0 {
+            TrieOrVec::Vec(vec) if !vec.is_sorted() => {
+                TrieVecIterator::Sorted(vec.iter().copied().sorted_unstable())
+            }
+            _ => self.iter(),
         }
     }
 
@@ -216,6 +565,7 @@ impl<const BYTES: usize> TrieVec<BYTES> {
                 TrieVecIterator::Vec(vec.iter())
             }
             TrieOrVec::Trie(trie, _) => TrieVecIterator::Trie(trie.iter()),
+            TrieOrVec::Split(split) => TrieVecIterator::Split(split.children.iter(), None),
         }
     }
 }
@@ -228,7 +578,12 @@ impl<const BYTES: usize> Default for TrieVec<BYTES> {
 
 pub enum TrieVecIterator<'a, const BYTES: usize> {
     Vec(Iter<'a, SlicedInt<BYTES>>),
+    Sorted(std::vec::IntoIter<SlicedInt<BYTES>>),
     Trie(TrieIterator<'a, BYTES>),
+    Split(
+        Iter<'a, TrieVec<BYTES>>,
+        Option<Box<TrieVecIterator<'a, BYTES>>>,
+    ),
 }
 
 impl<'a, const BYTES: usize> Iterator for TrieVecIterator<'a, BYTES> {
@@ -236,7 +591,288 @@ impl<'a, const BYTES: usize> Iterator for TrieVecIterator<'a, BYTES> {
     fn next(&mut self) -> Option<Self::Item> {
         match self {
             Self::Vec(iter) => iter.next().copied(),
+            Self::Sorted(iter) => iter.next(),
             Self::Trie(iter) => iter.next().map(|bytes| SlicedInt::from_be_bytes(&bytes)),
+            Self::Split(children, current) => loop {
+                if let Some(x) = current.as_mut().and_then(|iter| iter.next()) {
+                    return Some(x);
+                }
+                *current = Some(Box::new(children.next()?.iter()));
+            },
+        }
+    }
+}
+
+/// Deserializes a bucket at the given depth of splitting.
+/// Sub-buckets deeper than the last byte are rejected, so that corrupted inputs cannot cause an unbounded recursion.
+struct TrieVecSeed<const BYTES: usize> {
+    depth: usize,
+}
+
+impl<'de, const BYTES: usize> DeserializeSeed<'de> for TrieVecSeed<BYTES> {
+    type Value = TrieVec<BYTES>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        if self.depth >= BYTES {
+            return Err(de::Error::custom(format!(
+                "a sub-bucket is deeper than {BYTES} bytes"
+            )));
+        }
+        deserializer.deserialize_newtype_struct("TrieVec", self)
+    }
+}
+
+impl<'de, const BYTES: usize> Visitor<'de> for TrieVecSeed<BYTES> {
+    type Value = TrieVec<BYTES>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a bucket")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer
+            .deserialize_enum(
+                "TrieOrVec",
+                &["Vec", "Trie", "Split"],
+                TrieOrVecVisitor::<BYTES> { depth: self.depth },
+            )
+            .map(TrieVec)
+    }
+}
+
+struct TrieOrVecVisitor<const BYTES: usize> {
+    depth: usize,
+}
+
+impl<'de, const BYTES: usize> Visitor<'de> for TrieOrVecVisitor<BYTES> {
+    type Value = TrieOrVec<BYTES>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a vector, a trie or a split bucket")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        let (variant, access): (u32, _) = data.variant()?;
+        match variant {
+            0 => access.newtype_variant().map(TrieOrVec::Vec),
+            1 => access.tuple_variant(2, TrieVariantVisitor::<BYTES> {}),
+            2 => access
+                .newtype_variant_seed(SplitBucketSeed::<BYTES> { depth: self.depth })
+                .map(|split| TrieOrVec::Split(Box::new(split))),
+            _ => Err(de::Error::invalid_value(
+                Unexpected::Unsigned(variant as u64),
+                &self,
+            )),
+        }
+    }
+}
+
+struct TrieVariantVisitor<const BYTES: usize> {}
+
+impl<'de, const BYTES: usize> Visitor<'de> for TrieVariantVisitor<BYTES> {
+    type Value = TrieOrVec<BYTES>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a trie and its length")
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+        let trie = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let len = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(TrieOrVec::Trie(trie, len))
+    }
+}
+
+struct SplitBucketSeed<const BYTES: usize> {
+    depth: usize,
+}
+
+impl<'de, const BYTES: usize> DeserializeSeed<'de> for SplitBucketSeed<BYTES> {
+    type Value = SplitBucket<BYTES>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_struct("SplitBucket", &["depth", "bv", "children", "len"], self)
+    }
+}
+
+impl<'de, const BYTES: usize> Visitor<'de> for SplitBucketSeed<BYTES> {
+    type Value = SplitBucket<BYTES>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a split bucket")
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+        let depth = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let bv = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let children = access
+            .next_element_seed(SubBucketsSeed::<BYTES> {
+                depth: self.depth + 1,
+            })?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        let len = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+        Ok(SplitBucket {
+            depth,
+            bv,
+            children,
+            len,
+        })
+    }
+}
+
+struct SubBucketsSeed<const BYTES: usize> {
+    depth: usize,
+}
+
+impl<'de, const BYTES: usize> DeserializeSeed<'de> for SubBucketsSeed<BYTES> {
+    type Value = Vec<TrieVec<BYTES>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, const BYTES: usize> Visitor<'de> for SubBucketsSeed<BYTES> {
+    type Value = Vec<TrieVec<BYTES>>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("the sub-buckets of a split bucket")
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+        let mut children = Vec::new();
+        while let Some(child) =
+            access.next_element_seed(TrieVecSeed::<BYTES> { depth: self.depth })?
+        {
+            children.push(child);
+        }
+        Ok(children)
+    }
+}
+
+impl<'de, const BYTES: usize> Deserialize<'de> for TrieVec<BYTES> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        TrieVecSeed::<BYTES> { depth: 0 }.deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    const BYTES: usize = 3;
+
+    #[test]
+    fn test_split() {
+        let n = 4 * TrieVec::<BYTES>::SPLIT_THRESHOLD;
+        let mut container = TrieVec::<BYTES>::new();
+        for i in (0..n).rev() {
+            container.insert(SlicedInt::from_int(i as u32));
+            container.adapt_grow();
+        }
+        assert!(matches!(container.0, TrieOrVec::Split(_)));
+        assert_eq!(container.len(), n);
+        assert!(container.contains(&SlicedInt::from_int(42u32)));
+        assert!(!container.contains(&SlicedInt::from_int(n as u32)));
+        assert_eq!(
+            container.iter().map(|x| x.get::<u32>()).collect_vec(),
+            (0..n as u32).collect_vec()
+        );
+        for i in 0..(n - 10) {
+            assert!(container.remove(&SlicedInt::from_int(i as u32)));
+            container.adapt_shrink();
+        }
+        assert!(matches!(container.0, TrieOrVec::Vec(_)));
+        assert_eq!(container.len(), 10);
+    }
+
+    #[test]
+    fn test_canonical() {
+        let mut container = TrieVec::<BYTES>::new();
+        for i in (0..100u32).rev() {
+            container.insert(SlicedInt::from_int(i));
+        }
+        assert!(!container.is_canonical());
+        assert_eq!(
+            container
+                .iter_ordered()
+                .map(|x| x.get::<u32>())
+                .collect_vec(),
+            (0..100).collect_vec()
+        );
+        let canonical = TrieVec::from_sorted_iter(container.iter_ordered());
+        assert!(canonical.is_canonical());
+        let n = 2 * TrieVec::<BYTES>::TRIE_THRESHOLD;
+        let mut container = TrieVec::<BYTES>::new();
+        for i in 0..n {
+            container.insert(SlicedInt::from_int(i as u32));
+            container.adapt_grow();
+        }
+        for i in 0..(n / 2 + 1) {
+            container.remove(&SlicedInt::from_int(i as u32));
+        }
+        assert!(!container.is_canonical());
+        container.adapt_shrink();
+        assert!(container.is_canonical());
+    }
+
+    #[test]
+    fn test_validate() {
+        let n = 2 * TrieVec::<BYTES>::SPLIT_THRESHOLD;
+        let mut container = TrieVec::<BYTES>::new();
+        for i in 0..n {
+            container.insert(SlicedInt::from_int(i as u32));
+            container.adapt_grow();
+            if i == 0 || i == n / 2 {
+                assert_eq!(container.validate(), Ok(()));
+            }
+        }
+        assert!(matches!(container.0, TrieOrVec::Split(_)));
+        assert_eq!(container.validate(), Ok(()));
+        let x = SlicedInt::<BYTES>::from_int(7u32);
+        assert!(TrieVec(TrieOrVec::Vec(vec![x, x])).validate().is_err());
+        let mut trie = Trie::new();
+        trie.insert(&x.to_be_bytes());
+        assert!(TrieVec(TrieOrVec::Trie(trie, 2)).validate().is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let n = 2 * TrieVec::<BYTES>::SPLIT_THRESHOLD;
+        let container =
+            TrieVec::<BYTES>::from_sorted_iter((0..n).map(|i| SlicedInt::from_int(i as u32)));
+        assert!(matches!(container.0, TrieOrVec::Split(_)));
+        let bytes = bincode::serialize(&container).unwrap();
+        let res: TrieVec<BYTES> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(res.validate(), Ok(()));
+        assert!(res.iter().eq(container.iter()));
+        // a bucket split on every byte is deeper than the suffixes
+        let mut nested = TrieVec::<1>::new_with_one(SlicedInt::from_int(1u8));
+        for depth in (0..2).rev() {
+            let mut split = SplitBucket::new(depth);
+            split.bv.insert(1);
+            split.children.push(nested);
+            split.len = 1;
+            nested = TrieVec(TrieOrVec::Split(Box::new(split)));
         }
+        let bytes = bincode::serialize(&nested).unwrap();
+        assert!(bincode::deserialize::<TrieVec<1>>(&bytes).is_err());
+        let mut bytes = bincode::serialize(&TrieVec::<BYTES>::new()).unwrap();
+        bytes[0] = 3;
+        assert!(bincode::deserialize::<TrieVec<BYTES>>(&bytes).is_err());
     }
 }
@@ -0,0 +1,559 @@
+//! Write-ahead log of the updates of an index, replayed on top of its last snapshot.
+//!
+//! The log starts with a header identifying the index, followed by one record per update:
+//! the kind of update, the length of the sequence in bytes, the sequence and a checksum of the record.
+//! The log ends at the first incomplete or corrupted record, which is what a crash in the middle of an append leaves behind.
+
+use crate::cbl::*;
+use crate::checksum::{checksum, invalid_data, Fnv, CHECKSUM_BYTES};
+use crate::kmer::Base;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes identifying a log.
+const MAGIC: [u8; 4] = *b"CBLW";
+/// Version of the log format.
+const VERSION: u8 = 1;
+/// Size in bytes of the header of a log.
+const HEADER_BYTES: usize = MAGIC.len() + 4;
+/// Size in bytes of a record, excluding its sequence.
+const RECORD_BYTES: usize = 1 + 8 + CHECKSUM_BYTES;
+
+/// Kind of update recorded in a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Update {
+    /// Insertion of the *k*-mers of a sequence.
+    Insert = 0,
+    /// Removal of the *k*-mers of a sequence.
+    Remove = 1,
+}
+
+impl Update {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Insert),
+            1 => Some(Self::Remove),
+            _ => None,
+        }
+    }
+}
+
+/// When the updates appended to a log are synchronized to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// After every update: an update survives a crash of the machine as soon as it is applied.
+    #[default]
+    Always,
+    /// After every `n` updates.
+    Every(usize),
+    /// Only on checkpoints and explicit calls to `sync`: an update survives a crash of the process, but not of the machine.
+    Never,
+}
+
+/// Returns the path of the log of an index, i.e. the path of the index followed by `.wal`.
+pub fn log_path<P: AsRef<Path>>(index: P) -> PathBuf {
+    let mut path = index.as_ref().as_os_str().to_owned();
+    path.push(".wal");
+    path.into()
+}
+
+/// Returns the header of a log for the given parameters.
+fn header(k: usize, prefix_bits: usize, canonical: bool) -> [u8; HEADER_BYTES] {
+    let mut bytes = [0u8; HEADER_BYTES];
+    bytes[..4].copy_from_slice(&MAGIC);
+    bytes[4] = VERSION;
+    bytes[5] = k as u8;
+    bytes[6] = prefix_bits as u8;
+    bytes[7] = canonical as u8;
+    bytes
+}
+
+/// Fills the buffer, returns `false` if the reader ends before.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Reads the updates recorded in the log at `path`, checking that it was written with the given header.
+/// Returns the length in bytes of the valid part of the log and the number of updates it contains.
+fn replay<F: FnMut(Update, &[u8])>(
+    path: &Path,
+    header: &[u8; HEADER_BYTES],
+    mut apply: F,
+) -> io::Result<(u64, usize)> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut bytes = [0u8; HEADER_BYTES];
+    if !read_full(&mut reader, &mut bytes)? || bytes[..5] != header[..5] {
+        return Err(invalid_data("invalid log header"));
+    }
+    if bytes != *header {
+        return Err(invalid_data("the log was written for another index"));
+    }
+    let mut valid_len = HEADER_BYTES as u64;
+    let mut updates = 0;
+    let mut prefix = [0u8; 9];
+    let mut seq = Vec::new();
+    let mut sum = [0u8; CHECKSUM_BYTES];
+    while read_full(&mut reader, &mut prefix)? {
+        let Some(update) = Update::from_byte(prefix[0]) else {
+            break;
+        };
+        // the length is checked against the file before allocating anything
+        let len = u64::from_le_bytes(prefix[1..].try_into().unwrap());
+        if len > (file_len - valid_len).saturating_sub(RECORD_BYTES as u64) {
+            break;
+        }
+        seq.resize(len as usize, 0);
+        if !read_full(&mut reader, &mut seq)? || !read_full(&mut reader, &mut sum)? {
+            break;
+        }
+        let mut hash = Fnv::new();
+        hash.update(&prefix);
+        hash.update(&seq);
+        if hash.finish() != u64::from_le_bytes(sum) {
+            break;
+        }
+        apply(update, &seq);
+        valid_len += RECORD_BYTES as u64 + len;
+        updates += 1;
+    }
+    Ok((valid_len, updates))
+}
+
+/// Append-only log of updates.
+struct Log {
+    file: File,
+    policy: SyncPolicy,
+    updates: usize,
+    unsynced: usize,
+}
+
+impl Log {
+    /// Creates an empty log, replacing any existing one.
+    fn create(path: &Path, header: &[u8; HEADER_BYTES], policy: SyncPolicy) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(header)?;
+        file.sync_all()?;
+        Ok(Self {
+            file,
+            policy,
+            updates: 0,
+            unsynced: 0,
+        })
+    }
+
+    /// Opens an existing log, passing its updates to `apply` and dropping the incomplete records at its end.
+    fn open<F: FnMut(Update, &[u8])>(
+        path: &Path,
+        header: &[u8; HEADER_BYTES],
+        policy: SyncPolicy,
+        apply: F,
+    ) -> io::Result<Self> {
+        let (valid_len, updates) = replay(path, header, apply)?;
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        if file.metadata()?.len() != valid_len {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            policy,
+            updates,
+            unsynced: 0,
+        })
+    }
+
+    fn append(&mut self, update: Update, seq: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(RECORD_BYTES + seq.len());
+        record.push(update as u8);
+        record.extend_from_slice(&(seq.len() as u64).to_le_bytes());
+        record.extend_from_slice(seq);
+        let sum = checksum(&record);
+        record.extend_from_slice(&sum.to_le_bytes());
+        // a single write keeps the record contiguous, a crash can only cut its end
+        self.file.write_all(&record)?;
+        self.updates += 1;
+        self.unsynced += 1;
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// Removes every update from the log.
+    fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(HEADER_BYTES as u64)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.sync_all()?;
+        self.updates = 0;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Makes a rename in the directory of `path` durable.
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// A [`CBL`] saved as a snapshot file, whose updates are appended to a write-ahead log next to it (see [`log_path`]).
+///
+/// Opening it loads the snapshot and replays the log on top of it, and [`checkpoint`](Self::checkpoint) folds the log into a new snapshot.
+/// Replaying an update that is already in the snapshot has no effect, so a crash during a checkpoint loses nothing.
+///
+/// # Type Parameters
+/// - `K`: the length of the *k*-mers, it must be ≤ 59.
+/// - `T`: the integer type used to store *k*-mers, it must be large enough to store $2k + \lg(2k)$ bits.
+/// - `PREFIX_BITS` (optional): the size of the prefixes in bits.
+pub struct LoggedCBL<const K: usize, T: Base, const PREFIX_BITS: usize = 24>
+where
+    [(); kmer_bits::<K>()]:,
+    [(); PREFIX_BITS.div_ceil(8)]:,
+    [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+    [(); queue_width::<K>()]:,
+{
+    cbl: CBL<K, T, PREFIX_BITS>,
+    path: PathBuf,
+    log: Log,
+    format: Format,
+    checkpoint_interval: Option<usize>,
+}
+
+macro_rules! impl_logged {
+    ($T:ty) => {
+        impl<const K: usize, const PREFIX_BITS: usize> LoggedCBL<K, $T, PREFIX_BITS>
+        where
+            [(); kmer_bits::<K>()]:,
+            [(); PREFIX_BITS.div_ceil(8)]:,
+            [(); suffix_bits::<K, PREFIX_BITS>().div_ceil(8)]:,
+            [(); queue_width::<K>()]:,
+        {
+            /// Saves a set as a snapshot at `path` and starts an empty log next to it.
+            pub fn create<P: AsRef<Path>>(
+                cbl: CBL<K, $T, PREFIX_BITS>,
+                path: P,
+                policy: SyncPolicy,
+            ) -> io::Result<Self> {
+                let path = path.as_ref().to_path_buf();
+                let header = header(K, PREFIX_BITS, cbl.is_canonical());
+                let mut res = Self {
+                    log: Log::create(&log_path(&path), &header, policy)?,
+                    cbl,
+                    path,
+                    format: Format::default(),
+                    checkpoint_interval: None,
+                };
+                res.write_snapshot()?;
+                Ok(res)
+            }
+
+            /// Opens the set saved at `path` and replays the updates of its log.
+            /// An empty log is started if there is none.
+            pub fn open<P: AsRef<Path>>(path: P, policy: SyncPolicy) -> io::Result<Self> {
+                let path = path.as_ref().to_path_buf();
+                let mut cbl = CBL::<K, $T, PREFIX_BITS>::try_load_from_file(&path)?;
+                let header = header(K, PREFIX_BITS, cbl.is_canonical());
+                let log_path = log_path(&path);
+                let log = if log_path.exists() {
+                    Log::open(&log_path, &header, policy, |update, seq| {
+                        Self::apply(&mut cbl, update, seq)
+                    })?
+                } else {
+                    Log::create(&log_path, &header, policy)?
+                };
+                Ok(Self {
+                    cbl,
+                    path,
+                    log,
+                    format: Format::default(),
+                    checkpoint_interval: None,
+                })
+            }
+
+            /// Loads the set saved at `path` with the updates of its log, if any, without modifying them.
+            pub fn load<P: AsRef<Path>>(path: P) -> io::Result<CBL<K, $T, PREFIX_BITS>> {
+                let mut cbl = CBL::<K, $T, PREFIX_BITS>::try_load_from_file(&path)?;
+                let log_path = log_path(&path);
+                if log_path.exists() {
+                    let header = header(K, PREFIX_BITS, cbl.is_canonical());
+                    replay(&log_path, &header, |update, seq| {
+                        Self::apply(&mut cbl, update, seq)
+                    })?;
+                }
+                Ok(cbl)
+            }
+
+            fn apply(cbl: &mut CBL<K, $T, PREFIX_BITS>, update: Update, seq: &[u8]) {
+                match update {
+                    Update::Insert => cbl.insert_seq(seq),
+                    Update::Remove => cbl.remove_seq(seq),
+                }
+            }
+
+            /// Sets the [`Format`] of the snapshots written by checkpoints.
+            #[inline]
+            pub fn set_format(&mut self, format: Format) {
+                self.format = format;
+            }
+
+            /// Makes every update trigger a checkpoint once the log contains `interval` updates, or never if `None`.
+            #[inline]
+            pub fn set_checkpoint_interval(&mut self, interval: Option<usize>) {
+                self.checkpoint_interval = interval;
+            }
+
+            /// Returns the set with every update applied.
+            #[inline]
+            pub fn cbl(&self) -> &CBL<K, $T, PREFIX_BITS> {
+                &self.cbl
+            }
+
+            /// Returns the set with every update applied, closing the log.
+            #[inline]
+            pub fn into_inner(self) -> CBL<K, $T, PREFIX_BITS> {
+                self.cbl
+            }
+
+            /// Returns the number of updates in the log, i.e. since the last checkpoint.
+            #[inline]
+            pub fn pending_updates(&self) -> usize {
+                self.log.updates
+            }
+
+            /// Returns a vector of booleans indicating for each *k*-mer of the sequence if it is in the set.
+            #[inline]
+            pub fn contains_seq(&mut self, seq: &[u8]) -> Vec<bool> {
+                self.cbl.contains_seq(seq)
+            }
+
+            /// Appends the insertion of every *k*-mer of the sequence to the log, and then applies it.
+            /// Sequences shorter than `K` are rejected with an error of kind [`InvalidInput`](io::ErrorKind::InvalidInput).
+            #[inline]
+            pub fn insert_seq(&mut self, seq: &[u8]) -> io::Result<()> {
+                self.update(Update::Insert, seq)
+            }
+
+            /// Appends the removal of every *k*-mer of the sequence to the log, and then applies it.
+            /// Sequences shorter than `K` are rejected with an error of kind [`InvalidInput`](io::ErrorKind::InvalidInput).
+            #[inline]
+            pub fn remove_seq(&mut self, seq: &[u8]) -> io::Result<()> {
+                self.update(Update::Remove, seq)
+            }
+
+            fn update(&mut self, update: Update, seq: &[u8]) -> io::Result<()> {
+                // a record that cannot be applied would make every replay of the log fail, it is never appended
+                if seq.len() < K {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Sequence size ({}) is smaller than K ({K})", seq.len()),
+                    ));
+                }
+                self.log.append(update, seq)?;
+                Self::apply(&mut self.cbl, update, seq);
+                if self
+                    .checkpoint_interval
+                    .is_some_and(|interval| self.log.updates >= interval)
+                {
+                    self.checkpoint()?;
+                }
+                Ok(())
+            }
+
+            /// Synchronizes the updates of the log to disk.
+            #[inline]
+            pub fn sync(&mut self) -> io::Result<()> {
+                self.log.sync()
+            }
+
+            /// Folds the log into a new snapshot and empties it.
+            pub fn checkpoint(&mut self) -> io::Result<()> {
+                self.log.sync()?;
+                self.write_snapshot()?;
+                self.log.clear()
+            }
+
+            /// Atomically replaces the snapshot with the current set.
+            fn write_snapshot(&mut self) -> io::Result<()> {
+                let mut tmp_path = self.path.clone().into_os_string();
+                tmp_path.push(".tmp");
+                let file = File::create(&tmp_path)?;
+                self.cbl.write_to(BufWriter::new(&file), self.format)?;
+                file.sync_all()?;
+                rename(&tmp_path, &self.path)?;
+                sync_parent_dir(&self.path)
+            }
+        }
+    };
+}
+
+impl_logged!(u32);
+impl_logged!(u64);
+impl_logged!(u128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const K: usize = 25;
+    type T = u64;
+
+    fn random_seqs(rng: &mut StdRng, n: usize) -> Vec<Vec<u8>> {
+        (0..n)
+            .map(|_| {
+                (0..200)
+                    .map(|_| u8::bases()[rng.gen_range(0..4)].to_nuc())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cbl-test-wal-{name}-{}", std::process::id()))
+    }
+
+    fn remove_files(path: &Path) {
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(log_path(path)).unwrap();
+    }
+
+    #[test]
+    fn test_replay_checkpoint() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let seqs = random_seqs(&mut rng, 20);
+        let path = temp_path("replay");
+        let mut expected = CBL::<K, T>::new_canonical();
+        expected.insert_seq(&seqs[0]);
+        let mut logged =
+            LoggedCBL::<K, T>::create(expected.clone(), &path, SyncPolicy::Every(4)).unwrap();
+        for (i, seq) in seqs.iter().enumerate().skip(1) {
+            if i % 3 == 0 {
+                logged.remove_seq(&seqs[i - 1]).unwrap();
+                expected.remove_seq(&seqs[i - 1]);
+            } else {
+                logged.insert_seq(seq).unwrap();
+                expected.insert_seq(seq);
+            }
+        }
+        assert!(*logged.cbl() == expected);
+        assert_eq!(logged.pending_updates(), seqs.len() - 1);
+        drop(logged);
+        assert!(LoggedCBL::<K, T>::load(&path).unwrap() == expected);
+        let mut logged = LoggedCBL::<K, T>::open(&path, SyncPolicy::Always).unwrap();
+        assert!(*logged.cbl() == expected);
+        // replaying a log already folded into the snapshot gives the same set
+        let log = std::fs::read(log_path(&path)).unwrap();
+        logged.checkpoint().unwrap();
+        assert_eq!(logged.pending_updates(), 0);
+        assert_eq!(
+            std::fs::metadata(log_path(&path)).unwrap().len(),
+            HEADER_BYTES as u64
+        );
+        std::fs::write(log_path(&path), log).unwrap();
+        assert!(LoggedCBL::<K, T>::load(&path).unwrap() == expected);
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let seqs = random_seqs(&mut rng, 4);
+        let path = temp_path("torn");
+        let mut logged = LoggedCBL::<K, T>::create(CBL::new(), &path, SyncPolicy::Never).unwrap();
+        for seq in seqs[..3].iter() {
+            logged.insert_seq(seq).unwrap();
+        }
+        logged.sync().unwrap();
+        drop(logged);
+        // a crash in the middle of an append leaves an incomplete record
+        let log = std::fs::read(log_path(&path)).unwrap();
+        std::fs::write(log_path(&path), &log[..log.len() - 5]).unwrap();
+        let mut expected = CBL::<K, T>::new();
+        expected.insert_seq(&seqs[0]);
+        expected.insert_seq(&seqs[1]);
+        let mut logged = LoggedCBL::<K, T>::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(logged.pending_updates(), 2);
+        assert!(*logged.cbl() == expected);
+        logged.insert_seq(&seqs[3]).unwrap();
+        expected.insert_seq(&seqs[3]);
+        logged.sync().unwrap();
+        drop(logged);
+        let logged = LoggedCBL::<K, T>::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(logged.pending_updates(), 3);
+        assert!(logged.into_inner() == expected);
+        // a log is only replayed on the index it was written for
+        CBL::<K, T>::new_canonical().save_to_file(&path);
+        assert!(LoggedCBL::<K, T>::open(&path, SyncPolicy::Never).is_err());
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_short_sequence() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let seqs = random_seqs(&mut rng, 2);
+        let path = temp_path("short");
+        let mut logged = LoggedCBL::<K, T>::create(CBL::new(), &path, SyncPolicy::Always).unwrap();
+        logged.insert_seq(&seqs[0]).unwrap();
+        let error = logged.insert_seq(&seqs[1][..(K - 1)]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = logged.remove_seq(&seqs[0][..(K - 1)]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(logged.pending_updates(), 1);
+        drop(logged);
+        let mut expected = CBL::<K, T>::new();
+        expected.insert_seq(&seqs[0]);
+        let logged = LoggedCBL::<K, T>::open(&path, SyncPolicy::Always).unwrap();
+        assert_eq!(logged.pending_updates(), 1);
+        assert!(logged.into_inner() == expected);
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_checkpoint_interval() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let seqs = random_seqs(&mut rng, 10);
+        let path = temp_path("interval");
+        let mut logged = LoggedCBL::<K, T>::create(CBL::new(), &path, SyncPolicy::Always).unwrap();
+        logged.set_checkpoint_interval(Some(4));
+        logged.set_format(Format::Compact);
+        for seq in seqs.iter() {
+            logged.insert_seq(seq).unwrap();
+        }
+        assert_eq!(logged.pending_updates(), 2);
+        let mut folded = CBL::<K, T>::new();
+        for seq in seqs[..8].iter() {
+            folded.insert_seq(seq);
+        }
+        assert!(CBL::<K, T>::load_from_file(&path) == folded);
+        assert!(LoggedCBL::<K, T>::load(&path).unwrap() == *logged.cbl());
+        remove_files(&path);
+    }
+}
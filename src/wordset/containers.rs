@@ -0,0 +1,65 @@
+use crate::trievec::TrieVec;
+use core::ops::Deref;
+use std::sync::Arc;
+
+/// The array of containers of a set, shared with its clones along with the containers themselves.
+/// Modifying a container copies the array and the container if they are shared.
+#[derive(Default)]
+pub struct Containers<const BYTES: usize>(Arc<Vec<Arc<TrieVec<BYTES>>>>);
+
+impl<const BYTES: usize> Containers<BYTES> {
+    /// Returns a container to modify it, copying it first if it is shared with a clone.
+    #[inline]
+    pub fn make_mut(&mut self, id: usize) -> &mut TrieVec<BYTES> {
+        Arc::make_mut(&mut Arc::make_mut(&mut self.0)[id])
+    }
+
+    #[inline]
+    pub fn push(&mut self, container: Arc<TrieVec<BYTES>>) {
+        Arc::make_mut(&mut self.0).push(container);
+    }
+
+    /// Empties a container, a shared container is replaced instead of being copied.
+    #[inline]
+    pub fn clear(&mut self, id: usize) {
+        let container = &mut Arc::make_mut(&mut self.0)[id];
+        match Arc::get_mut(container) {
+            Some(container) => container.clear(),
+            None => *container = Arc::default(),
+        }
+    }
+
+    /// Releases the unused capacity of the array and of the containers that are not shared.
+    pub fn shrink_to_fit(&mut self) {
+        if let Some(containers) = Arc::get_mut(&mut self.0) {
+            containers.shrink_to_fit();
+            for container in containers.iter_mut() {
+                if let Some(container) = Arc::get_mut(container) {
+                    container.shrink_to_fit();
+                }
+            }
+        }
+    }
+}
+
+impl<const BYTES: usize> Clone for Containers<BYTES> {
+    /// Shares the array and the containers with the clone.
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<const BYTES: usize> Deref for Containers<BYTES> {
+    type Target = Vec<Arc<TrieVec<BYTES>>>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<const BYTES: usize> From<Vec<Arc<TrieVec<BYTES>>>> for Containers<BYTES> {
+    fn from(containers: Vec<Arc<TrieVec<BYTES>>>) -> Self {
+        Self(Arc::new(containers))
+    }
+}
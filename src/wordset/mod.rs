@@ -1,28 +1,110 @@
+mod containers;
+mod par_ops;
 mod set_ops;
 
 use crate::bitvector::*;
-use crate::ffi::TieredVec32 as TieredVec; // default tiered vector
-use crate::ffi::{UniquePtr, WithinUniquePtr};
 use crate::sliced_int::SlicedInt;
+use crate::tiered::Tiered;
 use crate::trievec::*;
+use containers::Containers;
+use core::cmp::Reverse;
+use core::mem::size_of;
+use core::ops::Range;
+use itertools::{Either, Itertools};
 use num_traits::cast::AsPrimitive;
 use num_traits::sign::Unsigned;
 use num_traits::PrimInt;
 use serde::{
-    de::{MapAccess, Visitor},
-    ser::SerializeMap,
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::{SerializeMap, SerializeTuple},
     Deserialize, Deserializer, Serialize, Serializer,
 };
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::sync::Arc;
 
+/// A set of words split into a prefix and a suffix, the suffixes being stored in a bucket per prefix.
+///
+/// The buckets, the array of buckets and the prefix structures are reference-counted:
+/// clones share them and only copy the parts they modify.
 pub struct WordSet<const PREFIX_BITS: usize, const SUFFIX_BITS: usize>
 where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     pub(crate) prefixes: Bitvector,
-    pub(crate) tiered: UniquePtr<TieredVec>,
-    pub(crate) suffix_containers: Vec<TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>,
+    pub(crate) tiered: Tiered,
+    pub(crate) suffix_containers: Containers<{ SUFFIX_BITS.div_ceil(8) }>,
     pub(crate) empty_containers: Vec<usize>,
+    /// Number of words stored in the containers, maintained by every operation on the prefix/bucket layout.
+    pub(crate) num_words: usize,
+    /// Sorted (prefix, suffix) pairs, only used while the set is small.
+    pub(crate) small: Vec<(u32, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)>,
+}
+
+/// Breakdown of the memory used by a set, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryUsage {
+    /// Prefix bitvector and its rank structure.
+    pub prefixes: usize,
+    /// Tiered vector mapping prefix ranks to container ids, estimated from its length.
+    pub tiered: usize,
+    /// Array of containers and their reference-counted allocations, including the free containers.
+    pub containers: usize,
+    /// Buckets stored as vectors, including the small layout.
+    pub vec_buckets: usize,
+    /// Nodes of the buckets stored as tries.
+    pub trie_nodes: usize,
+    /// Arrays of children of the trie nodes.
+    pub trie_children: usize,
+    /// Headers and arrays of sub-buckets of the split buckets.
+    pub split_buckets: usize,
+    /// Free containers waiting to be reused, and the list of their ids.
+    pub free_containers: usize,
+}
+
+impl MemoryUsage {
+    /// Returns the total number of bytes.
+    pub fn total(&self) -> usize {
+        self.prefixes
+            + self.tiered
+            + self.containers
+            + self.vec_buckets
+            + self.trie_nodes
+            + self.trie_children
+            + self.split_buckets
+            + self.free_containers
+    }
+}
+
+/// Number of buckets and of nodes stored with a given representation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ContainerStats {
+    /// Number of buckets.
+    pub buckets: usize,
+    /// Total number of nodes of these buckets.
+    pub nodes: usize,
+}
+
+/// Statistics on the buckets of a set.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    /// Number of words in the set.
+    pub count: usize,
+    /// Number of non-empty buckets.
+    pub buckets: usize,
+    /// Proportion of available prefixes used in the set.
+    pub prefix_load: f64,
+    /// `true` if the set uses the small layout, where the buckets are not stored in containers.
+    pub small_layout: bool,
+    /// Number of buckets of each size.
+    pub bucket_sizes: BTreeMap<usize, usize>,
+    /// Number of buckets with each number of nodes.
+    pub bucket_nodes: BTreeMap<usize, usize>,
+    /// Buckets and nodes stored with each representation, empty in the small layout.
+    pub containers: BTreeMap<ContainerKind, ContainerStats>,
+    /// Prefixes and sizes of the largest buckets, by decreasing size.
+    pub largest_buckets: Vec<(usize, usize)>,
+    /// Breakdown of the memory used by the set.
+    pub memory_usage: MemoryUsage,
 }
 
 impl<const PREFIX_BITS: usize, const SUFFIX_BITS: usize> WordSet<PREFIX_BITS, SUFFIX_BITS>
@@ -31,9 +113,29 @@ where
 {
     const PREFIX_BITS: usize = PREFIX_BITS;
     const SUFFIX_BITS: usize = SUFFIX_BITS;
-    const THRESHOLD: usize = 1024;
+    pub(crate) const SMALL_THRESHOLD: usize = 4096;
+    /// Number of largest buckets reported by [`stats`](Self::stats).
+    pub(crate) const STATS_LARGEST_BUCKETS: usize = 10;
 
+    /// Creates an empty set using the small layout, the prefix structures are not allocated until it grows.
     pub fn new() -> Self {
+        assert!(
+            PREFIX_BITS <= 32,
+            "PREFIX_BITS={PREFIX_BITS} but it should be ≤ 32"
+        );
+        assert!(SUFFIX_BITS > 0, "SUFFIX_BITS should be ≠ 0");
+        Self {
+            prefixes: Bitvector::unallocated(),
+            tiered: Tiered::null(),
+            suffix_containers: Containers::default(),
+            empty_containers: Vec::new(),
+            num_words: 0,
+            small: Vec::new(),
+        }
+    }
+
+    /// Creates an empty set using the prefix/bucket layout.
+    pub(crate) fn new_large() -> Self {
         assert!(
             PREFIX_BITS <= 32,
             "PREFIX_BITS={PREFIX_BITS} but it should be ≤ 32"
@@ -41,21 +143,406 @@ where
         assert!(SUFFIX_BITS > 0, "SUFFIX_BITS should be ≠ 0");
         Self {
             prefixes: Bitvector::new_with_bitlength(Self::PREFIX_BITS),
-            tiered: TieredVec::new().within_unique_ptr(),
-            suffix_containers: Vec::new(),
+            tiered: Tiered::new(),
+            suffix_containers: Containers::default(),
             empty_containers: Vec::new(),
+            num_words: 0,
+            small: Vec::new(),
         }
     }
 
-    pub fn count(&self) -> usize {
-        self.suffix_containers
+    /// Creates a set from words sorted in increasing order, possibly with duplicates.
+    ///
+    /// The buckets are filled in a single sequential pass.
+    pub fn from_sorted_iter<
+        T: PrimInt + Unsigned + AsPrimitive<usize>,
+        I: IntoIterator<Item = T>,
+    >(
+        words: I,
+    ) -> Self {
+        let mut words = words.into_iter().dedup().map(Self::split_prefix_suffix);
+        let mut small: Vec<(u32, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)> = Vec::new();
+        while small.len() <= Self::SMALL_THRESHOLD {
+            match words.next() {
+                Some((prefix, suffix)) => {
+                    let word = (prefix as u32, suffix);
+                    if let Some(&last) = small.last() {
+                        assert!(last < word, "The words must be sorted");
+                    }
+                    small.push(word);
+                }
+                None => {
+                    let mut res = Self::new();
+                    res.small = small;
+                    return res;
+                }
+            }
+        }
+        let mut res = Self::new_large();
+        let mut current_prefix = small[0].0 as usize;
+        let mut suffixes = Vec::new();
+        for (prefix, suffix) in small
+            .into_iter()
+            .map(|(prefix, suffix)| (prefix as usize, suffix))
+            .chain(words)
+        {
+            if prefix != current_prefix {
+                assert!(prefix > current_prefix, "The words must be sorted");
+                let mut container = TrieVec::new();
+                container.insert_sorted_iter(suffixes.drain(..));
+                res.push_container(current_prefix, container);
+                current_prefix = prefix;
+            }
+            suffixes.push(suffix);
+        }
+        let mut container = TrieVec::new();
+        container.insert_sorted_iter(suffixes.drain(..));
+        res.push_container(current_prefix, container);
+        res
+    }
+
+    /// Returns `true` if the set uses the small layout (a single sorted vector).
+    #[inline]
+    pub fn is_small(&self) -> bool {
+        self.tiered.is_null()
+    }
+
+    /// Switches from the small layout to the prefix/bucket layout.
+    pub(crate) fn upgrade(&mut self) {
+        if !self.is_small() {
+            return;
+        }
+        let small = core::mem::take(&mut self.small);
+        *self = Self::new_large();
+        for group in small.chunk_by(|(p1, _), (p2, _)| p1 == p2) {
+            let mut container = TrieVec::new();
+            container.insert_sorted_iter(group.iter().map(|&(_, suffix)| suffix));
+            self.push_container(group[0].0 as usize, container);
+        }
+    }
+
+    /// Switches from the prefix/bucket layout to the small layout.
+    pub(crate) fn downgrade(&mut self) {
+        if self.is_small() {
+            return;
+        }
+        let mut small = Vec::with_capacity(self.count());
+        for (rank, prefix) in self.prefixes.iter().enumerate() {
+            let id = self.tiered.get(rank) as usize;
+            small.extend(
+                self.suffix_containers[id]
+                    .iter()
+                    .map(|suffix| (prefix as u32, suffix)),
+            );
+        }
+        small.sort_unstable();
+        *self = Self::new();
+        self.small = small;
+    }
+
+    /// Goes back to the small layout if the set became small enough.
+    #[inline]
+    pub(crate) fn adapt_layout_shrink(&mut self) {
+        if !self.is_small() && self.count() <= Self::SMALL_THRESHOLD / 2 {
+            self.downgrade();
+        }
+    }
+
+    #[inline]
+    fn adapt_layout_grow(&mut self) {
+        if self.is_small() && self.small.len() > Self::SMALL_THRESHOLD {
+            self.upgrade();
+        }
+    }
+
+    /// Returns the (prefix, suffix) pairs of a set using the small layout.
+    #[inline]
+    pub(crate) fn small_words(&self) -> Vec<(usize, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)> {
+        self.small
             .iter()
-            .map(|container| container.len())
-            .sum()
+            .map(|&(prefix, suffix)| (prefix as usize, suffix))
+            .collect()
+    }
+
+    /// Returns the number of bytes allocated on the heap for the buckets (and the small layout).
+    pub(crate) fn buckets_heap_size(&self) -> usize {
+        self.small.capacity() * size_of::<(u32, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)>()
+            + self.containers_size()
+            + self
+                .suffix_containers
+                .iter()
+                .map(|container| container.heap_size())
+                .sum::<usize>()
+            + self.empty_containers.capacity() * size_of::<usize>()
+    }
+
+    /// Returns a breakdown of the memory used by the set.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            prefixes: self.prefixes.heap_size(),
+            containers: self.containers_size(),
+            vec_buckets: self.small.capacity()
+                * size_of::<(u32, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)>(),
+            free_containers: self.empty_containers.capacity() * size_of::<usize>(),
+            ..Default::default()
+        };
+        if self.is_small() {
+            return usage;
+        }
+        usage.tiered = self.tiered.len() * size_of::<u32>();
+        for rank in 0..self.tiered.len() {
+            let id = self.tiered.get(rank) as usize;
+            self.suffix_containers[id].add_memory_usage(&mut usage);
+        }
+        for &id in self.empty_containers.iter() {
+            usage.free_containers += self.suffix_containers[id].heap_size();
+        }
+        usage
+    }
+
+    /// Returns statistics on the buckets of the set, computed in a single pass over the buckets.
+    /// The representation of each bucket is read from its container.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            count: self.count(),
+            buckets: 0,
+            prefix_load: self.prefix_load(),
+            small_layout: self.is_small(),
+            bucket_sizes: BTreeMap::new(),
+            bucket_nodes: BTreeMap::new(),
+            containers: BTreeMap::new(),
+            largest_buckets: Vec::new(),
+            memory_usage: self.memory_usage(),
+        };
+        let buckets = if self.is_small() {
+            // every small bucket would be stored as a vector
+            Either::Left(
+                self.small
+                    .chunk_by(|(p1, _), (p2, _)| p1 == p2)
+                    .map(|group| (group[0].0 as usize, group.len(), group.len(), None)),
+            )
+        } else {
+            Either::Right(self.prefixes.iter().enumerate().map(|(rank, prefix)| {
+                let container = &self.suffix_containers[self.tiered.get(rank) as usize];
+                (
+                    prefix,
+                    container.len(),
+                    container.count_nodes(),
+                    Some(container.kind()),
+                )
+            }))
+        };
+        // min-heap of the largest buckets, the smallest prefix wins a tie
+        let mut largest = BinaryHeap::with_capacity(Self::STATS_LARGEST_BUCKETS + 1);
+        for (prefix, size, nodes, kind) in buckets {
+            stats.buckets += 1;
+            *stats.bucket_sizes.entry(size).or_insert(0) += 1;
+            *stats.bucket_nodes.entry(nodes).or_insert(0) += 1;
+            if let Some(kind) = kind {
+                let container: &mut ContainerStats = stats.containers.entry(kind).or_default();
+                container.buckets += 1;
+                container.nodes += nodes;
+            }
+            largest.push(Reverse((size, Reverse(prefix))));
+            if largest.len() > Self::STATS_LARGEST_BUCKETS {
+                largest.pop();
+            }
+        }
+        stats.largest_buckets = largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, Reverse(prefix)))| (prefix, size))
+            .collect();
+        stats
+    }
+
+    /// Returns the number of bytes used by the array of containers and their reference-counted allocations,
+    /// excluding the content of the containers.
+    #[inline]
+    fn containers_size(&self) -> usize {
+        self.suffix_containers.capacity() * size_of::<Arc<TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>>()
+            + self.suffix_containers.len()
+                * (size_of::<TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>() + 2 * size_of::<usize>())
+    }
+
+    /// Releases the unused capacity of the buckets, without renumbering them.
+    /// The buckets shared with a clone are left as they are.
+    pub fn shrink_to_fit(&mut self) {
+        self.small.shrink_to_fit();
+        self.suffix_containers.shrink_to_fit();
+        self.empty_containers.shrink_to_fit();
+    }
+
+    /// Drops the empty containers, renumbers the live ones in the order of their prefixes
+    /// and releases unused capacity.
+    /// Returns the number of bytes reclaimed.
+    pub fn compact(&mut self) -> usize {
+        let before = self.buckets_heap_size();
+        if !self.is_small() {
+            let mut containers = Vec::with_capacity(self.tiered.len());
+            for rank in 0..self.tiered.len() {
+                let id = self.tiered.get(rank) as usize;
+                self.tiered.update(rank, containers.len() as u32);
+                containers.push(Arc::clone(&self.suffix_containers[id]));
+            }
+            self.suffix_containers = containers.into();
+            self.empty_containers = Vec::new();
+        }
+        self.shrink_to_fit();
+        before.saturating_sub(self.buckets_heap_size())
+    }
+
+    /// Checks the internal consistency of the set.
+    /// Returns the list of violated invariants, if any.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.is_small() {
+            if self.small.len() > Self::SMALL_THRESHOLD {
+                errors.push(format!(
+                    "the small layout stores {} words, more than {}",
+                    self.small.len(),
+                    Self::SMALL_THRESHOLD
+                ));
+            }
+            if self.small.windows(2).any(|pair| pair[0] >= pair[1]) {
+                errors.push("the words of the small layout are not sorted".to_string());
+            }
+            if self.small.iter().any(|(prefix, suffix)| {
+                (*prefix as u64) >> PREFIX_BITS != 0 || !Self::suffix_fits(suffix)
+            }) {
+                errors.push("the small layout contains a word that is too large".to_string());
+            }
+            return if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            };
+        }
+        if self.prefixes.bitlength() != PREFIX_BITS {
+            errors.push(format!(
+                "the prefix bitvector has 2^{} bits instead of 2^{PREFIX_BITS}",
+                self.prefixes.bitlength()
+            ));
+        }
+        let num_prefixes = self.prefixes.count();
+        if num_prefixes != self.tiered.len() {
+            errors.push(format!(
+                "{num_prefixes} prefixes are set but the tiered vector has {} ids",
+                self.tiered.len()
+            ));
+        }
+        let num_containers = self.suffix_containers.len();
+        let mut free = vec![false; num_containers];
+        for &id in self.empty_containers.iter() {
+            if id >= num_containers {
+                errors.push(format!("free container {id} does not exist"));
+            } else if free[id] {
+                errors.push(format!("container {id} is freed twice"));
+            } else {
+                free[id] = true;
+                if !self.suffix_containers[id].is_empty() {
+                    errors.push(format!("free container {id} is not empty"));
+                }
+            }
+        }
+        let mut used = vec![false; num_containers];
+        let mut num_words = 0;
+        for (rank, prefix) in self.prefixes.iter().take(self.tiered.len()).enumerate() {
+            let id = self.tiered.get(rank) as usize;
+            if id >= num_containers {
+                errors.push(format!(
+                    "prefix {prefix} points to container {id} which does not exist"
+                ));
+                continue;
+            }
+            if used[id] {
+                errors.push(format!(
+                    "prefix {prefix} shares container {id} with another prefix"
+                ));
+                continue;
+            }
+            used[id] = true;
+            if free[id] {
+                errors.push(format!("prefix {prefix} points to free container {id}"));
+            }
+            let container = &self.suffix_containers[id];
+            if container.is_empty() {
+                errors.push(format!("prefix {prefix} points to an empty container"));
+            }
+            if let Err(error) = container.validate() {
+                errors.push(format!("bucket of prefix {prefix}: {error}"));
+            } else if container.iter().any(|suffix| !Self::suffix_fits(&suffix)) {
+                errors.push(format!(
+                    "bucket of prefix {prefix} contains a suffix larger than {SUFFIX_BITS} bits"
+                ));
+            }
+            num_words += container.len();
+        }
+        let orphans = (0..num_containers)
+            .filter(|&id| !used[id] && !free[id])
+            .count();
+        if orphans > 0 {
+            errors.push(format!("{orphans} containers are neither used nor free"));
+        }
+        if num_words != self.num_words {
+            errors.push(format!(
+                "the buckets contain {num_words} words but the count is {}",
+                self.num_words
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns `true` if the suffix is stored on at most `SUFFIX_BITS` bits.
+    #[inline]
+    fn suffix_fits(suffix: &SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>) -> bool {
+        suffix
+            .get::<u128>()
+            .checked_shr(SUFFIX_BITS as u32)
+            .unwrap_or(0)
+            == 0
+    }
+
+    /// Checks a deserialized bucket: its prefix and its suffixes must fit in `PREFIX_BITS` and `SUFFIX_BITS` bits,
+    /// and it must be non-empty and structurally valid.
+    pub(crate) fn check_bucket(
+        prefix: u32,
+        container: &TrieVec<{ SUFFIX_BITS.div_ceil(8) }>,
+    ) -> Result<(), String> {
+        if (prefix as u64) >> PREFIX_BITS != 0 {
+            return Err(format!("prefix {prefix} is larger than {PREFIX_BITS} bits"));
+        }
+        if container.is_empty() {
+            return Err(format!("the bucket of prefix {prefix} is empty"));
+        }
+        container
+            .validate()
+            .map_err(|error| format!("bucket of prefix {prefix}: {error}"))?;
+        if !container.iter().all(|suffix| Self::suffix_fits(&suffix)) {
+            return Err(format!(
+                "the bucket of prefix {prefix} contains a suffix larger than {SUFFIX_BITS} bits"
+            ));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        if self.is_small() {
+            return self.small.len();
+        }
+        self.num_words
     }
 
     #[inline]
     pub fn is_empty(&self) -> bool {
+        if self.is_small() {
+            return self.small.is_empty();
+        }
         self.prefixes.count() == 0
     }
 
@@ -86,46 +573,95 @@ where
     #[inline]
     pub fn contains<T: PrimInt + Unsigned + AsPrimitive<usize>>(&self, word: T) -> bool {
         let (prefix, suffix) = Self::split_prefix_suffix(word);
+        self.contains_split(prefix, &suffix)
+    }
+
+    #[inline]
+    pub(crate) fn contains_split(
+        &self,
+        prefix: usize,
+        suffix: &SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>,
+    ) -> bool {
+        if self.is_small() {
+            return self.small.binary_search(&(prefix as u32, *suffix)).is_ok();
+        }
         if !self.prefixes.contains(prefix) {
             return false;
         }
         let rank = self.prefixes.rank(prefix);
         let id = self.tiered.get(rank) as usize;
-        self.suffix_containers[id].contains(&suffix)
+        self.suffix_containers[id].contains(suffix)
     }
 
     pub fn insert<T: PrimInt + Unsigned + AsPrimitive<usize>>(&mut self, word: T) -> bool {
         let (prefix, suffix) = Self::split_prefix_suffix(word);
+        self.insert_split(prefix, suffix)
+    }
+
+    pub(crate) fn insert_split(
+        &mut self,
+        prefix: usize,
+        suffix: SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>,
+    ) -> bool {
+        if self.is_small() {
+            return match self.small.binary_search(&(prefix as u32, suffix)) {
+                Ok(_) => false,
+                Err(i) => {
+                    self.small.insert(i, (prefix as u32, suffix));
+                    self.adapt_layout_grow();
+                    true
+                }
+            };
+        }
         let mut absent = self.prefixes.insert(prefix);
         let rank = self.prefixes.rank(prefix);
         if absent {
             match self.empty_containers.pop() {
                 Some(id) => {
-                    self.suffix_containers[id].insert(suffix);
+                    self.suffix_containers.make_mut(id).insert(suffix);
                     self.tiered.insert(rank, id as u32);
                 }
                 None => {
                     let id = self.suffix_containers.len();
                     self.suffix_containers
-                        .push(TrieVec::<{ SUFFIX_BITS.div_ceil(8) }>::new_with_one(suffix));
+                        .push(Arc::new(TrieVec::new_with_one(suffix)));
                     self.tiered.insert(rank, id as u32);
                 }
             };
         } else {
             let id = self.tiered.get(rank) as usize;
-            absent = self.suffix_containers[id].insert(suffix);
+            absent = self.suffix_containers.make_mut(id).insert(suffix);
             self.adapt_container_grow(id);
         }
+        self.num_words += absent as usize;
         absent
     }
 
     pub fn remove<T: PrimInt + Unsigned + AsPrimitive<usize>>(&mut self, word: T) -> bool {
         let (prefix, suffix) = Self::split_prefix_suffix(word);
+        self.remove_split(prefix, &suffix)
+    }
+
+    pub(crate) fn remove_split(
+        &mut self,
+        prefix: usize,
+        suffix: &SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>,
+    ) -> bool {
+        if self.is_small() {
+            return match self.small.binary_search(&(prefix as u32, *suffix)) {
+                Ok(i) => {
+                    self.small.remove(i);
+                    true
+                }
+                Err(_) => false,
+            };
+        }
         let mut present = self.prefixes.contains(prefix);
         if present {
             let rank = self.prefixes.rank(prefix);
             let id = self.tiered.get(rank) as usize;
-            present = self.suffix_containers[id].remove(&suffix);
+            present = self.suffix_containers.make_mut(id).remove(suffix);
+            self.num_words -= present as usize;
             self.adapt_container_shrink(id);
             if self.suffix_containers[id].is_empty() {
                 self.empty_containers.push(id);
@@ -136,14 +672,16 @@ where
         present
     }
 
-    pub fn contains_all<T: PrimInt + Unsigned + AsPrimitive<usize>>(
-        &mut self,
-        words: &[T],
-    ) -> bool {
+    pub fn contains_all<T: PrimInt + Unsigned + AsPrimitive<usize>>(&self, words: &[T]) -> bool {
         let prefixes_suffixes: Vec<_> = words
             .iter()
             .map(|&word| Self::split_prefix_suffix(word))
             .collect();
+        if self.is_small() {
+            return prefixes_suffixes
+                .iter()
+                .all(|(prefix, suffix)| self.contains_split(*prefix, suffix));
+        }
         for group in prefixes_suffixes.chunk_by(|(p1, _), (p2, _)| p1 == p2) {
             let prefix = group[0].0;
             if !self.prefixes.contains(prefix) {
@@ -161,7 +699,7 @@ where
     }
 
     pub fn contains_batch<T: PrimInt + Unsigned + AsPrimitive<usize>>(
-        &mut self,
+        &self,
         words: &[T],
     ) -> Vec<bool> {
         let mut res = Vec::with_capacity(words.len());
@@ -169,6 +707,14 @@ where
             .iter()
             .map(|&word| Self::split_prefix_suffix(word))
             .collect();
+        if self.is_small() {
+            res.extend(
+                prefixes_suffixes
+                    .iter()
+                    .map(|(prefix, suffix)| self.contains_split(*prefix, suffix)),
+            );
+            return res;
+        }
         for group in prefixes_suffixes.chunk_by(|(p1, _), (p2, _)| p1 == p2) {
             let prefix = group[0].0;
             if !self.prefixes.contains(prefix) {
@@ -185,10 +731,58 @@ where
     }
 
     pub fn insert_batch<T: PrimInt + Unsigned + AsPrimitive<usize>>(&mut self, words: &[T]) {
+        self.insert_batch_with(words, |_| ());
+    }
+
+    /// Inserts a batch of words and calls `inserted` on each word that was absent from the set.
+    pub fn insert_batch_with<T: PrimInt + Unsigned + AsPrimitive<usize>, F: FnMut(T)>(
+        &mut self,
+        words: &[T],
+        mut inserted: F,
+    ) {
         let prefixes_suffixes: Vec<_> = words
             .iter()
             .map(|&word| Self::split_prefix_suffix(word))
             .collect();
+        self.insert_split_batch_with(&prefixes_suffixes, |i| inserted(words[i]));
+    }
+
+    #[inline]
+    pub(crate) fn insert_split_batch(
+        &mut self,
+        prefixes_suffixes: &[(usize, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)],
+    ) {
+        self.insert_split_batch_with(prefixes_suffixes, |_| ());
+    }
+
+    /// Inserts a batch of (prefix, suffix) pairs and calls `inserted` on the index of each pair that was absent.
+    pub(crate) fn insert_split_batch_with<F: FnMut(usize)>(
+        &mut self,
+        prefixes_suffixes: &[(usize, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)],
+        mut inserted: F,
+    ) {
+        if self.is_small() {
+            let mut new_words: Vec<_> = prefixes_suffixes
+                .iter()
+                .enumerate()
+                .map(|(i, &(prefix, suffix))| ((prefix as u32, suffix), i))
+                .collect();
+            new_words.sort_unstable();
+            new_words.dedup_by_key(|(word, _)| *word);
+            new_words.retain(|(word, _)| self.small.binary_search(word).is_err());
+            for &(_, i) in new_words.iter() {
+                inserted(i);
+            }
+            self.small = self
+                .small
+                .iter()
+                .copied()
+                .merge(new_words.into_iter().map(|(word, _)| word))
+                .collect();
+            self.adapt_layout_grow();
+            return;
+        }
+        let mut start = 0;
         for group in prefixes_suffixes.chunk_by(|(p1, _), (p2, _)| p1 == p2) {
             let prefix = group[0].0;
             let absent = self.prefixes.insert(prefix);
@@ -201,8 +795,7 @@ where
                     }
                     None => {
                         let id = self.suffix_containers.len();
-                        self.suffix_containers
-                            .push(TrieVec::<{ SUFFIX_BITS.div_ceil(8) }>::new());
+                        self.suffix_containers.push(Arc::default());
                         self.tiered.insert(rank, id as u32);
                         id
                     }
@@ -210,22 +803,81 @@ where
             } else {
                 self.tiered.get(rank) as usize
             };
-            self.suffix_containers[id].insert_iter(group.iter().map(|&(_, suffix)| suffix));
+            let container = self.suffix_containers.make_mut(id);
+            for (i, &(_, suffix)) in group.iter().enumerate() {
+                if container.insert(suffix) {
+                    self.num_words += 1;
+                    inserted(start + i);
+                }
+            }
             self.adapt_container_grow(id);
+            start += group.len();
         }
     }
 
     pub fn remove_batch<T: PrimInt + Unsigned + AsPrimitive<usize>>(&mut self, words: &[T]) {
+        self.remove_batch_with(words, |_| ());
+    }
+
+    /// Removes a batch of words and calls `removed` on each word that was present in the set.
+    pub fn remove_batch_with<T: PrimInt + Unsigned + AsPrimitive<usize>, F: FnMut(T)>(
+        &mut self,
+        words: &[T],
+        mut removed: F,
+    ) {
         let prefixes_suffixes: Vec<_> = words
             .iter()
             .map(|&word| Self::split_prefix_suffix(word))
             .collect();
+        self.remove_split_batch_with(&prefixes_suffixes, |i| removed(words[i]));
+    }
+
+    #[inline]
+    pub(crate) fn remove_split_batch(
+        &mut self,
+        prefixes_suffixes: &[(usize, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)],
+    ) {
+        self.remove_split_batch_with(prefixes_suffixes, |_| ());
+    }
+
+    /// Removes a batch of (prefix, suffix) pairs and calls `removed` on the index of each pair that was present.
+    pub(crate) fn remove_split_batch_with<F: FnMut(usize)>(
+        &mut self,
+        prefixes_suffixes: &[(usize, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)],
+        mut removed: F,
+    ) {
+        if self.is_small() {
+            let mut old_words: Vec<_> = prefixes_suffixes
+                .iter()
+                .enumerate()
+                .map(|(i, &(prefix, suffix))| ((prefix as u32, suffix), i))
+                .collect();
+            old_words.sort_unstable();
+            old_words.dedup_by_key(|(word, _)| *word);
+            old_words.retain(|(word, _)| self.small.binary_search(word).is_ok());
+            for &(_, i) in old_words.iter() {
+                removed(i);
+            }
+            self.small.retain(|word| {
+                old_words
+                    .binary_search_by(|(old_word, _)| old_word.cmp(word))
+                    .is_err()
+            });
+            return;
+        }
+        let mut start = 0;
         for group in prefixes_suffixes.chunk_by(|(p1, _), (p2, _)| p1 == p2) {
             let prefix = group[0].0;
             if self.prefixes.contains(prefix) {
                 let rank = self.prefixes.rank(prefix);
                 let id = self.tiered.get(rank) as usize;
-                self.suffix_containers[id].remove_iter(group.iter().map(|&(_, suffix)| suffix));
+                let container = self.suffix_containers.make_mut(id);
+                for (i, (_, suffix)) in group.iter().enumerate() {
+                    if container.remove(suffix) {
+                        self.num_words -= 1;
+                        removed(start + i);
+                    }
+                }
                 if self.suffix_containers[id].is_empty() {
                     self.empty_containers.push(id);
                     self.tiered.remove(rank);
@@ -233,33 +885,222 @@ where
                 }
                 self.adapt_container_shrink(id);
             }
+            start += group.len();
         }
     }
 
-    #[inline]
-    fn adapt_container_grow(&mut self, id: usize) {
-        if self.suffix_containers[id].len() > Self::THRESHOLD {
-            self.suffix_containers[id].as_trie();
+    /// Returns the smallest prefix of a non-empty bucket that is at least `from`.
+    pub(crate) fn next_prefix(&self, from: usize) -> Option<usize> {
+        if self.is_small() {
+            let i = self
+                .small
+                .partition_point(|&(prefix, _)| (prefix as usize) < from);
+            return self.small.get(i).map(|&(prefix, _)| prefix as usize);
+        }
+        self.prefixes.iter_range(from..(1 << PREFIX_BITS)).next()
+    }
+
+    /// Returns the size of the bucket of `prefix`, with its container id and its container in the large layout.
+    /// The container is `None` if the bucket is empty or if the set uses the small layout.
+    pub(crate) fn locate(
+        &self,
+        prefix: usize,
+    ) -> (
+        usize,
+        Option<(usize, &TrieVec<{ SUFFIX_BITS.div_ceil(8) }>)>,
+    ) {
+        if self.is_small() {
+            let start = self.small.partition_point(|&(p, _)| (p as usize) < prefix);
+            let end = self.small.partition_point(|&(p, _)| (p as usize) <= prefix);
+            return (end - start, None);
+        }
+        if !self.prefixes.contains(prefix) {
+            return (0, None);
+        }
+        let id = self.tiered.get(self.prefixes.rank(prefix)) as usize;
+        let container: &TrieVec<{ SUFFIX_BITS.div_ceil(8) }> = &self.suffix_containers[id];
+        (container.len(), Some((id, container)))
+    }
+
+    /// Removes the words of the bucket of `prefix` for which `pred` returns `true`
+    /// and returns them in increasing order.
+    pub(crate) fn extract_bucket_if<
+        T: PrimInt + Unsigned + AsPrimitive<usize>,
+        F: FnMut(T) -> bool,
+    >(
+        &mut self,
+        prefix: usize,
+        mut pred: F,
+    ) -> Vec<T>
+    where
+        usize: AsPrimitive<T>,
+    {
+        if self.is_small() {
+            let start = self.small.partition_point(|&(p, _)| (p as usize) < prefix);
+            let end = self.small.partition_point(|&(p, _)| (p as usize) <= prefix);
+            let (extracted, kept): (Vec<_>, Vec<_>) = self.small[start..end]
+                .iter()
+                .copied()
+                .partition(|&(p, suffix)| pred(Self::merge_prefix_suffix(p as usize, suffix)));
+            self.small.splice(start..end, kept);
+            return extracted
+                .into_iter()
+                .map(|(p, suffix)| Self::merge_prefix_suffix(p as usize, suffix))
+                .collect();
+        }
+        if !self.prefixes.contains(prefix) {
+            return Vec::new();
+        }
+        let rank = self.prefixes.rank(prefix);
+        let id = self.tiered.get(rank) as usize;
+        let extracted: Vec<_> = self.suffix_containers[id]
+            .iter_ordered()
+            .filter(|&suffix| pred(Self::merge_prefix_suffix(prefix, suffix)))
+            .collect();
+        // a bucket shared with a clone is only copied if it changes
+        if extracted.is_empty() {
+            return Vec::new();
+        }
+        let container = self.suffix_containers.make_mut(id);
+        container.sort();
+        container.remove_sorted_iter(extracted.iter().copied());
+        self.num_words -= extracted.len();
+        if self.suffix_containers[id].is_empty() {
+            self.empty_containers.push(id);
+            self.tiered.remove(rank);
+            self.prefixes.remove(prefix);
+        }
+        self.adapt_container_shrink(id);
+        extracted
+            .into_iter()
+            .map(|suffix| Self::merge_prefix_suffix(prefix, suffix))
+            .collect()
+    }
+
+    /// Inserts the absent pairs and removes the present ones.
+    pub(crate) fn toggle_split_batch(
+        &mut self,
+        prefixes_suffixes: &[(usize, SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>)],
+    ) {
+        for &(prefix, suffix) in prefixes_suffixes.iter() {
+            if !self.remove_split(prefix, &suffix) {
+                self.insert_split(prefix, suffix);
+            }
         }
     }
 
     #[inline]
-    fn adapt_container_shrink(&mut self, id: usize) {
-        if self.suffix_containers[id].len() <= Self::THRESHOLD {
-            self.suffix_containers[id].as_vec();
+    pub(crate) fn adapt_container_grow(&mut self, id: usize) {
+        self.suffix_containers.make_mut(id).adapt_grow();
+    }
+
+    #[inline]
+    pub(crate) fn adapt_container_shrink(&mut self, id: usize) {
+        self.suffix_containers.make_mut(id).adapt_shrink();
+    }
+
+    /// Appends a container for a prefix greater than all the stored ones.
+    #[inline]
+    pub(crate) fn push_container(
+        &mut self,
+        prefix: usize,
+        container: TrieVec<{ SUFFIX_BITS.div_ceil(8) }>,
+    ) {
+        let rank = self.tiered.len();
+        let id = self.suffix_containers.len();
+        self.num_words += container.len();
+        self.suffix_containers.push(Arc::new(container));
+        self.tiered.insert(rank, id as u32);
+        self.prefixes.insert(prefix);
+        self.adapt_container_grow(id);
+    }
+
+    /// Appends a container shared with another set, for a prefix greater than all the stored ones.
+    #[inline]
+    pub(crate) fn push_shared_container(
+        &mut self,
+        prefix: usize,
+        container: Arc<TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>,
+    ) {
+        let rank = self.tiered.len();
+        let id = self.suffix_containers.len();
+        self.num_words += container.len();
+        self.suffix_containers.push(container);
+        self.tiered.insert(rank, id as u32);
+        self.prefixes.insert(prefix);
+    }
+
+    /// Splits the set by prefix range into `num_parts` sets.
+    /// The prefixes of part `i` are the ones such that `prefix * num_parts >> PREFIX_BITS == i`.
+    /// The parts start in the small layout and only switch to the prefix/bucket layout, sharing the buckets of the set,
+    /// once they outgrow it, so that splitting into many parts does not allocate a prefix bitvector for each of them.
+    pub(crate) fn split_by_prefix(&self, num_parts: usize) -> Vec<Self> {
+        let part = |prefix: usize| (prefix * num_parts) >> PREFIX_BITS;
+        let mut parts = (0..num_parts).map(|_| Self::new()).collect_vec();
+        if self.is_small() {
+            for &(prefix, suffix) in self.small.iter() {
+                parts[part(prefix as usize)].small.push((prefix, suffix));
+            }
+            return parts;
+        }
+        for (rank, prefix) in self.prefixes.iter().enumerate() {
+            let id = self.tiered.get(rank) as usize;
+            let container = &self.suffix_containers[id];
+            let dest = &mut parts[part(prefix)];
+            if dest.is_small() && dest.small.len() + container.len() > Self::SMALL_THRESHOLD {
+                dest.upgrade();
+            }
+            if dest.is_small() {
+                dest.small.extend(
+                    container
+                        .iter_ordered()
+                        .map(|suffix| (prefix as u32, suffix)),
+                );
+            } else {
+                dest.push_shared_container(prefix, Arc::clone(container));
+            }
         }
+        parts
+    }
+
+    /// Joins sets covering increasing prefix ranges into a single set, sharing their buckets.
+    pub(crate) fn concat<'a, I: IntoIterator<Item = &'a Self>>(parts: I) -> Self {
+        let mut res = Self::new_large();
+        for part in parts {
+            if part.is_small() {
+                res.insert_split_batch(&part.small_words());
+                continue;
+            }
+            for (rank, prefix) in part.prefixes.iter().enumerate() {
+                let id = part.tiered.get(rank) as usize;
+                res.push_shared_container(prefix, Arc::clone(&part.suffix_containers[id]));
+            }
+        }
+        res.adapt_layout_shrink();
+        res
     }
 
     #[inline]
     pub fn prefix_load(&self) -> f64 {
-        self.tiered.len() as f64 / (1 << Self::PREFIX_BITS) as f64
+        if self.is_small() {
+            let used = self.small.chunk_by(|(p1, _), (p2, _)| p1 == p2).count();
+            return used as f64 / (1u64 << Self::PREFIX_BITS) as f64;
+        }
+        self.tiered.len() as f64 / (1u64 << Self::PREFIX_BITS) as f64
     }
 
     pub fn buckets_sizes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
-        self.prefixes.iter().enumerate().map(|(rank, prefix)| {
+        if self.is_small() {
+            return Either::Left(
+                self.small
+                    .chunk_by(|(p1, _), (p2, _)| p1 == p2)
+                    .map(|group| (group[0].0 as usize, group.len())),
+            );
+        }
+        Either::Right(self.prefixes.iter().enumerate().map(|(rank, prefix)| {
             let id = self.tiered.get(rank) as usize;
             (prefix, self.suffix_containers[id].len())
-        })
+        }))
     }
 
     pub fn buckets_size_count(&self) -> BTreeMap<usize, usize> {
@@ -280,10 +1121,18 @@ where
     }
 
     pub fn buckets_nodes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
-        self.prefixes.iter().enumerate().map(|(rank, prefix)| {
+        if self.is_small() {
+            // every small bucket would be stored as a vector
+            return Either::Left(
+                self.small
+                    .chunk_by(|(p1, _), (p2, _)| p1 == p2)
+                    .map(|group| (group[0].0 as usize, group.len())),
+            );
+        }
+        Either::Right(self.prefixes.iter().enumerate().map(|(rank, prefix)| {
             let id = self.tiered.get(rank) as usize;
             (prefix, self.suffix_containers[id].count_nodes())
-        })
+        }))
     }
 
     pub fn buckets_node_count(&self) -> BTreeMap<usize, usize> {
@@ -294,18 +1143,63 @@ where
         node_count
     }
 
+    /// Returns an iterator over the words of the set, in increasing order.
     #[inline]
     pub fn iter<T: PrimInt + Unsigned + AsPrimitive<usize>>(&self) -> impl Iterator<Item = T> + '_
     where
         usize: AsPrimitive<T>,
     {
-        WordSetIterator {
+        if self.is_small() {
+            return Either::Left(
+                self.small
+                    .iter()
+                    .map(|&(prefix, suffix)| Self::merge_prefix_suffix(prefix as usize, suffix)),
+            );
+        }
+        Either::Right(WordSetIterator {
             wordset: self,
             prefix_iter: self.prefixes.iter(),
             prefix: None,
             suffix_iter: None,
             suffix: None,
+        })
+    }
+
+    /// Returns an iterator over the words whose prefix is in `range`, in increasing order.
+    pub fn iter_prefix_range<T: PrimInt + Unsigned + AsPrimitive<usize>>(
+        &self,
+        range: Range<usize>,
+    ) -> impl Iterator<Item = T> + '_
+    where
+        usize: AsPrimitive<T>,
+    {
+        if self.is_small() {
+            let start = self
+                .small
+                .partition_point(|&(prefix, _)| (prefix as usize) < range.start);
+            let end = self
+                .small
+                .partition_point(|&(prefix, _)| (prefix as usize) < range.end);
+            return Either::Left(
+                self.small[start..end.max(start)]
+                    .iter()
+                    .map(|&(prefix, suffix)| Self::merge_prefix_suffix(prefix as usize, suffix)),
+            );
         }
+        Either::Right(WordSetIterator {
+            wordset: self,
+            prefix_iter: self.prefixes.iter_range(range),
+            prefix: None,
+            suffix_iter: None,
+            suffix: None,
+        })
+    }
+
+    /// Returns the range of prefixes of part `part` when the prefix space is split into `num_parts` parts,
+    /// i.e. the prefixes such that `prefix * num_parts >> PREFIX_BITS == part`.
+    #[inline]
+    pub(crate) fn part_prefixes(part: usize, num_parts: usize) -> Range<usize> {
+        (part << PREFIX_BITS).div_ceil(num_parts)..((part + 1) << PREFIX_BITS).div_ceil(num_parts)
     }
 }
 
@@ -351,7 +1245,7 @@ where
             self.prefix = self.prefix_iter.next();
             let rank = self.wordset.prefixes.rank(self.prefix?);
             let id = self.wordset.tiered.get(rank) as usize;
-            self.suffix_iter = Some(self.wordset.suffix_containers[id].iter());
+            self.suffix_iter = Some(self.wordset.suffix_containers[id].iter_ordered());
             self.suffix = self.suffix_iter.as_mut().unwrap().next();
         }
         let word =
@@ -365,31 +1259,82 @@ impl<const PREFIX_BITS: usize, const SUFFIX_BITS: usize> Clone for WordSet<PREFI
 where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
+    /// Shares the buckets and the prefix structures with the clone, without copying them.
     fn clone(&self) -> Self {
-        let tiered = TieredVec::new().within_unique_ptr();
-        for i in 0..self.tiered.len() {
-            tiered.insert(i, self.tiered.get(i));
-        }
         Self {
             prefixes: self.prefixes.clone(),
-            tiered,
+            tiered: self.tiered.clone(),
             suffix_containers: self.suffix_containers.clone(),
             empty_containers: self.empty_containers.clone(),
+            num_words: self.num_words,
+            small: self.small.clone(),
         }
     }
 }
 
+/// The buckets of a set, serialized as a map from prefixes to containers.
+struct BucketsRef<'a, const PREFIX_BITS: usize, const SUFFIX_BITS: usize>(
+    &'a WordSet<PREFIX_BITS, SUFFIX_BITS>,
+)
+where
+    [(); SUFFIX_BITS.div_ceil(8)]:;
+
+/// The buckets of a deserialized set, also used to read the layout of older versions which only stored the map of buckets.
+pub(crate) struct Buckets<const PREFIX_BITS: usize, const SUFFIX_BITS: usize>(
+    pub(crate) WordSet<PREFIX_BITS, SUFFIX_BITS>,
+)
+where
+    [(); SUFFIX_BITS.div_ceil(8)]:;
+
 impl<const PREFIX_BITS: usize, const SUFFIX_BITS: usize> Serialize
     for WordSet<PREFIX_BITS, SUFFIX_BITS>
 where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut map = serializer.serialize_map(Some(self.tiered.len()))?;
-        for (rank, prefix) in self.prefixes.iter().enumerate() {
+        // the number of words is written first so that it can be checked against the buckets on load
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.count())?;
+        tuple.serialize_element(&BucketsRef(self))?;
+        tuple.end()
+    }
+}
+
+impl<'a, const PREFIX_BITS: usize, const SUFFIX_BITS: usize> Serialize
+    for BucketsRef<'a, PREFIX_BITS, SUFFIX_BITS>
+where
+    [(); SUFFIX_BITS.div_ceil(8)]:,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let wordset = self.0;
+        if wordset.is_small() {
+            let groups: Vec<_> = wordset
+                .small
+                .chunk_by(|(p1, _), (p2, _)| p1 == p2)
+                .collect();
+            let mut map = serializer.serialize_map(Some(groups.len()))?;
+            for group in groups {
+                let container = TrieVec::<{ SUFFIX_BITS.div_ceil(8) }>::from_sorted_iter(
+                    group.iter().map(|&(_, suffix)| suffix),
+                );
+                map.serialize_entry(&group[0].0, &container)?;
+            }
+            return map.end();
+        }
+        // buckets are written in their canonical representation so that the bytes only depend on the content
+        let mut map = serializer.serialize_map(Some(wordset.tiered.len()))?;
+        for (rank, prefix) in wordset.prefixes.iter().enumerate() {
             let prefix = prefix as u32;
-            let id = self.tiered.get(rank) as usize;
-            map.serialize_entry(&prefix, &self.suffix_containers[id])?;
+            let id = wordset.tiered.get(rank) as usize;
+            let container = &*wordset.suffix_containers[id];
+            if container.is_canonical() {
+                map.serialize_entry(&prefix, container)?;
+            } else {
+                map.serialize_entry(
+                    &prefix,
+                    &TrieVec::from_sorted_iter(container.iter_ordered()),
+                )?;
+            }
         }
         map.end()
     }
@@ -408,31 +1353,91 @@ where
         formatter.write_str("a wordset")
     }
 
-    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
-        let mut wordset = WordSet {
-            prefixes: Bitvector::new_with_bitlength(PREFIX_BITS),
-            tiered: TieredVec::new().within_unique_ptr(),
-            suffix_containers: Vec::with_capacity(access.size_hint().unwrap_or(0)),
-            empty_containers: Vec::new(),
-        };
-        while let Some((prefix, suffix_container)) = access.next_entry::<u32, _>()? {
-            let prefix = prefix as usize;
-            let rank = wordset.suffix_containers.len();
-            wordset.prefixes.insert(prefix);
-            wordset.tiered.insert(rank, rank as u32);
-            wordset.suffix_containers.push(suffix_container);
+    fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+        let count: usize = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let Buckets(wordset) = access
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        if wordset.count() != count {
+            return Err(de::Error::custom(format!(
+                "the buckets contain {} words but {count} were expected",
+                wordset.count()
+            )));
         }
         Ok(wordset)
     }
 }
 
+struct BucketsVisitor<const PREFIX_BITS: usize, const SUFFIX_BITS: usize> {}
+
+impl<'de, const PREFIX_BITS: usize, const SUFFIX_BITS: usize> Visitor<'de>
+    for BucketsVisitor<PREFIX_BITS, SUFFIX_BITS>
+where
+    [(); SUFFIX_BITS.div_ceil(8)]:,
+{
+    type Value = Buckets<PREFIX_BITS, SUFFIX_BITS>;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a map of buckets")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+        let mut wordset = WordSet::<PREFIX_BITS, SUFFIX_BITS>::new();
+        let mut last_prefix = None;
+        while let Some((prefix, mut suffix_container)) =
+            access.next_entry::<u32, TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>()?
+        {
+            // the buckets are checked before being used, a corrupted one could make the set panic later on
+            if last_prefix.is_some_and(|last| last >= prefix) {
+                return Err(de::Error::custom("the prefixes are not sorted"));
+            }
+            last_prefix = Some(prefix);
+            WordSet::<PREFIX_BITS, SUFFIX_BITS>::check_bucket(prefix, &suffix_container)
+                .map_err(de::Error::custom)?;
+            if wordset.is_small()
+                && wordset.small.len() + suffix_container.len()
+                    > WordSet::<PREFIX_BITS, SUFFIX_BITS>::SMALL_THRESHOLD
+            {
+                wordset.upgrade();
+            }
+            if wordset.is_small() {
+                wordset.small.extend(
+                    suffix_container
+                        .iter_sorted()
+                        .map(|suffix| (prefix, suffix)),
+                );
+            } else {
+                let prefix = prefix as usize;
+                let rank = wordset.suffix_containers.len();
+                wordset.prefixes.insert(prefix);
+                wordset.tiered.insert(rank, rank as u32);
+                wordset.num_words += suffix_container.len();
+                wordset.suffix_containers.push(Arc::new(suffix_container));
+            }
+        }
+        Ok(Buckets(wordset))
+    }
+}
+
 impl<'de, const PREFIX_BITS: usize, const SUFFIX_BITS: usize> Deserialize<'de>
     for WordSet<PREFIX_BITS, SUFFIX_BITS>
 where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        deserializer.deserialize_map(WordSetVisitor {})
+        deserializer.deserialize_tuple(2, WordSetVisitor {})
+    }
+}
+
+impl<'de, const PREFIX_BITS: usize, const SUFFIX_BITS: usize> Deserialize<'de>
+    for Buckets<PREFIX_BITS, SUFFIX_BITS>
+where
+    [(); SUFFIX_BITS.div_ceil(8)]:,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(BucketsVisitor {})
     }
 }
 
@@ -442,7 +1447,7 @@ mod tests {
     use itertools::Itertools;
     use rand::rngs::StdRng;
     use rand::seq::SliceRandom;
-    use rand::{thread_rng, SeedableRng};
+    use rand::{thread_rng, Rng, SeedableRng};
 
     const N: usize = 1_000_000;
     const PREFIX_BITS: usize = 24;
@@ -531,4 +1536,219 @@ mod tests {
         assert_eq!(iter.next(), Some(10 * (1 << SUFFIX_BITS) + 10));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn test_small_layout() {
+        type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+        let mut set = Set::new();
+        assert!(set.is_small());
+        let v0 = (0..(2 * Set::SMALL_THRESHOLD)).step_by(2).collect_vec();
+        set.insert_batch(&v0[..10]);
+        assert!(set.is_small());
+        assert_eq!(set.count(), 10);
+        for &i in v0.iter() {
+            set.insert(i);
+        }
+        assert!(set.is_small());
+        assert!(set.insert(1usize));
+        assert!(!set.is_small());
+        assert_eq!(set.count(), Set::SMALL_THRESHOLD + 1);
+        for &i in v0.iter() {
+            assert!(set.contains(i));
+        }
+        assert!(set.contains(1usize));
+        assert!(!set.contains(3usize));
+    }
+
+    #[test]
+    fn test_small_serde() {
+        type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+        let mut small = Set::new();
+        let mut large = Set::new();
+        small.insert_batch(&(0..100usize).collect_vec());
+        large.insert_batch(&(0..(2 * Set::SMALL_THRESHOLD)).collect_vec());
+        for set in [small, large] {
+            let bytes = bincode::serialize(&set).unwrap();
+            let res: Set = bincode::deserialize(&bytes).unwrap();
+            assert_eq!(res.is_small(), set.is_small());
+            assert_eq!(res.count(), set.count());
+            assert_eq!(
+                res.iter::<usize>().sorted().collect_vec(),
+                set.iter::<usize>().sorted().collect_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+        let mut set = Set::new();
+        set.insert_batch(&(0..100usize).collect_vec());
+        assert_eq!(set.validate(), Ok(()));
+        set.small.swap(0, 1);
+        assert!(set.validate().is_err());
+        let words = (0..N).map(|i| 3 * i).collect_vec();
+        let mut set = Set::new();
+        set.insert_batch(&words);
+        set.remove_batch(&words[..(N / 2)]);
+        assert_eq!(set.validate(), Ok(()));
+        set.num_words += 1;
+        assert_eq!(set.validate().unwrap_err().len(), 1);
+        set.num_words -= 1;
+        let id = set.tiered.get(0) as usize;
+        set.empty_containers.push(id);
+        assert!(set.validate().is_err());
+    }
+
+    #[test]
+    fn test_count() {
+        type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+        let mut rng = StdRng::seed_from_u64(42);
+        let words = (0..N).map(|_| rng.gen_range(0..(4 * N))).collect_vec();
+        let mut set = Set::new();
+        for &word in words[..(N / 2)].iter() {
+            set.insert(word);
+        }
+        assert_eq!(set.count(), set.iter::<usize>().count());
+        set.insert_batch(&words[(N / 2)..]);
+        assert_eq!(set.count(), words.iter().unique().count());
+        set.remove_batch(&words[..(N / 4)]);
+        assert_eq!(set.count(), set.iter::<usize>().count());
+        for &word in words[(N / 4)..(N / 2)].iter() {
+            set.remove(word);
+        }
+        assert_eq!(set.count(), set.iter::<usize>().count());
+        let bytes = bincode::serialize(&set).unwrap();
+        let res: Set = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(res.count(), set.count());
+        let bytes = bincode::serialize(&(set.count() + 1, BucketsRef(&set))).unwrap();
+        assert!(bincode::deserialize::<Set>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_from_sorted_iter() {
+        type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+        let small_words = (0..100usize).flat_map(|i| [3 * i, 3 * i]).collect_vec();
+        let set = Set::from_sorted_iter(small_words.iter().copied());
+        assert!(set.is_small());
+        assert_eq!(
+            set.iter::<usize>().collect_vec(),
+            small_words.iter().copied().dedup().collect_vec()
+        );
+        let large_words = (0..N).map(|i| 3 * i).collect_vec();
+        let set = Set::from_sorted_iter(large_words.iter().copied());
+        assert!(!set.is_small());
+        assert_eq!(set.count(), N);
+        assert_eq!(set.iter::<usize>().collect_vec(), large_words);
+    }
+
+    #[test]
+    fn test_compact() {
+        type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+        let mut set = Set::new();
+        let words = (0..N).map(|i| 7 * i).collect_vec();
+        set.insert_batch(&words);
+        set.remove_batch(&words[..(N - 100)]);
+        assert!(!set.empty_containers.is_empty());
+        let reclaimed = set.compact();
+        assert!(reclaimed > 0);
+        assert!(set.empty_containers.is_empty());
+        assert_eq!(set.suffix_containers.len(), set.tiered.len());
+        assert_eq!(set.iter::<usize>().collect_vec(), words[(N - 100)..]);
+        for (rank, _) in set.prefixes.iter().enumerate() {
+            assert_eq!(set.tiered.get(rank) as usize, rank);
+        }
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        type Set = WordSet<8, 16>;
+        let mut set = Set::new();
+        assert_eq!(set.memory_usage().total(), 0);
+        let words = (0usize..(1 << 21)).step_by(2).collect_vec();
+        set.insert_batch(&words);
+        let usage = set.memory_usage();
+        assert!(usage.prefixes > 0);
+        assert_eq!(usage.tiered, 32 * size_of::<u32>());
+        assert!(usage.trie_nodes > 0 && usage.trie_children > 0);
+        assert_eq!(usage.free_containers, 0);
+        assert_eq!(
+            usage.total() - usage.prefixes - usage.tiered,
+            set.buckets_heap_size()
+        );
+        set.remove_batch(&words[..(1 << 15)]);
+        let usage = set.memory_usage();
+        assert_eq!(usage.tiered, 31 * size_of::<u32>());
+        assert!(usage.free_containers > 0);
+        assert_eq!(
+            usage.total() - usage.prefixes - usage.tiered,
+            set.buckets_heap_size()
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        type Set = WordSet<8, 16>;
+        let mut set = Set::new();
+        let few: [usize; 3] = [100 << 16, (100 << 16) + 1, (100 << 16) + 2];
+        set.insert_batch(&few);
+        let stats = set.stats();
+        assert!(stats.small_layout && stats.containers.is_empty());
+        assert_eq!((stats.count, stats.buckets), (3, 1));
+        assert_eq!(stats.largest_buckets, vec![(100, 3)]);
+
+        let words = (0usize..(1 << 21)).step_by(2).collect_vec();
+        set.insert_batch(&words);
+        let stats = set.stats();
+        assert!(!stats.small_layout);
+        assert_eq!(stats.count, set.count());
+        assert_eq!(stats.buckets, 33);
+        assert_eq!(stats.prefix_load, set.prefix_load());
+        assert_eq!(stats.bucket_sizes, BTreeMap::from([(3, 1), (1 << 15, 32)]));
+        assert_eq!(stats.bucket_nodes, set.buckets_node_count());
+        assert_eq!(stats.containers[&ContainerKind::Vec].buckets, 1);
+        assert_eq!(stats.containers[&ContainerKind::Vec].nodes, 3);
+        assert_eq!(stats.containers[&ContainerKind::Trie].buckets, 32);
+        assert_eq!(
+            stats.containers.values().map(|c| c.nodes).sum::<usize>(),
+            set.buckets_nodes().map(|(_, nodes)| nodes).sum::<usize>()
+        );
+        assert_eq!(
+            stats.largest_buckets,
+            (0..Set::STATS_LARGEST_BUCKETS)
+                .map(|prefix| (prefix, 1 << 15))
+                .collect_vec()
+        );
+        assert_eq!(stats.memory_usage, set.memory_usage());
+    }
+
+    #[test]
+    fn test_shared_buckets() {
+        type Set = WordSet<8, 16>;
+        let mut set = Set::new();
+        let words = (0usize..(1 << 20)).step_by(3).collect_vec();
+        set.insert_batch(&words);
+        let shared = set.clone();
+        let num_containers = set.suffix_containers.len();
+        // only the modified buckets are copied
+        assert!(set.insert(1usize));
+        assert!(set.remove(3usize << 16));
+        let copied = (0..num_containers)
+            .filter(|&id| !Arc::ptr_eq(&set.suffix_containers[id], &shared.suffix_containers[id]))
+            .count();
+        assert_eq!(copied, 2);
+        assert!(set.contains(1usize) && !shared.contains(1usize));
+        assert!(!set.contains(3usize << 16) && shared.contains(3usize << 16));
+        assert_eq!(set.count(), shared.count());
+        // new and emptied buckets only affect the modified set
+        set.insert((1usize << 20) + 1);
+        set.remove_batch(&words[..(1 << 14)]);
+        assert_eq!(shared.count(), words.len());
+        assert!(shared.contains_batch(&words).iter().all(|&b| b));
+        assert!(set.validate().is_ok());
+        assert!(shared.validate().is_ok());
+        let mut shared = shared;
+        shared.compact();
+        assert!(shared.iter::<usize>().eq(words.iter().copied()));
+    }
 }
@@ -0,0 +1,260 @@
+use super::WordSet;
+use crate::sliced_int::SlicedInt;
+use crate::trievec::TrieVec;
+use iter_set_ops::{intersect_iters, merge_iters, merge_iters_detailed_by};
+use itertools::EitherOrBoth::{Both, Left, Right};
+use itertools::Itertools;
+use rayon::prelude::*;
+use std::sync::Arc;
+
+impl<const PREFIX_BITS: usize, const SUFFIX_BITS: usize> WordSet<PREFIX_BITS, SUFFIX_BITS>
+where
+    [(); SUFFIX_BITS.div_ceil(8)]:,
+{
+    /// Number of prefix ranges processed in parallel, a few per thread to balance the load.
+    pub(crate) fn num_parts() -> usize {
+        (4 * rayon::current_num_threads()).min(1 << PREFIX_BITS)
+    }
+
+    /// Applies `op` to the prefix ranges of the sets in parallel and stitches the buckets it returns into a single set.
+    ///
+    /// For each prefix of a range stored in some of the sets, `op` gets the indexes of these sets with their buckets
+    /// and returns the bucket of the result, if it is not empty.
+    /// The ranges are read directly from the sets, which are neither split nor copied.
+    fn par_apply<F>(wordsets: &[&Self], op: F) -> Self
+    where
+        F: Fn(
+                &[(usize, &Arc<TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>)],
+            ) -> Option<Arc<TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>>
+            + Sync,
+    {
+        let num_parts = Self::num_parts();
+        let results: Vec<Vec<_>> = (0..num_parts)
+            .into_par_iter()
+            .map(|part| {
+                let range = Self::part_prefixes(part, num_parts);
+                let mut prefix_iters = wordsets
+                    .iter()
+                    .map(|set| {
+                        let first_rank = set.prefixes.rank(range.start);
+                        set.prefixes.iter_range(range.clone()).zip(first_rank..)
+                    })
+                    .collect_vec();
+                let mut buckets = Vec::new();
+                for details in merge_iters_detailed_by(&mut prefix_iters, |(x, _), (y, _)| x.cmp(y))
+                {
+                    let prefix = (details[0].1).0;
+                    let containers = details
+                        .iter()
+                        .map(|&(i, (_, rank))| {
+                            let id = wordsets[i].tiered.get(rank) as usize;
+                            (i, &wordsets[i].suffix_containers[id])
+                        })
+                        .collect_vec();
+                    if let Some(container) = op(&containers) {
+                        buckets.push((prefix, container));
+                    }
+                }
+                buckets
+            })
+            .collect();
+        let mut res = Self::new_large();
+        for (prefix, container) in results.into_iter().flatten() {
+            res.push_shared_container(prefix, container);
+        }
+        res.adapt_layout_shrink();
+        res
+    }
+
+    /// Creates a bucket of the result from sorted suffixes, `None` if there are none.
+    fn new_bucket<I: Iterator<Item = SlicedInt<{ SUFFIX_BITS.div_ceil(8) }>>>(
+        suffixes: I,
+    ) -> Option<Arc<TrieVec<{ SUFFIX_BITS.div_ceil(8) }>>> {
+        let container = TrieVec::from_sorted_iter(suffixes);
+        if container.is_empty() {
+            None
+        } else {
+            Some(Arc::new(container))
+        }
+    }
+
+    /// Computes the union of multiple sets, processing prefix ranges in parallel.
+    pub fn par_merge(wordsets: Vec<&mut Self>) -> Self {
+        // a set using the small layout is merged faster by inserting its words
+        if wordsets.iter().any(|set| set.is_small()) {
+            return Self::merge(wordsets);
+        }
+        let wordsets = wordsets.iter().map(|set| &**set).collect_vec();
+        Self::par_apply(&wordsets, |containers| match containers {
+            [(_, container)] => Some(Arc::clone(container)),
+            _ => {
+                let mut suffix_iters = containers
+                    .iter()
+                    .map(|(_, container)| container.iter_ordered())
+                    .collect_vec();
+                Self::new_bucket(merge_iters(&mut suffix_iters))
+            }
+        })
+    }
+
+    /// Computes the intersection of multiple sets, processing prefix ranges in parallel.
+    pub fn par_intersect(wordsets: Vec<&mut Self>) -> Self {
+        if wordsets.iter().any(|set| set.is_small()) {
+            return Self::intersect(wordsets);
+        }
+        let wordsets = wordsets.iter().map(|set| &**set).collect_vec();
+        Self::par_apply(&wordsets, |containers| {
+            if containers.len() < wordsets.len() {
+                return None;
+            }
+            let mut suffix_iters = containers
+                .iter()
+                .map(|(_, container)| container.iter_ordered())
+                .collect_vec();
+            Self::new_bucket(intersect_iters(&mut suffix_iters))
+        })
+    }
+
+    /// Computes the union of two sets, processing prefix ranges in parallel.
+    pub fn par_union(&mut self, other: &mut Self) -> Self {
+        if self.is_small() || other.is_small() {
+            return self | other;
+        }
+        Self::par_apply(&[&*self, &*other], |containers| match containers {
+            [(_, container)] => Some(Arc::clone(container)),
+            [(_, left), (_, right)] => Self::new_bucket(
+                left.iter_ordered()
+                    .merge_join_by(right.iter_ordered(), |x, y| x.cmp(y))
+                    .map(|c| match c {
+                        Left(x) | Right(x) | Both(x, _) => x,
+                    }),
+            ),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Computes the intersection of two sets, processing prefix ranges in parallel.
+    pub fn par_intersection(&mut self, other: &mut Self) -> Self {
+        if self.is_small() || other.is_small() {
+            return self & other;
+        }
+        Self::par_apply(&[&*self, &*other], |containers| match containers {
+            [(_, left), (_, right)] => Self::new_bucket(
+                left.iter_ordered()
+                    .merge_join_by(right.iter_ordered(), |x, y| x.cmp(y))
+                    .filter_map(|c| match c {
+                        Both(x, _) => Some(x),
+                        _ => None,
+                    }),
+            ),
+            _ => None,
+        })
+    }
+
+    /// Computes the difference of two sets, processing prefix ranges in parallel.
+    pub fn par_difference(&mut self, other: &mut Self) -> Self {
+        if self.is_small() || other.is_small() {
+            return self - other;
+        }
+        Self::par_apply(&[&*self, &*other], |containers| match containers {
+            [(0, container)] => Some(Arc::clone(container)),
+            [(_, left), (_, right)] => Self::new_bucket(
+                left.iter_ordered()
+                    .merge_join_by(right.iter_ordered(), |x, y| x.cmp(y))
+                    .filter_map(|c| match c {
+                        Left(x) => Some(x),
+                        _ => None,
+                    }),
+            ),
+            _ => None,
+        })
+    }
+
+    /// Computes the symmetric difference of two sets, processing prefix ranges in parallel.
+    pub fn par_symmetric_difference(&mut self, other: &mut Self) -> Self {
+        if self.is_small() || other.is_small() {
+            return self ^ other;
+        }
+        Self::par_apply(&[&*self, &*other], |containers| match containers {
+            [(_, container)] => Some(Arc::clone(container)),
+            [(_, left), (_, right)] => Self::new_bucket(
+                left.iter_ordered()
+                    .merge_join_by(right.iter_ordered(), |x, y| x.cmp(y))
+                    .filter_map(|c| match c {
+                        Left(x) | Right(x) => Some(x),
+                        Both(_, _) => None,
+                    }),
+            ),
+            _ => unreachable!(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const N: usize = 1_000_000;
+    const PREFIX_BITS: usize = 16;
+    const SUFFIX_BITS: usize = 8;
+
+    type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+
+    #[test]
+    fn test_par_ops() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut random_set = |len: usize| {
+            let mut words = (0..len)
+                .map(|_| rng.gen_range(0..(1usize << (PREFIX_BITS + SUFFIX_BITS))))
+                .collect_vec();
+            words.sort_unstable();
+            Set::from_sorted_iter(words)
+        };
+        let mut sets = [
+            random_set(N),
+            random_set(N),
+            random_set(N / 2),
+            random_set(100),
+        ];
+        let words = sets
+            .iter()
+            .map(|set| set.iter::<usize>().collect_vec())
+            .collect_vec();
+        for (i, j) in [(0, 1), (1, 2), (2, 3), (3, 0)] {
+            let mut a = sets[i].clone();
+            let mut b = sets[j].clone();
+            let union = a.par_union(&mut b);
+            let inter = a.par_intersection(&mut b);
+            let diff = a.par_difference(&mut b);
+            let sym_diff = a.par_symmetric_difference(&mut b);
+            let mut a2 = sets[i].clone();
+            let mut b2 = sets[j].clone();
+            assert!(union
+                .iter::<usize>()
+                .eq((&mut a2 | &mut b2).iter::<usize>()));
+            assert!(inter
+                .iter::<usize>()
+                .eq((&mut a2 & &mut b2).iter::<usize>()));
+            assert!(diff.iter::<usize>().eq((&mut a2 - &mut b2).iter::<usize>()));
+            assert!(sym_diff
+                .iter::<usize>()
+                .eq((&mut a2 ^ &mut b2).iter::<usize>()));
+            // the operands get their buckets back
+            assert!(a.iter::<usize>().eq(words[i].iter().copied()));
+            assert!(b.iter::<usize>().eq(words[j].iter().copied()));
+            assert!(a.validate().is_ok() && b.validate().is_ok());
+        }
+        let merged = Set::par_merge(sets.iter_mut().collect());
+        let mut copies = sets.clone();
+        assert!(merged
+            .iter::<usize>()
+            .eq(Set::merge(copies.iter_mut().collect()).iter::<usize>()));
+        let intersected = Set::par_intersect(sets[..2].iter_mut().collect());
+        assert!(intersected
+            .iter::<usize>()
+            .eq(Set::intersect(copies[..2].iter_mut().collect()).iter::<usize>()));
+        assert!(merged.validate().is_ok() && intersected.validate().is_ok());
+    }
+}
@@ -3,40 +3,59 @@ use core::ops::*;
 use iter_set_ops::*;
 use itertools::EitherOrBoth::{Both, Left, Right};
 use itertools::Itertools;
+use std::sync::Arc;
 
 impl<const PREFIX_BITS: usize, const SUFFIX_BITS: usize> WordSet<PREFIX_BITS, SUFFIX_BITS>
 where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
-    pub fn merge(mut wordsets: Vec<&mut Self>) -> Self {
-        let mut res = Self::new();
+    pub fn merge(wordsets: Vec<&mut Self>) -> Self {
+        let (small, large): (Vec<_>, Vec<_>) = wordsets.into_iter().partition(|set| set.is_small());
+        if small.is_empty() {
+            return Self::merge_large(large);
+        }
+        let mut res = match large.len() {
+            0 => Self::new(),
+            1 => large[0].clone(),
+            _ => Self::merge_large(large),
+        };
+        let words = small
+            .iter()
+            .map(|set| set.small.iter().copied())
+            .kmerge()
+            .dedup()
+            .map(|(prefix, suffix)| (prefix as usize, suffix))
+            .collect_vec();
+        res.insert_split_batch(&words);
+        res
+    }
+
+    fn merge_large(mut wordsets: Vec<&mut Self>) -> Self {
+        let mut res = Self::new_large();
         let ptr = wordsets.as_mut_ptr();
         let mut prefix_iters = wordsets
             .iter()
             .map(|set| set.prefixes.iter().enumerate())
             .collect_vec();
         for details in merge_iters_detailed_by(&mut prefix_iters, |(_, x), (_, y)| x.cmp(y)) {
-            let container = if details.len() == 1 {
+            let prefix = (details[0].1).1;
+            if details.len() == 1 {
+                // the bucket is shared with the only set containing it
                 let (i, (rank, _)) = details[0];
                 let id = wordsets[i].tiered.get(rank) as usize;
-                wordsets[i].suffix_containers[id].clone()
+                res.push_shared_container(prefix, Arc::clone(&wordsets[i].suffix_containers[id]));
             } else {
                 let mut suffix_iters = Vec::new();
                 for &(i, (rank, _)) in details.iter() {
                     // each mutable reference is unique since each index is unique
                     let set = unsafe { ptr.add(i).as_mut().unwrap_unchecked() };
                     let id = set.tiered.get(rank) as usize;
-                    suffix_iters.push(set.suffix_containers[id].iter_sorted());
+                    suffix_iters.push(set.suffix_containers.make_mut(id).iter_sorted());
                 }
                 let mut container = TrieVec::new();
                 container.insert_sorted_iter(merge_iters(&mut suffix_iters));
-                container
-            };
-            let prefix = (details[0].1).1;
-            let rank = res.suffix_containers.len();
-            res.suffix_containers.push(container);
-            res.tiered.insert(rank, rank as u32);
-            res.prefixes.insert(prefix);
+                res.push_container(prefix, container);
+            }
         }
         res
     }
@@ -47,7 +66,25 @@ where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     pub fn intersect(mut wordsets: Vec<&mut Self>) -> Self {
-        let mut res = Self::new();
+        if let Some(smallest) = wordsets
+            .iter()
+            .filter(|set| set.is_small())
+            .min_by_key(|set| set.small.len())
+        {
+            let mut res = Self::new();
+            res.small = smallest
+                .small
+                .iter()
+                .copied()
+                .filter(|(prefix, suffix)| {
+                    wordsets
+                        .iter()
+                        .all(|set| set.contains_split(*prefix as usize, suffix))
+                })
+                .collect();
+            return res;
+        }
+        let mut res = Self::new_large();
         let ptr = wordsets.as_mut_ptr();
         let mut prefix_iters = wordsets
             .iter()
@@ -60,17 +97,15 @@ where
                 // each mutable reference is unique since each index is unique
                 let set = unsafe { ptr.add(i).as_mut().unwrap_unchecked() };
                 let id = set.tiered.get(rank) as usize;
-                suffix_iters.push(set.suffix_containers[id].iter_sorted());
+                suffix_iters.push(set.suffix_containers.make_mut(id).iter_sorted());
             }
             let mut container = TrieVec::new();
             container.insert_sorted_iter(intersect_iters(&mut suffix_iters));
             if !container.is_empty() {
-                let rank = res.suffix_containers.len();
-                res.suffix_containers.push(container);
-                res.tiered.insert(rank, rank as u32);
-                res.prefixes.insert(prefix);
+                res.push_container(prefix, container);
             }
         }
+        res.adapt_layout_shrink();
         res
     }
 }
@@ -83,7 +118,17 @@ where
     type Output = WordSet<PREFIX_BITS, SUFFIX_BITS>;
 
     fn bitor(self, other: Self) -> Self::Output {
-        let mut res = Self::Output::new();
+        if self.is_small() || other.is_small() {
+            let (small, large) = if self.is_small() {
+                (self, other)
+            } else {
+                (other, self)
+            };
+            let mut res = large.clone();
+            res.insert_split_batch(&small.small_words());
+            return res;
+        }
+        let mut res = Self::Output::new_large();
         self.prefixes
             .iter()
             .enumerate()
@@ -91,29 +136,20 @@ where
             .for_each(|c| match c {
                 Left((rank_left, prefix)) => {
                     let id_left = self.tiered.get(rank_left) as usize;
-                    let container = self.suffix_containers[id_left].clone();
-                    let rank = res.suffix_containers.len();
-                    res.suffix_containers.push(container);
-                    res.tiered.insert(rank, rank as u32);
-                    res.prefixes.insert(prefix);
+                    let container = Arc::clone(&self.suffix_containers[id_left]);
+                    res.push_shared_container(prefix, container);
                 }
                 Right((rank_right, prefix)) => {
                     let id_right = other.tiered.get(rank_right) as usize;
-                    let container = other.suffix_containers[id_right].clone();
-                    let rank = res.suffix_containers.len();
-                    res.suffix_containers.push(container);
-                    res.tiered.insert(rank, rank as u32);
-                    res.prefixes.insert(prefix);
+                    let container = Arc::clone(&other.suffix_containers[id_right]);
+                    res.push_shared_container(prefix, container);
                 }
                 Both((rank_left, prefix), (rank_right, _)) => {
                     let id_left = self.tiered.get(rank_left) as usize;
                     let id_right = other.tiered.get(rank_right) as usize;
-                    let container = &mut self.suffix_containers[id_left]
-                        | &mut other.suffix_containers[id_right];
-                    let rank = res.suffix_containers.len();
-                    res.suffix_containers.push(container);
-                    res.tiered.insert(rank, rank as u32);
-                    res.prefixes.insert(prefix);
+                    let container = self.suffix_containers.make_mut(id_left)
+                        | other.suffix_containers.make_mut(id_right);
+                    res.push_container(prefix, container);
                 }
             });
         res
@@ -126,6 +162,16 @@ where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     fn bitor_assign(&mut self, other: &mut Self) {
+        if other.is_small() {
+            self.insert_split_batch(&other.small_words());
+            return;
+        }
+        if self.is_small() {
+            let mut res = other.clone();
+            res.insert_split_batch(&self.small_words());
+            *self = res;
+            return;
+        }
         let mut prefix_iter = self.prefixes.iter();
         let mut prefix = prefix_iter.next();
         let mut rank = 0;
@@ -139,15 +185,19 @@ where
                 // merge containers
                 let id = self.tiered.get(rank) as usize;
                 let other_id = other.tiered.get(other_rank) as usize;
-                self.suffix_containers[id] |= &mut other.suffix_containers[other_id];
+                let len = self.suffix_containers[id].len();
+                *self.suffix_containers.make_mut(id) |= other.suffix_containers.make_mut(other_id);
+                self.num_words += self.suffix_containers[id].len() - len;
+                self.suffix_containers.make_mut(id).adapt_grow();
                 prefix = prefix_iter.next();
                 rank += 1;
             } else {
                 // insert container
                 let id = self.suffix_containers.len();
                 let other_id = other.tiered.get(other_rank) as usize;
-                self.suffix_containers
-                    .push(other.suffix_containers[other_id].clone());
+                self.num_words += other.suffix_containers[other_id].len();
+                let container = Arc::clone(&other.suffix_containers[other_id]);
+                self.suffix_containers.push(container);
                 self.tiered.insert(rank, id as u32);
                 rank += 1;
             }
@@ -164,7 +214,22 @@ where
     type Output = WordSet<PREFIX_BITS, SUFFIX_BITS>;
 
     fn bitand(self, other: Self) -> Self::Output {
-        let mut res = Self::Output::new();
+        if self.is_small() || other.is_small() {
+            let (small, large) = if self.is_small() {
+                (self, other)
+            } else {
+                (other, self)
+            };
+            let mut res = Self::Output::new();
+            res.small = small
+                .small
+                .iter()
+                .copied()
+                .filter(|(prefix, suffix)| large.contains_split(*prefix as usize, suffix))
+                .collect();
+            return res;
+        }
+        let mut res = Self::Output::new_large();
         self.prefixes
             .iter()
             .enumerate()
@@ -175,16 +240,14 @@ where
                 Both((rank_left, prefix), (rank_right, _)) => {
                     let id_left = self.tiered.get(rank_left) as usize;
                     let id_right = other.tiered.get(rank_right) as usize;
-                    let container = &mut self.suffix_containers[id_left]
-                        & &mut other.suffix_containers[id_right];
+                    let container = self.suffix_containers.make_mut(id_left)
+                        & other.suffix_containers.make_mut(id_right);
                     if !container.is_empty() {
-                        let rank = res.suffix_containers.len();
-                        res.suffix_containers.push(container);
-                        res.tiered.insert(rank, rank as u32);
-                        res.prefixes.insert(prefix);
+                        res.push_container(prefix, container);
                     }
                 }
             });
+        res.adapt_layout_shrink();
         res
     }
 }
@@ -195,6 +258,22 @@ where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     fn bitand_assign(&mut self, other: &mut Self) {
+        if self.is_small() {
+            self.small
+                .retain(|(prefix, suffix)| other.contains_split(*prefix as usize, suffix));
+            return;
+        }
+        if other.is_small() {
+            let mut res = Self::new();
+            res.small = other
+                .small
+                .iter()
+                .copied()
+                .filter(|(prefix, suffix)| self.contains_split(*prefix as usize, suffix))
+                .collect();
+            *self = res;
+            return;
+        }
         let mut prefix_iter = self.prefixes.iter();
         let mut prefix = prefix_iter.next();
         let mut rank = 0;
@@ -203,7 +282,8 @@ where
             while prefix.is_some() && prefix.unwrap() < other_prefix {
                 // remove container
                 let id = self.tiered.get(rank) as usize;
-                self.suffix_containers[id].clear();
+                self.num_words -= self.suffix_containers[id].len();
+                self.suffix_containers.clear(id);
                 self.empty_containers.push(id);
                 self.tiered.remove(rank);
                 prefix = prefix_iter.next();
@@ -212,7 +292,10 @@ where
                 // intersect containers
                 let id = self.tiered.get(rank) as usize;
                 let other_id = other.tiered.get(other_rank) as usize;
-                self.suffix_containers[id] &= &mut other.suffix_containers[other_id];
+                let len = self.suffix_containers[id].len();
+                *self.suffix_containers.make_mut(id) &= other.suffix_containers.make_mut(other_id);
+                self.num_words -= len - self.suffix_containers[id].len();
+                self.suffix_containers.make_mut(id).adapt_shrink();
                 if self.suffix_containers[id].is_empty() {
                     self.empty_containers.push(id);
                     self.tiered.remove(rank);
@@ -226,7 +309,8 @@ where
         while prefix.is_some() {
             // remove container
             let id = self.tiered.get(rank) as usize;
-            self.suffix_containers[id].clear();
+            self.num_words -= self.suffix_containers[id].len();
+            self.suffix_containers.clear(id);
             self.empty_containers.push(id);
             self.tiered.remove(rank);
             prefix = prefix_iter.next();
@@ -235,6 +319,7 @@ where
         for prefix in empty_prefixes {
             self.prefixes.remove(prefix);
         }
+        self.adapt_layout_shrink();
     }
 }
 
@@ -246,7 +331,22 @@ where
     type Output = WordSet<PREFIX_BITS, SUFFIX_BITS>;
 
     fn sub(self, other: Self) -> Self::Output {
-        let mut res = Self::Output::new();
+        if self.is_small() {
+            let mut res = Self::Output::new();
+            res.small = self
+                .small
+                .iter()
+                .copied()
+                .filter(|(prefix, suffix)| !other.contains_split(*prefix as usize, suffix))
+                .collect();
+            return res;
+        }
+        if other.is_small() {
+            let mut res = self.clone();
+            res.remove_split_batch(&other.small_words());
+            return res;
+        }
+        let mut res = Self::Output::new_large();
         self.prefixes
             .iter()
             .enumerate()
@@ -254,26 +354,21 @@ where
             .for_each(|c| match c {
                 Left((rank_left, prefix)) => {
                     let id_left = self.tiered.get(rank_left) as usize;
-                    let container = self.suffix_containers[id_left].clone();
-                    let rank = res.suffix_containers.len();
-                    res.suffix_containers.push(container);
-                    res.tiered.insert(rank, rank as u32);
-                    res.prefixes.insert(prefix);
+                    let container = Arc::clone(&self.suffix_containers[id_left]);
+                    res.push_shared_container(prefix, container);
                 }
                 Right(_) => (),
                 Both((rank_left, prefix), (rank_right, _)) => {
                     let id_left = self.tiered.get(rank_left) as usize;
                     let id_right = other.tiered.get(rank_right) as usize;
-                    let container = &mut self.suffix_containers[id_left]
-                        - &mut other.suffix_containers[id_right];
+                    let container = self.suffix_containers.make_mut(id_left)
+                        - other.suffix_containers.make_mut(id_right);
                     if !container.is_empty() {
-                        let rank = res.suffix_containers.len();
-                        res.suffix_containers.push(container);
-                        res.tiered.insert(rank, rank as u32);
-                        res.prefixes.insert(prefix);
+                        res.push_container(prefix, container);
                     }
                 }
             });
+        res.adapt_layout_shrink();
         res
     }
 }
@@ -284,6 +379,15 @@ where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     fn sub_assign(&mut self, other: &mut Self) {
+        if self.is_small() {
+            self.small
+                .retain(|(prefix, suffix)| !other.contains_split(*prefix as usize, suffix));
+            return;
+        }
+        if other.is_small() {
+            self.remove_split_batch(&other.small_words());
+            return;
+        }
         let mut prefix_iter = self.prefixes.iter();
         let mut prefix = prefix_iter.next();
         let mut rank = 0;
@@ -298,7 +402,10 @@ where
                 // subtract containers
                 let id = self.tiered.get(rank) as usize;
                 let other_id = other.tiered.get(other_rank) as usize;
-                self.suffix_containers[id] -= &mut other.suffix_containers[other_id];
+                let len = self.suffix_containers[id].len();
+                *self.suffix_containers.make_mut(id) -= other.suffix_containers.make_mut(other_id);
+                self.num_words -= len - self.suffix_containers[id].len();
+                self.suffix_containers.make_mut(id).adapt_shrink();
                 if self.suffix_containers[id].is_empty() {
                     self.empty_containers.push(id);
                     self.tiered.remove(rank);
@@ -313,6 +420,7 @@ where
         for prefix in nonempty_prefixes {
             self.prefixes.insert(prefix);
         }
+        self.adapt_layout_shrink();
     }
 }
 
@@ -324,7 +432,17 @@ where
     type Output = WordSet<PREFIX_BITS, SUFFIX_BITS>;
 
     fn bitxor(self, other: Self) -> Self::Output {
-        let mut res = Self::Output::new();
+        if self.is_small() || other.is_small() {
+            let (small, large) = if self.is_small() {
+                (self, other)
+            } else {
+                (other, self)
+            };
+            let mut res = large.clone();
+            res.toggle_split_batch(&small.small_words());
+            return res;
+        }
+        let mut res = Self::Output::new_large();
         self.prefixes
             .iter()
             .enumerate()
@@ -332,33 +450,25 @@ where
             .for_each(|c| match c {
                 Left((rank_left, prefix)) => {
                     let id_left = self.tiered.get(rank_left) as usize;
-                    let container = self.suffix_containers[id_left].clone();
-                    let rank = res.suffix_containers.len();
-                    res.suffix_containers.push(container);
-                    res.tiered.insert(rank, rank as u32);
-                    res.prefixes.insert(prefix);
+                    let container = Arc::clone(&self.suffix_containers[id_left]);
+                    res.push_shared_container(prefix, container);
                 }
                 Right((rank_right, prefix)) => {
                     let id_right = other.tiered.get(rank_right) as usize;
-                    let container = other.suffix_containers[id_right].clone();
-                    let rank = res.suffix_containers.len();
-                    res.suffix_containers.push(container);
-                    res.tiered.insert(rank, rank as u32);
-                    res.prefixes.insert(prefix);
+                    let container = Arc::clone(&other.suffix_containers[id_right]);
+                    res.push_shared_container(prefix, container);
                 }
                 Both((rank_left, prefix), (rank_right, _)) => {
                     let id_left = self.tiered.get(rank_left) as usize;
                     let id_right = other.tiered.get(rank_right) as usize;
-                    let container = &mut self.suffix_containers[id_left]
-                        ^ &mut other.suffix_containers[id_right];
+                    let container = self.suffix_containers.make_mut(id_left)
+                        ^ other.suffix_containers.make_mut(id_right);
                     if !container.is_empty() {
-                        let rank = res.suffix_containers.len();
-                        res.suffix_containers.push(container);
-                        res.tiered.insert(rank, rank as u32);
-                        res.prefixes.insert(prefix);
+                        res.push_container(prefix, container);
                     }
                 }
             });
+        res.adapt_layout_shrink();
         res
     }
 }
@@ -369,6 +479,16 @@ where
     [(); SUFFIX_BITS.div_ceil(8)]:,
 {
     fn bitxor_assign(&mut self, other: &mut Self) {
+        if other.is_small() {
+            self.toggle_split_batch(&other.small_words());
+            return;
+        }
+        if self.is_small() {
+            let mut res = other.clone();
+            res.toggle_split_batch(&self.small_words());
+            *self = res;
+            return;
+        }
         let mut prefix_iter = self.prefixes.iter();
         let mut prefix = prefix_iter.next();
         let mut rank = 0;
@@ -383,7 +503,10 @@ where
                 // xor containers
                 let id = self.tiered.get(rank) as usize;
                 let other_id = other.tiered.get(other_rank) as usize;
-                self.suffix_containers[id] ^= &mut other.suffix_containers[other_id];
+                let len = self.suffix_containers[id].len();
+                *self.suffix_containers.make_mut(id) ^= other.suffix_containers.make_mut(other_id);
+                self.num_words = self.num_words + self.suffix_containers[id].len() - len;
+                self.suffix_containers.make_mut(id).adapt_grow();
                 if self.suffix_containers[id].is_empty() {
                     self.empty_containers.push(id);
                     self.tiered.remove(rank);
@@ -396,8 +519,9 @@ where
                 // insert container
                 let id = self.suffix_containers.len();
                 let other_id = other.tiered.get(other_rank) as usize;
-                self.suffix_containers
-                    .push(other.suffix_containers[other_id].clone());
+                self.num_words += other.suffix_containers[other_id].len();
+                let container = Arc::clone(&other.suffix_containers[other_id]);
+                self.suffix_containers.push(container);
                 self.tiered.insert(rank, id as u32);
                 rank += 1;
             }
@@ -406,6 +530,7 @@ where
         for prefix in nonempty_prefixes {
             self.prefixes.insert(prefix);
         }
+        self.adapt_layout_shrink();
     }
 }
 
@@ -434,6 +559,7 @@ mod tests {
             set2.insert(i);
         }
         let res = &mut set | &mut set2;
+        assert_eq!(res.count(), 2 * N);
         for &i in v0.iter() {
             assert!(res.contains(i), "false negative for {i}");
         }
@@ -444,6 +570,7 @@ mod tests {
             assert!(!res.contains(i), "false positive for {i}");
         }
         set |= &mut set2;
+        assert_eq!(set.count(), 2 * N);
         for &i in v0.iter() {
             assert!(set.contains(i), "false negative for {i}");
         }
@@ -473,6 +600,7 @@ mod tests {
             set2.insert(i);
         }
         let res = &mut set & &mut set2;
+        assert_eq!(res.count(), N);
         for &i in v0.iter() {
             assert!(!res.contains(i), "false positive for {i}");
         }
@@ -483,6 +611,7 @@ mod tests {
             assert!(!res.contains(i), "false positive for {i}");
         }
         set &= &mut set2;
+        assert_eq!(set.count(), N);
         for &i in v0.iter() {
             assert!(!set.contains(i), "false positive for {i}");
         }
@@ -512,6 +641,7 @@ mod tests {
             set2.insert(i);
         }
         let res = &mut set - &mut set2;
+        assert_eq!(res.count(), N);
         for &i in v0.iter() {
             assert!(res.contains(i), "false negative for {i}");
         }
@@ -522,6 +652,7 @@ mod tests {
             assert!(!res.contains(i), "false positive for {i}");
         }
         set -= &mut set2;
+        assert_eq!(set.count(), N);
         for &i in v0.iter() {
             assert!(set.contains(i), "false negative for {i}");
         }
@@ -551,6 +682,7 @@ mod tests {
             set2.insert(i);
         }
         let res = &mut set ^ &mut set2;
+        assert_eq!(res.count(), 2 * N);
         for &i in v0.iter() {
             assert!(res.contains(i), "false negative for {i}");
         }
@@ -561,6 +693,7 @@ mod tests {
             assert!(res.contains(i), "false negative for {i}");
         }
         set ^= &mut set2;
+        assert_eq!(set.count(), 2 * N);
         for &i in v0.iter() {
             assert!(set.contains(i), "false negative for {i}");
         }
@@ -834,4 +967,49 @@ mod tests {
         assert_eq!(res2_bis.count(), res1.count());
         assert_eq!(res2_alt.count(), res1.count());
     }
+
+    #[test]
+    fn test_mixed_layouts() {
+        type Set = WordSet<PREFIX_BITS, SUFFIX_BITS>;
+        let small_words = (0..(3 * 1000)).step_by(3).collect_vec();
+        let large_words = (0..(2 * N)).step_by(2).collect_vec();
+        let mut small = Set::new();
+        let mut large = Set::new();
+        small.insert_batch(&small_words);
+        large.insert_batch(&large_words);
+        assert!(small.is_small());
+        assert!(!large.is_small());
+        let expected_or: HashSet<usize> = small_words
+            .iter()
+            .chain(large_words.iter())
+            .copied()
+            .collect();
+        let expected_and: HashSet<usize> =
+            small_words.iter().copied().filter(|i| i % 2 == 0).collect();
+        let expected_sub: HashSet<usize> =
+            small_words.iter().copied().filter(|i| i % 2 == 1).collect();
+        let expected_xor = &expected_or - &expected_and;
+        for (mut left, mut right) in [
+            (small.clone(), large.clone()),
+            (large.clone(), small.clone()),
+        ] {
+            let res = &mut left | &mut right;
+            assert_eq!(res.iter::<usize>().collect::<HashSet<_>>(), expected_or);
+            let res = &mut left & &mut right;
+            assert!(res.is_small());
+            assert_eq!(res.iter::<usize>().collect::<HashSet<_>>(), expected_and);
+            let res = &mut left ^ &mut right;
+            assert_eq!(res.iter::<usize>().collect::<HashSet<_>>(), expected_xor);
+            let mut res = left.clone();
+            res &= &mut right;
+            assert_eq!(res.iter::<usize>().collect::<HashSet<_>>(), expected_and);
+            let mut res = left.clone();
+            res |= &mut right;
+            assert_eq!(res.iter::<usize>().collect::<HashSet<_>>(), expected_or);
+        }
+        let res = &mut small - &mut large;
+        assert_eq!(res.iter::<usize>().collect::<HashSet<_>>(), expected_sub);
+        small -= &mut large;
+        assert_eq!(small.iter::<usize>().collect::<HashSet<_>>(), expected_sub);
+    }
 }